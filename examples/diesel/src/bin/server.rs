@@ -2,7 +2,7 @@ use diesel::{
     r2d2::{self, ConnectionManager},
     SqliteConnection,
 };
-use diesel_example::BlogServer;
+use diesel_example::{run_migrations, BlogServer};
 
 use phalanx::prelude::*;
 
@@ -17,11 +17,14 @@ async fn main() -> anyhow::Result<()> {
 
     let pool = r2d2::Pool::builder().build(manager)?;
 
-    let server = BlogServer::new(pool);
+    run_migrations(&pool).await?;
+
+    let server = BlogServer::new(pool.clone());
 
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
+            .data(pool.clone())
             .phalanx_mount(server.clone())
     })
     .bind("127.0.0.1:8080")?
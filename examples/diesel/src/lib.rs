@@ -1,9 +1,11 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
 use diesel::prelude::*;
-use diesel::r2d2::{self, ConnectionManager};
 
+use phalanx::diesel::{DbConnection, DbError, DbPool};
 use phalanx::prelude::*;
 use phalanx::{client::Client, web};
 
@@ -12,15 +14,13 @@ pub mod schema;
 
 use models::{Post, PostBuilder};
 
-type DbPool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
-
 #[derive(Clone)]
 pub struct BlogServer {
-    pool: DbPool,
+    pool: DbPool<SqliteConnection>,
 }
 
 impl BlogServer {
-    pub fn new(pool: DbPool) -> Self {
+    pub fn new(pool: DbPool<SqliteConnection>) -> Self {
         Self { pool }
     }
 }
@@ -41,90 +41,120 @@ impl BlogClient {
 
 no_arg_sql_function!(last_insert_rowid, diesel::sql_types::Integer);
 
+embed_migrations!();
+
+/// Bring the database schema up to date
+pub async fn run_migrations(pool: &DbPool<SqliteConnection>) -> Result<(), DbError> {
+    phalanx::diesel::run_migrations(pool, embedded_migrations::run).await
+}
+
 #[phalanx(BlogClient)]
 impl BlogServer {
     #[post("/post")]
-    async fn create_post(&self, new_post: web::Json<PostBuilder>) -> web::Json<Post> {
+    #[transactional]
+    async fn create_post(
+        &self,
+        conn: DbConnection<SqliteConnection>,
+        new_post: web::Json<PostBuilder>,
+    ) -> Result<web::Json<Post>, DbError> {
         use crate::schema::posts::{self, dsl::*};
 
-        let conn = self.pool.get().expect("conn");
         let new_post = new_post.into_inner();
 
-        diesel::insert_into(posts::table)
-            .values(&new_post)
-            .execute(&conn)
-            .expect("Error saving new post");
+        let post = conn
+            .run(move |conn| {
+                diesel::insert_into(posts::table)
+                    .values(&new_post)
+                    .execute(conn)?;
 
-        let post_id: i32 = diesel::select(last_insert_rowid).first(&conn).unwrap();
+                let post_id: i32 = diesel::select(last_insert_rowid).first(conn)?;
 
-        let post = posts
-            .filter(id.eq(post_id))
-            .first(&conn)
-            .expect("Error loading posts");
+                posts.filter(id.eq(post_id)).first(conn)
+            })
+            .await?;
 
-        web::Json(post)
+        Ok(web::Json(post))
     }
 
     #[get("/post/{post_id}")]
-    async fn read_post(&self, post_id: i32) -> web::Json<Post> {
+    async fn read_post(&self, post_id: i32) -> Result<web::Json<Post>, DbError> {
         use crate::schema::posts::dsl::*;
 
-        let conn = self.pool.get().expect("conn");
+        let conn = DbConnection::get(&self.pool).await?;
 
-        let post = posts
-            .filter(id.eq(post_id))
-            .first(&conn)
-            .expect("Error loading posts");
+        let post = conn
+            .run(move |conn| posts.filter(id.eq(post_id)).first(conn))
+            .await?;
 
-        web::Json(post)
+        Ok(web::Json(post))
     }
 
     #[put("/post/{post_id}")]
-    async fn update_post(&self, post_id: i32, post: web::Json<PostBuilder>) {
+    #[transactional]
+    async fn update_post(
+        &self,
+        conn: DbConnection<SqliteConnection>,
+        post_id: i32,
+        post: web::Json<PostBuilder>,
+    ) -> Result<(), DbError> {
         use crate::schema::posts::dsl::*;
 
         let post = post.into_inner();
-        let (title_, body_, published_) = match (post.title, post.body, post.published) {
-            (None, None, None) => return,
-            (title_, body_, published_) => (title_, body_, published_),
-        };
-
-        let conn = self.pool.get().expect("conn");
-
-        let stmt = diesel::update(posts.find(post_id));
-
-        let stmt = match (title_, body_, published_) {
-            (None, None, None) => return,
-            (Some(title_), None, None) => stmt.set(title.eq(title_)).execute(&conn),
-            (None, Some(body_), None) => stmt.set(body.eq(body_)).execute(&conn),
-            (None, None, Some(published_)) => stmt.set(published.eq(published_)).execute(&conn),
-
-            (Some(title_), Some(body_), None) => {
-                stmt.set((title.eq(title_), body.eq(body_))).execute(&conn)
-            }
-            (None, Some(body_), Some(published_)) => stmt
-                .set((body.eq(body_), published.eq(published_)))
-                .execute(&conn),
-            (Some(title_), None, Some(published_)) => stmt
-                .set((title.eq(title_), published.eq(published_)))
-                .execute(&conn),
-            (Some(title_), Some(body_), Some(published_)) => stmt
-                .set((title.eq(title_), body.eq(body_), published.eq(published_)))
-                .execute(&conn),
-        };
-
-        stmt.expect(&format!("Unable to find post {}", post_id));
+
+        if let (None, None, None) = (&post.title, &post.body, &post.published) {
+            return Ok(());
+        }
+
+        let updated = conn
+            .run(move |conn| {
+                let stmt = diesel::update(posts.find(post_id));
+
+                match (post.title, post.body, post.published) {
+                    (None, None, None) => unreachable!(),
+                    (Some(title_), None, None) => stmt.set(title.eq(title_)).execute(conn),
+                    (None, Some(body_), None) => stmt.set(body.eq(body_)).execute(conn),
+                    (None, None, Some(published_)) => {
+                        stmt.set(published.eq(published_)).execute(conn)
+                    }
+
+                    (Some(title_), Some(body_), None) => {
+                        stmt.set((title.eq(title_), body.eq(body_))).execute(conn)
+                    }
+                    (None, Some(body_), Some(published_)) => stmt
+                        .set((body.eq(body_), published.eq(published_)))
+                        .execute(conn),
+                    (Some(title_), None, Some(published_)) => stmt
+                        .set((title.eq(title_), published.eq(published_)))
+                        .execute(conn),
+                    (Some(title_), Some(body_), Some(published_)) => stmt
+                        .set((title.eq(title_), body.eq(body_), published.eq(published_)))
+                        .execute(conn),
+                }
+            })
+            .await?;
+
+        if updated == 0 {
+            return Err(diesel::result::Error::NotFound.into());
+        }
+
+        Ok(())
     }
 
     #[delete("/post/{post_id}")]
-    async fn delete_post(&self, post_id: i32) {
+    async fn delete_post(&self, post_id: i32) -> Result<(), DbError> {
         use crate::schema::posts::dsl::*;
 
-        let conn = self.pool.get().expect("conn");
+        let conn = DbConnection::get(&self.pool).await?;
+
+        let deleted = conn
+            .run(move |conn| diesel::delete(posts.find(post_id)).execute(conn))
+            .await?;
+
+        if deleted == 0 {
+            return Err(diesel::result::Error::NotFound.into());
+        }
 
-        diesel::delete(posts.find(post_id))
-            .execute(&conn)
-            .expect(&format!("Unable to find post {}", post_id));
+        Ok(())
     }
 }
 
@@ -0,0 +1,414 @@
+//! Spreading calls across replicas
+//!
+//! A [Balancer] holds the base URLs of a service's replicas, and picks one for
+//! each request a [Client](crate::client::Client) makes with a [Strategy].
+//! Replicas failing several calls in a row are ejected, and no longer picked
+//! until a background health check gets a successful response from them.
+//!
+//! ```ignore
+//! let balancer = Balancer::new(vec!["http://posts-1:8080", "http://posts-2:8080"])
+//!     .strategy(Strategy::LeastOutstanding)
+//!     .eject_after(3)
+//!     .health_path("/healthz");
+//! let client = BlogClient(Client::balanced(ReqwestClient::default(), balancer));
+//! ```
+//!
+//! Calls fail if they don't get a response, or are responded to with a
+//! `5xx` status. When every replica is ejected, calls are spread across all
+//! of them rather than failing outright. Retried calls pick a replica again
+//! for each attempt, so they move off a replica once it's ejected.
+//!
+//! Consistently hashed balancers pick by a request's path, unless the caller
+//! sets a key of its own with [hash_key]:
+//!
+//! ```ignore
+//! // Every post of a user is read from the same replica
+//! let posts = hash_key(user_id.to_string(), async {
+//!     futures::future::try_join_all(ids.iter().map(|id| client.read_post(*id))).await
+//! })
+//! .await?;
+//! ```
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use actix_web::rt::time::delay_for;
+use reqwest::{Client as ReqwestClient, Request, Url};
+use tokio::runtime::Handle;
+
+/// Points each replica has on the hash ring, evening out the share of keys each gets
+const RING_POINTS: usize = 100;
+
+tokio::task_local! {
+    static KEY: String;
+}
+
+/// Run `future` with the requests it makes through [ConsistentHash](Strategy::ConsistentHash)
+/// balancers sent to the replica `key` hashes to, rather than the one their path does
+pub async fn hash_key<F: Future>(key: impl Into<String>, future: F) -> F::Output {
+    KEY.scope(key.into(), future).await
+}
+
+/// How replicas are picked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each replica in turn
+    #[default]
+    RoundRobin,
+    /// The replica with the fewest requests in flight
+    ///
+    /// Requests are counted as their replica is picked, when a client sends
+    /// them, so concurrent calls see each other and spread out.
+    LeastOutstanding,
+    /// The replica a request's path, or the key set with [hash_key], hashes to,
+    /// so requests for the same resource go to the same replica while it's healthy
+    ConsistentHash,
+}
+
+/// The state of a replica, for metrics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointStats {
+    pub url: String,
+    pub healthy: bool,
+    /// Requests in flight
+    pub outstanding: usize,
+}
+
+/// Picks the replica each request is sent to
+///
+/// Cloning a balancer is cheap, and clones share the same replicas.
+#[derive(Clone)]
+pub struct Balancer {
+    endpoints: Arc<[Endpoint]>,
+    /// Indexes of the replicas, sorted by their points on the hash ring
+    ring: Arc<[(u64, usize)]>,
+    next: Arc<AtomicUsize>,
+    /// Held while picking a replica and counting the request to it
+    picking: Arc<Mutex<()>>,
+    strategy: Strategy,
+    eject_after: u32,
+    health_path: String,
+    health_interval: Duration,
+}
+
+struct Endpoint {
+    url: String,
+    /// The URL as requests to the replica are written, to find which replica a request was sent to
+    normalized: String,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    outstanding: AtomicUsize,
+}
+
+impl Balancer {
+    /// Balance requests across the replicas at `urls`
+    ///
+    /// # Panics
+    /// If `urls` is empty
+    pub fn new<I, S>(urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let endpoints: Arc<[Endpoint]> = urls
+            .into_iter()
+            .map(|url| {
+                let url = url.into();
+                let normalized = Url::parse(&url)
+                    .map(|parsed| String::from(parsed.as_str().trim_end_matches('/')))
+                    .unwrap_or_else(|_| url.clone());
+                Endpoint {
+                    url,
+                    normalized,
+                    healthy: AtomicBool::new(true),
+                    failures: AtomicU32::new(0),
+                    outstanding: AtomicUsize::new(0),
+                }
+            })
+            .collect();
+        assert!(!endpoints.is_empty(), "balancers need at least one replica");
+
+        let mut ring: Vec<_> = endpoints
+            .iter()
+            .enumerate()
+            .flat_map(|(index, endpoint)| {
+                (0..RING_POINTS).map(move |point| (hash(&(&endpoint.url, point)), index))
+            })
+            .collect();
+        ring.sort_unstable();
+
+        Balancer {
+            endpoints,
+            ring: ring.into(),
+            next: Arc::default(),
+            picking: Arc::default(),
+            strategy: Strategy::default(),
+            eject_after: 3,
+            health_path: String::from("/health"),
+            health_interval: Duration::from_secs(5),
+        }
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Set how many calls in a row a replica must fail to be ejected
+    ///
+    /// # Panics
+    /// If `failures` is zero
+    pub fn eject_after(mut self, failures: u32) -> Self {
+        assert!(
+            failures > 0,
+            "replicas must fail at least once to be ejected"
+        );
+        self.eject_after = failures;
+        self
+    }
+
+    /// Set the path ejected replicas are checked at, which must respond with a `2xx` status
+    pub fn health_path(mut self, path: &str) -> Self {
+        self.health_path = String::from(path);
+        self
+    }
+
+    /// Set how often ejected replicas are checked
+    pub fn health_interval(mut self, interval: Duration) -> Self {
+        self.health_interval = interval;
+        self
+    }
+
+    /// The state of every replica
+    pub fn endpoints(&self) -> Vec<EndpointStats> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| EndpointStats {
+                url: endpoint.url.clone(),
+                healthy: endpoint.healthy.load(Ordering::SeqCst),
+                outstanding: endpoint.outstanding.load(Ordering::SeqCst),
+            })
+            .collect()
+    }
+
+    /// The base URL of the replica to send the request for `path` to
+    ///
+    /// Consistently hashed balancers pick by the key set with [hash_key], if
+    /// there is one, rather than `path`.
+    pub fn pick(&self, path: &str) -> &str {
+        &self.endpoints[self.pick_index(path)].url
+    }
+
+    fn pick_index(&self, path: &str) -> usize {
+        let healthy = |index: &usize| self.endpoints[*index].healthy.load(Ordering::SeqCst);
+        // With every replica ejected, any of them may be picked
+        let any_healthy = self
+            .endpoints
+            .iter()
+            .any(|endpoint| endpoint.healthy.load(Ordering::SeqCst));
+        let eligible = |index: &usize| !any_healthy || healthy(index);
+
+        // Replicas are taken in turn from those eligible, so skipping one doesn't
+        // double the share of the next
+        let turns = || {
+            let indexes: Vec<_> = (0..self.endpoints.len()).filter(eligible).collect();
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            let count = indexes.len();
+            (0..count).map(move |offset| indexes[(start + offset) % count])
+        };
+        match self.strategy {
+            Strategy::RoundRobin => turns().next().unwrap_or(0),
+            // Ties are broken in turn, so idle replicas share the load
+            Strategy::LeastOutstanding => turns()
+                .min_by_key(|index| self.endpoints[*index].outstanding.load(Ordering::SeqCst))
+                .unwrap_or(0),
+            Strategy::ConsistentHash => {
+                let key = KEY.try_with(hash).unwrap_or_else(|_| hash(&path));
+                let start = self
+                    .ring
+                    .binary_search_by_key(&key, |(point, _)| *point)
+                    .unwrap_or_else(|index| index);
+                (0..self.ring.len())
+                    .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+                    .find(eligible)
+                    .unwrap_or(0)
+            }
+        }
+    }
+
+    /// Whether requests are sent to the replica picked as they're sent,
+    /// rather than the one their URL was written with
+    ///
+    /// Least outstanding balancers count requests as they pick them, which
+    /// only happens once they're sent.
+    pub(crate) fn picks_on_send(&self) -> bool {
+        self.strategy == Strategy::LeastOutstanding
+    }
+
+    /// Track a request to `url` until the returned [Tracked] is dropped
+    ///
+    /// Returns `None` if `url` isn't at one of the replicas.
+    pub(crate) fn track(&self, url: &Url) -> Option<Tracked> {
+        let (index, _) = self.locate(url)?;
+        Some(self.track_index(index))
+    }
+
+    fn track_index(&self, index: usize) -> Tracked {
+        self.endpoints[index]
+            .outstanding
+            .fetch_add(1, Ordering::SeqCst);
+        Tracked {
+            balancer: self.clone(),
+            index,
+        }
+    }
+
+    /// Send `request` to the replica picked for it now, and track it, for
+    /// requests made to one of the replicas
+    ///
+    /// The request is counted as the replica is picked, so concurrent picks
+    /// see it. Returns `None`, leaving `request` as it was, if it isn't to one
+    /// of the replicas.
+    pub(crate) fn repick(&self, request: &mut Request) -> Option<Tracked> {
+        let (index, rest) = self.locate(request.url())?;
+        let rest = String::from(rest);
+        let tracked = {
+            let _picking = self.picking.lock().unwrap();
+            self.track_index(self.pick_index(&rest))
+        };
+        if tracked.index != index {
+            let url = format!("{}{}", self.endpoints[tracked.index].normalized, rest);
+            *request.url_mut() = Url::parse(&url).ok()?;
+        }
+        Some(tracked)
+    }
+
+    /// The index of the replica `url` is at, and the rest of `url` after its base URL
+    fn locate<'a>(&self, url: &'a Url) -> Option<(usize, &'a str)> {
+        let url = url.as_str();
+        self.endpoints
+            .iter()
+            .enumerate()
+            .find_map(|(index, endpoint)| {
+                url.strip_prefix(endpoint.normalized.as_str())
+                    .filter(|rest| rest.is_empty() || rest.starts_with(&['/', '?', '#'][..]))
+                    .map(|rest| (index, rest))
+            })
+    }
+
+    /// Eject the replica at `index`, checking its health until it's readmitted
+    ///
+    /// Health checks run on the current Tokio runtime; outside of one, the
+    /// replica isn't ejected, as nothing would readmit it.
+    fn eject(&self, index: usize, client: &ReqwestClient) {
+        let runtime = match Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+        let endpoint = &self.endpoints[index];
+        if endpoint
+            .healthy
+            .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+
+        let endpoints = Arc::downgrade(&self.endpoints);
+        let url = format!("{}{}", endpoint.url, self.health_path);
+        let interval = self.health_interval;
+        runtime.spawn(health_check(
+            endpoints,
+            index,
+            client.clone(),
+            url,
+            interval,
+        ));
+    }
+}
+
+impl fmt::Debug for Balancer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Balancer")
+            .field("endpoints", &self.endpoints())
+            .field("strategy", &self.strategy)
+            .field("eject_after", &self.eject_after)
+            .field("health_path", &self.health_path)
+            .field("health_interval", &self.health_interval)
+            .finish()
+    }
+}
+
+/// Check the replica at `index` every `interval` until it responds successfully,
+/// or its balancer is dropped
+async fn health_check(
+    endpoints: Weak<[Endpoint]>,
+    index: usize,
+    client: ReqwestClient,
+    url: String,
+    interval: Duration,
+) {
+    loop {
+        delay_for(interval).await;
+        if endpoints.strong_count() == 0 {
+            return;
+        }
+
+        let res = client.get(&url).timeout(interval).send().await;
+        if matches!(res, Ok(res) if res.status().is_success()) {
+            if let Some(endpoints) = endpoints.upgrade() {
+                let endpoint = &endpoints[index];
+                endpoint.failures.store(0, Ordering::SeqCst);
+                endpoint.healthy.store(true, Ordering::SeqCst);
+            }
+            return;
+        }
+    }
+}
+
+/// A request in flight to a replica
+pub(crate) struct Tracked {
+    balancer: Balancer,
+    index: usize,
+}
+
+impl Tracked {
+    /// The base URL of the replica
+    pub(crate) fn url(&self) -> &str {
+        &self.balancer.endpoints[self.index].url
+    }
+
+    /// Record the outcome of the request, ejecting the replica if it's failed too often
+    pub(crate) fn record(self, success: bool, client: &ReqwestClient) {
+        let endpoint = &self.balancer.endpoints[self.index];
+        if success {
+            endpoint.failures.store(0, Ordering::SeqCst);
+        } else if endpoint.failures.fetch_add(1, Ordering::SeqCst) + 1 >= self.balancer.eject_after
+        {
+            self.balancer.eject(self.index, client);
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.balancer.endpoints[self.index]
+            .outstanding
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
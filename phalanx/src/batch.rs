@@ -0,0 +1,328 @@
+//! Many route calls in one round trip
+//!
+//! Services declared with `#[phalanx(BlogClient, batch)]` mount a `/_batch`
+//! endpoint, which takes a list of calls naming a route and its arguments,
+//! runs them concurrently, and responds with each call's status and result.
+//! Batches of more than [MAX_BATCH_SIZE] calls are refused with
+//! `413 Payload Too Large`, so clients send larger batches in several requests.
+//! Routes are called as they are over [JSON-RPC](crate::jsonrpc), so the same
+//! routes are left out.
+//!
+//! The client gets a `batch` method returning a builder with a method for
+//! each route. Sending it returns a tuple with a result for each call, while
+//! `batch_all` collects calls returning the same type into a `Vec`.
+//!
+//! ```ignore
+//! let (first, second) = client.batch().read_post(1).read_post(2).send().await?;
+//!
+//! let posts = ids
+//!     .iter()
+//!     .fold(client.batch_all(), |batch, id| batch.read_post(*id))
+//!     .send()
+//!     .await?;
+//! ```
+
+use std::{convert::TryFrom, fmt, sync::Arc};
+
+use actix_web::{
+    web::{self, Data, Json, ServiceConfig},
+    HttpRequest, HttpResponse,
+};
+use futures::future::{join_all, try_join_all};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    client::{Client, PhalanxClientError},
+    deadline::Deadline,
+    jsonrpc::{self, JsonRpcService, Params, RpcError},
+    retry::RetryPolicy,
+};
+
+/// The path batches are posted to
+pub const PATH: &str = "/_batch";
+
+/// The most calls one request may hold, as many as a JSON-RPC batch
+pub const MAX_BATCH_SIZE: usize = jsonrpc::MAX_BATCH_SIZE;
+
+/// A call to the route named `route`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Call {
+    pub route: String,
+    /// The route's arguments, by name or by position
+    #[serde(default)]
+    pub args: Params,
+}
+
+/// The outcome of a [Call]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CallResult {
+    /// The status the call would have been responded to with on its own
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<Value, RpcError>> for CallResult {
+    fn from(result: Result<Value, RpcError>) -> Self {
+        match result {
+            Ok(body) => CallResult {
+                status: 200,
+                body: Some(body),
+                error: None,
+            },
+            Err(err) => CallResult {
+                status: status(&err),
+                body: None,
+                error: Some(err.message),
+            },
+        }
+    }
+}
+
+/// The HTTP status of a failed call
+///
+/// Errors returned by routes carry their own status. Otherwise unknown
+/// routes are `404 Not Found` and invalid arguments `400 Bad Request`.
+fn status(err: &RpcError) -> u16 {
+    let status = err
+        .data
+        .as_ref()
+        .and_then(|data| data["status"].as_u64())
+        .and_then(|status| u16::try_from(status).ok());
+    status.unwrap_or(match err.code {
+        jsonrpc::METHOD_NOT_FOUND => 404,
+        jsonrpc::INVALID_PARAMS => 400,
+        _ => 500,
+    })
+}
+
+/// Run `calls` concurrently, returning their results in the same order
+pub async fn handle<S: JsonRpcService>(server: Arc<S>, calls: Vec<Call>) -> Vec<CallResult> {
+    let calls = calls.into_iter().map(|call| {
+        let server = server.clone();
+        async move { CallResult::from(server.call(call.route, call.args).await) }
+    });
+    join_all(calls).await
+}
+
+/// Mount the batch endpoint of `S` at [PATH]
+///
+/// Called by `PhalanxServer::mount` for services declared with `batch`.
+pub fn mount<S: JsonRpcService + 'static>(config: &mut ServiceConfig) {
+    config.route(PATH, web::post().to(endpoint::<S>));
+}
+
+/// Oversized batches are refused before any route is called. The rest share
+/// the request's deadline and run within it, so each call is limited as the
+/// route would be on its own
+async fn endpoint<S: JsonRpcService>(
+    req: HttpRequest,
+    server: Data<S>,
+    deadline: Deadline,
+    calls: Json<Vec<Call>>,
+) -> HttpResponse {
+    let calls = calls.into_inner();
+    if calls.len() > MAX_BATCH_SIZE {
+        return HttpResponse::PayloadTooLarge()
+            .body(format!("batches may have at most {} calls", MAX_BATCH_SIZE));
+    }
+
+    let calls = handle(server.into_inner(), calls);
+    HttpResponse::Ok().json(crate::server::scope(req, deadline.scope(calls)).await)
+}
+
+/// A call waiting for its result, decoded as `R`
+pub struct Pending<R> {
+    decode: Box<dyn FnOnce(Value) -> Result<R, PhalanxClientError>>,
+}
+
+impl<R> Pending<R> {
+    fn resolve(self, result: Option<CallResult>) -> Result<R, PhalanxClientError> {
+        match result {
+            Some(result) if (200..300).contains(&result.status) => {
+                (self.decode)(result.body.unwrap_or(Value::Null))
+            }
+            Some(result) => Err(PhalanxClientError::BatchCallError {
+                status: result.status,
+                message: result.error.unwrap_or_default(),
+            }),
+            None => Err(PhalanxClientError::DecodeError(String::from(
+                "the batch response is missing a result",
+            ))),
+        }
+    }
+}
+
+impl<R> fmt::Debug for Pending<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pending")
+    }
+}
+
+/// Calls which can have another call added
+pub trait Append<T> {
+    type Output;
+
+    fn append(self, call: T) -> Self::Output;
+}
+
+/// Calls which can be resolved from the results of a batch
+pub trait Resolve {
+    type Results;
+
+    fn resolve(self, results: Vec<CallResult>) -> Self::Results;
+}
+
+impl<R> Append<Pending<R>> for Vec<Pending<R>> {
+    type Output = Self;
+
+    fn append(mut self, call: Pending<R>) -> Self {
+        self.push(call);
+        self
+    }
+}
+
+impl<R> Resolve for Vec<Pending<R>> {
+    type Results = Vec<Result<R, PhalanxClientError>>;
+
+    fn resolve(self, results: Vec<CallResult>) -> Self::Results {
+        let mut results = results.into_iter();
+        self.into_iter()
+            .map(|call| call.resolve(results.next()))
+            .collect()
+    }
+}
+
+/// Implement [Append] and [Resolve] for a tuple of pending calls, or only
+/// [Resolve] for the largest tuple
+macro_rules! tuple_calls {
+    (resolve $($result:ident $call:ident),*) => {
+        impl<$($result),*> Resolve for ($(Pending<$result>,)*) {
+            type Results = ($(Result<$result, PhalanxClientError>,)*);
+
+            #[allow(unused_variables, unused_mut, clippy::unused_unit)]
+            fn resolve(self, results: Vec<CallResult>) -> Self::Results {
+                let mut results = results.into_iter();
+                let ($($call,)*) = self;
+                ($($call.resolve(results.next()),)*)
+            }
+        }
+    };
+    ($($result:ident $call:ident),*) => {
+        impl<$($result,)* T> Append<T> for ($(Pending<$result>,)*) {
+            type Output = ($(Pending<$result>,)* T,);
+
+            #[allow(clippy::unused_unit)]
+            fn append(self, call: T) -> Self::Output {
+                let ($($call,)*) = self;
+                ($($call,)* call,)
+            }
+        }
+
+        tuple_calls!(resolve $($result $call),*);
+    };
+}
+
+tuple_calls!();
+tuple_calls!(A a);
+tuple_calls!(A a, B b);
+tuple_calls!(A a, B b, C c);
+tuple_calls!(A a, B b, C c, D d);
+tuple_calls!(A a, B b, C c, D d, E e);
+tuple_calls!(A a, B b, C c, D d, E e, F f);
+tuple_calls!(A a, B b, C c, D d, E e, F f, G g);
+tuple_calls!(A a, B b, C c, D d, E e, F f, G g, H h);
+tuple_calls!(A a, B b, C c, D d, E e, F f, G g, H h, I i);
+tuple_calls!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j);
+tuple_calls!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j, K k);
+tuple_calls!(resolve A a, B b, C c, D d, E e, F f, G g, H h, I i, J j, K k, L l);
+
+/// Builds a batch of calls
+///
+/// Generated clients wrap this in a builder with a method for each route.
+/// Up to 12 calls can be resolved as a tuple; use a `Vec` of [Pending]
+/// calls for more.
+pub struct Batch<'a, P> {
+    client: &'a Client,
+    calls: Result<Vec<Call>, serde_json::Error>,
+    pending: P,
+}
+
+impl<'a, P> Batch<'a, P> {
+    pub fn new(client: &'a Client, pending: P) -> Self {
+        Batch {
+            client,
+            calls: Ok(Vec::new()),
+            pending,
+        }
+    }
+
+    /// Add a call to `route`, decoding its result as `T` and converting it with `wrap`
+    pub fn call<T, R>(
+        self,
+        route: &str,
+        args: Result<Params, serde_json::Error>,
+        wrap: fn(T) -> R,
+    ) -> Batch<'a, P::Output>
+    where
+        P: Append<Pending<R>>,
+        T: DeserializeOwned + 'static,
+        R: 'static,
+    {
+        let calls = self.calls.and_then(|mut calls| {
+            calls.push(Call {
+                route: String::from(route),
+                args: args?,
+            });
+            Ok(calls)
+        });
+        let pending = Pending {
+            decode: Box::new(move |value| Ok(wrap(serde_json::from_value(value)?))),
+        };
+
+        Batch {
+            client: self.client,
+            calls,
+            pending: self.pending.append(pending),
+        }
+    }
+
+    /// Send the calls, returning the result of each
+    ///
+    /// Batches of more than [MAX_BATCH_SIZE] calls are sent in several
+    /// requests at once. Fails only if the batch as a whole couldn't be sent;
+    /// each call's failure is returned as its result.
+    pub async fn send(self) -> Result<P::Results, PhalanxClientError>
+    where
+        P: Resolve,
+    {
+        let calls = self.calls?;
+        let client = self.client;
+        let requests = calls.chunks(MAX_BATCH_SIZE).map(|calls| async move {
+            let req = client.client.post(&client.format_url(PATH));
+            client
+                .send(PATH, req.json(calls), &RetryPolicy::never())
+                .await?
+                .error_for_status()?
+                .json::<Vec<CallResult>>()
+                .await
+                .map_err(PhalanxClientError::from)
+        });
+        let results = try_join_all(requests)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        Ok(self.pending.resolve(results))
+    }
+}
+
+impl<P> fmt::Debug for Batch<'_, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Batch").field("calls", &self.calls).finish()
+    }
+}
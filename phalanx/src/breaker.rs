@@ -0,0 +1,391 @@
+//! Failing fast when a service is down
+//!
+//! A [CircuitBreaker] counts the failed calls a [Client](crate::client::Client)
+//! makes to each base URL, or to each route with
+//! [per_route](CircuitBreaker::per_route). Once enough of the calls in a
+//! window fail, the circuit opens, and calls fail with
+//! [CircuitOpen](crate::client::PhalanxClientError::CircuitOpen) without
+//! being sent. After a while the circuit is half open, letting a probe call
+//! through; the circuit closes again if it succeeds, and stays open if not.
+//!
+//! ```ignore
+//! let breaker = CircuitBreaker::new()
+//!     .failure_rate(0.5)
+//!     .min_calls(20)
+//!     .open_for(Duration::from_secs(10));
+//!
+//! // Clones share circuits, so clients of the same service trip together
+//! let posts = BlogClient(Client::url("http://posts").circuit_breaker(breaker.clone()));
+//! let users = UserClient(Client::url("http://users").circuit_breaker(breaker.clone()));
+//!
+//! for circuit in breaker.circuits() {
+//!     metrics.gauge(&circuit.key, circuit.state as u8);
+//! }
+//! ```
+//!
+//! Calls fail if they don't get a response, or are responded to with a
+//! `5xx` status. Calls cancelled before they finish, such as by a timeout,
+//! count as failures too.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::client::PhalanxClientError;
+
+/// The source of the current time, which tests replace with a [ManualClock]
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when told to
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+impl ManualClock {
+    /// A clock stopped at the current time
+    pub fn new() -> Self {
+        ManualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Whether calls are let through a circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls are sent
+    Closed,
+    /// Calls fail without being sent
+    Open,
+    /// A limited number of probe calls are sent, deciding whether the circuit closes
+    HalfOpen,
+}
+
+/// The state of a circuit, for metrics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitStats {
+    /// The base URL, followed by the route's `Server::method` name if circuits
+    /// are per route
+    pub key: String,
+    pub state: CircuitState,
+    /// Calls finished in the current window
+    pub calls: u32,
+    /// Calls failed in the current window
+    pub failures: u32,
+}
+
+/// Opens the circuits of services failing too many calls
+///
+/// Cloning a breaker is cheap, and clones share the same circuits.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+    failure_rate: f64,
+    min_calls: u32,
+    window: Duration,
+    open_for: Duration,
+    probes: u32,
+    per_route: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        CircuitBreaker {
+            circuits: Arc::default(),
+            failure_rate: 0.5,
+            min_calls: 10,
+            window: Duration::from_secs(10),
+            open_for: Duration::from_secs(30),
+            probes: 1,
+            per_route: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Set the share of calls in a window which must fail for the circuit to open
+    ///
+    /// # Panics
+    /// If `failure_rate` isn't more than zero and at most one
+    pub fn failure_rate(mut self, failure_rate: f64) -> Self {
+        assert!(
+            failure_rate > 0.0 && failure_rate <= 1.0,
+            "the failure rate must be more than zero and at most one"
+        );
+        self.failure_rate = failure_rate;
+        self
+    }
+
+    /// Set how many calls a window needs before its failure rate can open the circuit
+    pub fn min_calls(mut self, min_calls: u32) -> Self {
+        self.min_calls = min_calls;
+        self
+    }
+
+    /// Set how long calls are counted for, before counting starts again
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Set how long circuits stay open before letting a probe through
+    pub fn open_for(mut self, open_for: Duration) -> Self {
+        self.open_for = open_for;
+        self
+    }
+
+    /// Set how many probes may be sent at once while a circuit is half open
+    ///
+    /// The first probe to finish decides whether the circuit closes.
+    ///
+    /// # Panics
+    /// If `probes` is zero
+    pub fn probes(mut self, probes: u32) -> Self {
+        assert!(
+            probes > 0,
+            "half open circuits must let at least one probe through"
+        );
+        self.probes = probes;
+        self
+    }
+
+    /// Set whether each route has its own circuit, rather than each base URL
+    pub fn per_route(mut self, per_route: bool) -> Self {
+        self.per_route = per_route;
+        self
+    }
+
+    /// Set the clock deciding when windows end and circuits half open
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The key of the circuit calls to `route` at `url` go through
+    pub fn key(&self, url: &str, route: &str) -> String {
+        if self.per_route {
+            format!("{} {}", url, route)
+        } else {
+            String::from(url)
+        }
+    }
+
+    /// The state of the circuit `key`; circuits without calls are closed
+    pub fn state(&self, key: &str) -> CircuitState {
+        let now = self.clock.now();
+        match self.circuits.lock().unwrap().get(key) {
+            Some(circuit) => circuit.state(now),
+            None => CircuitState::Closed,
+        }
+    }
+
+    /// The state of every circuit with calls
+    pub fn circuits(&self) -> Vec<CircuitStats> {
+        let now = self.clock.now();
+        let circuits = self.circuits.lock().unwrap();
+        let mut stats: Vec<_> = circuits
+            .iter()
+            .map(|(key, circuit)| CircuitStats {
+                key: key.clone(),
+                state: circuit.state(now),
+                calls: circuit.calls,
+                failures: circuit.failures,
+            })
+            .collect();
+        stats.sort_by(|a, b| a.key.cmp(&b.key));
+        stats
+    }
+
+    /// Ask to make a call through the circuit `key`
+    ///
+    /// Fails with [CircuitOpen](PhalanxClientError::CircuitOpen) if the
+    /// circuit is open, or half open with every probe taken. The outcome of
+    /// the call is recorded with the returned [Permit], which counts as a
+    /// failure if it's dropped without one.
+    pub fn acquire(&self, key: String) -> Result<Permit, PhalanxClientError> {
+        let now = self.clock.now();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(key.clone()).or_insert_with(|| Circuit {
+            phase: Phase::Closed,
+            window_start: now,
+            calls: 0,
+            failures: 0,
+        });
+
+        if let Phase::Open { until } = circuit.phase {
+            if now >= until {
+                circuit.phase = Phase::HalfOpen { probing: 0 };
+            }
+        }
+        let probe = match &mut circuit.phase {
+            Phase::Closed => false,
+            Phase::HalfOpen { probing } if *probing < self.probes => {
+                *probing += 1;
+                true
+            }
+            Phase::Open { .. } | Phase::HalfOpen { .. } => {
+                return Err(PhalanxClientError::CircuitOpen(key))
+            }
+        };
+        drop(circuits);
+
+        Ok(Permit {
+            breaker: self.clone(),
+            key,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, key: &str, probe: bool, success: bool) {
+        let now = self.clock.now();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(key) {
+            Some(circuit) => circuit,
+            None => return,
+        };
+
+        match circuit.phase {
+            // Probes which finish after the circuit closed or reopened are ignored
+            Phase::HalfOpen { .. } if probe => {
+                if success {
+                    circuit.phase = Phase::Closed;
+                    circuit.reset(now);
+                } else {
+                    circuit.phase = Phase::Open {
+                        until: now + self.open_for,
+                    };
+                }
+            }
+            Phase::Closed if !probe => {
+                if now.duration_since(circuit.window_start) >= self.window {
+                    circuit.reset(now);
+                }
+                circuit.calls += 1;
+                if !success {
+                    circuit.failures += 1;
+                }
+
+                let rate = f64::from(circuit.failures) / f64::from(circuit.calls);
+                if circuit.calls >= self.min_calls && rate >= self.failure_rate {
+                    circuit.phase = Phase::Open {
+                        until: now + self.open_for,
+                    };
+                    circuit.reset(now);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new()
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("failure_rate", &self.failure_rate)
+            .field("min_calls", &self.min_calls)
+            .field("window", &self.window)
+            .field("open_for", &self.open_for)
+            .field("probes", &self.probes)
+            .field("per_route", &self.per_route)
+            .finish()
+    }
+}
+
+struct Circuit {
+    phase: Phase,
+    window_start: Instant,
+    calls: u32,
+    failures: u32,
+}
+
+enum Phase {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probing: u32 },
+}
+
+impl Circuit {
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.phase {
+            Phase::Closed => CircuitState::Closed,
+            Phase::Open { until } if now < until => CircuitState::Open,
+            Phase::Open { .. } | Phase::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Start a new window
+    fn reset(&mut self, now: Instant) {
+        self.window_start = now;
+        self.calls = 0;
+        self.failures = 0;
+    }
+}
+
+/// Permission to make a call through a circuit, recording its outcome
+#[derive(Debug)]
+pub struct Permit {
+    breaker: CircuitBreaker,
+    key: String,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit {
+    pub fn succeed(mut self) {
+        self.record(true);
+    }
+
+    pub fn fail(mut self) {
+        self.record(false);
+    }
+
+    fn record(&mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(&self.key, self.probe, success);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.record(false);
+        }
+    }
+}
@@ -1,28 +1,266 @@
-use std::{future::Future, string::FromUtf8Error};
+use std::{
+    convert::TryFrom,
+    future::Future,
+    string::FromUtf8Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use actix_web::{client::Client as ActixClient, dev::Payload, FromRequest, HttpRequest};
 use err_derive::Error;
 
 use futures::future::{err, ok, Ready};
-use reqwest::{Client as ReqwestClient, Error as ReqwestError, Response};
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE, RETRY_AFTER},
+    Client as ReqwestClient, Error as ReqwestError, Request, RequestBuilder, Response, StatusCode,
+};
 
-use crate::util::AsyncTryFrom;
+use crate::{
+    balance::{Balancer, Tracked},
+    breaker::CircuitBreaker,
+    deadline::Deadline,
+    limit::{Limiter, Permit},
+    loader::{Loader, Loaders, DEFAULT_WINDOW},
+    retry::RetryPolicy,
+    server::rate_limit::{LIMIT_HEADER, RESET_HEADER},
+    util::AsyncTryFrom,
+};
 
+/// Sends a service's calls
+///
+/// Cloning a client is cheap, and clones share its connections, loaders and limits.
+#[derive(Clone)]
 pub struct Client {
     pub client: ReqwestClient,
     pub url: String,
+    /// How long calls to `#[batch_of]` routes wait for others to join them
+    batch_window: Duration,
+    loaders: Arc<Loaders>,
+    retry_policy: RetryPolicy,
+    /// How long calls may take, unless a route sets its own with `#[timeout]`
+    timeout: Option<Duration>,
+    circuit_breaker: Option<CircuitBreaker>,
+    /// Picks the replica each request is sent to, in place of `url`
+    balancer: Option<Balancer>,
+    limiter: Option<Limiter>,
+    /// Builds the clients WebSockets are opened with
+    ws_client: Option<Arc<dyn Fn() -> ActixClient + Send + Sync>>,
+    /// Id of the next JSON-RPC call made by this client
+    rpc_id: Arc<AtomicU64>,
 }
 
 impl Client {
     pub fn new(client: ReqwestClient, url: String) -> Self {
-        Client { client, url }
+        Client {
+            client,
+            url,
+            batch_window: DEFAULT_WINDOW,
+            loaders: Arc::default(),
+            retry_policy: RetryPolicy::default(),
+            timeout: None,
+            circuit_breaker: None,
+            balancer: None,
+            limiter: None,
+            ws_client: None,
+            rpc_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// A client sending requests with `client`, spread across the replicas of `balancer`
+    ///
+    /// Circuit breakers keep a circuit for each replica.
+    pub fn balanced(client: ReqwestClient, balancer: Balancer) -> Self {
+        let url = balancer.endpoints().remove(0).url;
+        Client {
+            balancer: Some(balancer),
+            ..Self::new(client, url)
+        }
+    }
+
+    pub fn balancer(&self) -> Option<&Balancer> {
+        self.balancer.as_ref()
+    }
+
+    /// Set how failed requests are retried, unless a route overrides it with `#[retry(...)]`
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Send `req` to the route named `route`, retrying it as `policy` allows
+    ///
+    /// Waits for the request's turn if the client has a concurrency limit,
+    /// and fails without sending it if the route's circuit is open. Balanced
+    /// clients send each retry to the replica picked for it then.
+    pub async fn send(
+        &self,
+        route: &str,
+        req: RequestBuilder,
+        policy: &RetryPolicy,
+    ) -> Result<Response, PhalanxClientError> {
+        let limited = match &self.limiter {
+            Some(limiter) => Some(limiter.acquire(route).await?),
+            None => None,
+        };
+        let request = req.build()?;
+
+        // The first attempt goes to the replica its URL was written with,
+        // unless the balancer picks replicas as requests are sent
+        let mut retry = false;
+        let res = policy
+            .send_each(request, |mut request| {
+                let tracked = self.balancer.as_ref().and_then(|balancer| {
+                    if retry || balancer.picks_on_send() {
+                        balancer.repick(&mut request)
+                    } else {
+                        balancer.track(request.url())
+                    }
+                });
+                retry = true;
+                self.attempt(route, request, tracked, limited.as_ref())
+            })
+            .await;
+
+        let res = res?;
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(rate_limited(res.headers()));
+        }
+        Ok(res)
+    }
+
+    /// Send one attempt at `request` to the route named `route`, through its circuit
+    ///
+    /// `tracked` is the replica the request is to, if the client is balanced,
+    /// and `limited` its turn under the client's concurrency limit, which
+    /// adapts to the response of every attempt rather than just the last.
+    async fn attempt(
+        &self,
+        route: &str,
+        request: Request,
+        tracked: Option<Tracked>,
+        limited: Option<&Permit>,
+    ) -> Result<Result<Response, ReqwestError>, PhalanxClientError> {
+        let url = tracked
+            .as_ref()
+            .map_or(self.url.as_str(), |tracked| tracked.url());
+        let permit = match &self.circuit_breaker {
+            Some(breaker) => Some(breaker.acquire(breaker.key(url, route))?),
+            None => None,
+        };
+        let res = self.client.execute(request).await;
+
+        let success = matches!(&res, Ok(res) if !res.status().is_server_error());
+        match permit {
+            Some(permit) if success => permit.succeed(),
+            Some(permit) => permit.fail(),
+            None => {}
+        }
+        if let Some(tracked) = tracked {
+            tracked.record(success, &self.client);
+        }
+        if let (Some(permit), Ok(res)) = (limited, &res) {
+            permit.record(res.status());
+        }
+        Ok(res)
+    }
+
+    /// Set how many requests may be in flight at once, queueing any more
+    pub fn concurrency_limit(mut self, limiter: Limiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    pub fn limiter(&self) -> Option<&Limiter> {
+        self.limiter.as_ref()
+    }
+
+    /// Set the circuit breaker calls go through, failing fast while the service is down
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    /// Set how the actix clients WebSockets are opened with are built, such as
+    /// to add headers or a TLS connector
+    pub fn websockets<F>(mut self, build: F) -> Self
+    where
+        F: Fn() -> ActixClient + Send + Sync + 'static,
+    {
+        self.ws_client = Some(Arc::new(build));
+        self
+    }
+
+    /// An actix client to open a WebSocket with
+    ///
+    /// Unless set with [Client::websockets], this is a default client whose
+    /// handshakes end after the client's timeout.
+    pub fn ws_client(&self) -> ActixClient {
+        match (&self.ws_client, self.timeout) {
+            (Some(build), _) => build(),
+            (None, Some(timeout)) => ActixClient::builder().timeout(timeout).finish(),
+            (None, None) => ActixClient::default(),
+        }
+    }
+
+    /// Set how long calls may take, including retries and reading the
+    /// response, unless a route sets its own with `#[timeout(ms = ...)]`
+    ///
+    /// Calls have no timeout by default, though calls made while handling a
+    /// request still end at its [Deadline].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn default_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Set how long calls to routes with a `#[batch_of]` list route are held
+    /// on to, waiting for others to send with them
+    pub fn batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = window;
+        self
+    }
+
+    /// The loader coalescing calls to `route`
+    ///
+    /// Used by generated clients for routes with a `#[batch_of]` list route,
+    /// which name them `Server::method` as a client can be shared by services.
+    pub fn loader<K, V>(&self, route: &'static str) -> Arc<Loader<K, V>>
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        self.loaders.get(route, self.batch_window)
+    }
+
+    /// The id to send the next JSON-RPC call with
+    pub(crate) fn next_rpc_id(&self) -> u64 {
+        self.rpc_id.fetch_add(1, Ordering::Relaxed)
     }
 
     pub fn url(url: &str) -> Self {
         Self::new(ReqwestClient::default(), String::from(url))
     }
 
+    /// The URL of `relative_url`, at the replica picked for it if the client is balanced
     pub fn format_url(&self, relative_url: &str) -> String {
-        format!("{}{}", self.url, relative_url)
+        match &self.balancer {
+            Some(balancer) => format!("{}{}", balancer.pick(relative_url), relative_url),
+            None => format!("{}{}", self.url, relative_url),
+        }
     }
 }
 
@@ -58,21 +296,96 @@ pub enum PhalanxClientError {
     ParseError(#[error(source)] FromUtf8Error),
     #[error(display = "error parsing request")]
     SerdeJsonError(#[error(source)] serde_json::Error),
+    #[error(display = "error parsing form body")]
+    SerdeUrlencodedError(#[error(source)] serde_urlencoded::de::Error),
+    #[cfg(feature = "cbor")]
+    #[error(display = "error parsing request")]
+    SerdeCborError(#[error(source)] serde_cbor::Error),
+    #[cfg(feature = "msgpack")]
+    #[error(display = "error parsing request")]
+    MsgPackError(#[error(source)] rmp_serde::decode::Error),
+    #[cfg(feature = "bincode")]
+    #[error(display = "error parsing request")]
+    BincodeError(#[error(source)] bincode::Error),
+    #[cfg(feature = "protobuf")]
+    #[error(display = "error parsing request")]
+    ProtobufError(#[error(source)] prost::DecodeError),
+    #[error(display = "error opening websocket")]
+    WsClientError(#[error(source)] actix_web::client::WsClientError),
+    #[error(display = "websocket protocol error")]
+    WsProtocolError(#[error(source)] actix_http::ws::ProtocolError),
+    #[error(display = "the websocket was closed")]
+    WsClosed,
+    #[error(display = "error response from the server")]
+    RpcError(#[error(source)] crate::jsonrpc::RpcError),
+    #[error(display = "call failed with status {}: {}", status, message)]
+    BatchCallError { status: u16, message: String },
+    #[error(display = "error loading a batch: {}", _0)]
+    BatchLoadError(String),
+    #[error(display = "error decoding response: {}", _0)]
+    DecodeError(String),
+    #[error(display = "error parsing request")]
+    TextParseError(#[error(source)] crate::web::TextParseError),
+    #[error(display = "the call timed out after {:?}", _0)]
+    Timeout(Duration),
+    #[error(display = "the circuit for {} is open", _0)]
+    CircuitOpen(String),
+    #[error(display = "too many requests are waiting for {}", _0)]
+    QueueFull(String),
+    #[error(display = "the request waited over {:?} to be sent", _0)]
+    QueueTimeout(Duration),
+    /// The server's rate limit refused the call, allowing another after `reset`
+    #[error(display = "rate limited, retry in {:?}", reset)]
+    RateLimited { limit: Option<u32>, reset: Duration },
 }
 
-type AsyncTryFromStringFuture = impl Future<Output = Result<String, PhalanxClientError>>;
-
-impl AsyncTryFrom<PhalanxResponse> for String {
-    type Error = PhalanxClientError;
-
-    type Future = AsyncTryFromStringFuture;
+/// The error for a `429 Too Many Requests` response with `headers`
+fn rate_limited(headers: &HeaderMap) -> PhalanxClientError {
+    let number = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    PhalanxClientError::RateLimited {
+        limit: number(LIMIT_HEADER).and_then(|limit| u32::try_from(limit).ok()),
+        reset: Duration::from_secs(
+            number(RESET_HEADER)
+                .or_else(|| number(RETRY_AFTER.as_str()))
+                .unwrap_or(0),
+        ),
+    }
+}
 
-    fn try_from(res: PhalanxResponse) -> Self::Future {
-        async {
-            let res = res.0.error_for_status()?;
-            let bytes = res.bytes().await?;
-            Ok(String::from_utf8(Vec::from(&bytes[..]))?)
+/// Run the generated call `call`, failing with [PhalanxClientError::Timeout]
+/// if it doesn't finish within `timeout`, or before the current
+/// [Deadline] if that's sooner
+///
+/// Requests sent by the call carry the time left in their
+/// [deadline header](crate::deadline::HEADER).
+pub async fn timeout<T, F>(
+    timeout: Option<Duration>,
+    call: F,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    F: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let deadline = match timeout {
+        Some(timeout) => Deadline::current().limit(timeout),
+        None => Deadline::current(),
+    };
+    let remaining = match deadline.remaining() {
+        // Report the call's own budget rather than the nothing left of the caller's
+        Some(remaining) if remaining == Duration::from_secs(0) => {
+            let budget = timeout.unwrap_or(remaining);
+            return Err(Box::new(PhalanxClientError::Timeout(budget)));
         }
+        Some(remaining) => remaining,
+        None => return call.await,
+    };
+    match actix_web::rt::time::timeout(remaining, deadline.scope(call)).await {
+        Ok(res) => res,
+        Err(_) => Err(Box::new(PhalanxClientError::Timeout(remaining))),
     }
 }
 
@@ -89,23 +402,63 @@ impl AsyncTryFrom<PhalanxResponse> for () {
     }
 }
 
-#[allow(non_camel_case_types)]
-pub enum ContentType {
-    TEXT_PLAIN,
-    APPLICATION_JSON,
+/// A value sent to a route as its payload, or returned from one
+///
+/// Implement this for your own wire types, such as XML or CSV, to use them
+/// as route payloads and return values. Generated clients attach payloads to
+/// their requests and decode return values from responses, while generated
+/// handlers extract payloads from requests. Return values are still sent by
+/// handlers with actix's [Responder](actix_web::Responder).
+pub trait PhalanxPayload: Sized {
+    type ExtractFuture: Future<Output = Result<Self, actix_web::Error>>;
+    type DecodeFuture: Future<Output = Result<Self, PhalanxClientError>>;
+
+    /// Attach the payload to a request, with its content type and encoded body
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>>;
+
+    /// Extract the payload from a request to a route, usually by reading the
+    /// body with [read_payload](crate::server::read_payload)
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture;
+
+    /// Decode the payload from the response to a call
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture;
 }
 
-impl ContentType {
-    pub fn header_value(self) -> &'static str {
-        match self {
-            ContentType::TEXT_PLAIN => "text/plain",
-            ContentType::APPLICATION_JSON => "application/json",
-        }
+/// Return values are decoded as payloads
+impl<T: PhalanxPayload> AsyncTryFrom<PhalanxResponse> for T {
+    type Error = PhalanxClientError;
+    type Future = T::DecodeFuture;
+
+    fn try_from(res: PhalanxResponse) -> Self::Future {
+        T::decode(res)
     }
 }
 
-impl From<&String> for ContentType {
-    fn from(_: &String) -> Self {
-        Self::TEXT_PLAIN
+type StringDecodeFuture = impl Future<Output = Result<String, PhalanxClientError>>;
+
+impl PhalanxPayload for String {
+    type ExtractFuture = <String as FromRequest>::Future;
+    type DecodeFuture = StringDecodeFuture;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        Ok(req.header(CONTENT_TYPE, "text/plain").body(self))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        String::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let res = res.0.error_for_status()?;
+            let bytes = res.bytes().await?;
+            Ok(String::from_utf8(Vec::from(&bytes[..]))?)
+        }
     }
 }
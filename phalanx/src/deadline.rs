@@ -0,0 +1,196 @@
+//! Deadlines shared by a tree of calls
+//!
+//! Generated clients send the time left for a call in the
+//! `x-phalanx-deadline` header, in milliseconds. Servers read it into the
+//! [Deadline] of the request, which route methods get with
+//! [Deadline::current]. Handlers still running when it passes are cancelled
+//! and responded to with `504 Gateway Timeout`, and calls made by a handler
+//! with a generated client get the time left, or their own timeout if shorter.
+//!
+//! Any client can send the header, so servers ignore it unless a
+//! [DeadlineConfig] trusting the caller is registered with the app:
+//!
+//! ```ignore
+//! App::new()
+//!     .app_data(DeadlineConfig::default().trust(|req| {
+//!         req.peer_addr().map_or(false, |addr| addr.ip().is_loopback())
+//!     }))
+//!     .phalanx_mount(FeedServer::new())
+//! ```
+//!
+//! ```ignore
+//! #[get("/feed")]
+//! async fn feed(&self) -> Json<Vec<Post>> {
+//!     // Fails once the caller's deadline passes, without waiting for its own timeout
+//!     let posts = self.posts.recent().await?;
+//!
+//!     if Deadline::current().remaining() > Some(Duration::from_millis(100)) {
+//!         // Time left to add recommendations
+//!     }
+//!     ...
+//! }
+//! ```
+
+use std::{
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{dev::Payload, error::Error, FromRequest, HttpRequest};
+use futures::future::{ok, Ready};
+use reqwest::{Request, RequestBuilder};
+
+/// The header the time left for a call is sent in, in milliseconds
+pub const HEADER: &str = "x-phalanx-deadline";
+
+tokio::task_local! {
+    static CURRENT: Deadline;
+}
+
+/// The time by which a request must be responded to, if any
+///
+/// Extracted from the [HEADER] of incoming requests the [DeadlineConfig]
+/// trusts; other requests have no deadline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// No deadline
+    pub fn none() -> Self {
+        Deadline(None)
+    }
+
+    pub fn at(instant: Instant) -> Self {
+        Deadline(Some(instant))
+    }
+
+    /// `timeout` from now, or no deadline if that's too far off to represent
+    pub fn after(timeout: Duration) -> Self {
+        Deadline(Instant::now().checked_add(timeout))
+    }
+
+    /// The deadline of the request being handled, or of the call being made
+    pub fn current() -> Self {
+        CURRENT.try_with(|deadline| *deadline).unwrap_or_default()
+    }
+
+    pub fn instant(&self) -> Option<Instant> {
+        self.0
+    }
+
+    /// The time left, which is zero once the deadline has passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .map(|instant| instant.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        matches!(self.0, Some(instant) if instant <= Instant::now())
+    }
+
+    /// The earlier of the two deadlines
+    pub fn min(self, other: Deadline) -> Self {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => Deadline(Some(a.min(b))),
+            (a, b) => Deadline(a.or(b)),
+        }
+    }
+
+    /// The deadline, or `timeout` from now if that's earlier
+    ///
+    /// A `timeout` too long to represent leaves the deadline as it is.
+    pub fn limit(self, timeout: Duration) -> Self {
+        self.min(Self::after(timeout))
+    }
+
+    /// Run `future` with this as the [current](Deadline::current) deadline
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Send the time left with `req`
+    pub fn propagate(&self, req: RequestBuilder) -> RequestBuilder {
+        match self.remaining() {
+            Some(remaining) => req.header(HEADER, remaining.as_millis() as u64),
+            None => req,
+        }
+    }
+
+    /// Send the time left with `req`, replacing any sent before
+    pub(crate) fn stamp(&self, req: &mut Request) {
+        if let Some(remaining) = self.remaining() {
+            req.headers_mut()
+                .insert(HEADER, (remaining.as_millis() as u64).into());
+        }
+    }
+}
+
+/// Decides whether a request's [HEADER] is honoured
+type Trust = Arc<dyn Fn(&HttpRequest) -> bool + Send + Sync>;
+
+/// The callers whose [HEADER] is honoured
+///
+/// Register with [App::app_data](actix_web::App::app_data). By default no
+/// caller is trusted, so a public client can't cut a request's time short.
+#[derive(Clone, Default)]
+pub struct DeadlineConfig {
+    trusted: Option<Trust>,
+}
+
+impl DeadlineConfig {
+    /// Honour the deadline of every caller, for services only reachable by other services
+    pub fn trust_all(self) -> Self {
+        self.trust(|_| true)
+    }
+
+    /// Honour the deadline of requests `trusted` returns true for
+    pub fn trust<F>(mut self, trusted: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> bool + Send + Sync + 'static,
+    {
+        self.trusted = Some(Arc::new(trusted));
+        self
+    }
+
+    fn is_trusted(req: &HttpRequest) -> bool {
+        match req
+            .app_data::<Self>()
+            .and_then(|config| config.trusted.as_ref())
+        {
+            Some(trusted) => trusted(req),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for DeadlineConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadlineConfig")
+            .field("trusted", &self.trusted.is_some())
+            .finish()
+    }
+}
+
+impl FromRequest for Deadline {
+    type Config = DeadlineConfig;
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    /// Unreadable deadlines are ignored, as if they weren't sent, as are
+    /// deadlines too far off to represent
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if !DeadlineConfig::is_trusted(req) {
+            return ok(Deadline::none());
+        }
+        let deadline = req
+            .headers()
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(|millis| Self::after(Duration::from_millis(millis)))
+            .unwrap_or_default();
+        ok(deadline)
+    }
+}
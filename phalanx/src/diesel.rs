@@ -0,0 +1,216 @@
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
+
+use actix_web::{
+    dev::Payload, error::BlockingError, http::StatusCode, web, FromRequest, HttpRequest,
+    ResponseError,
+};
+use diesel_crate::{
+    connection::{Connection, TransactionManager},
+    r2d2::{ConnectionManager, Pool, PoolError, PooledConnection},
+    result::{DatabaseErrorKind, Error as DieselError},
+};
+use diesel_migrations::RunMigrationsError;
+use err_derive::Error;
+
+/// A pool of diesel connections managed by r2d2
+pub type DbPool<C> = Pool<ConnectionManager<C>>;
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error(display = "database error: {}", _0)]
+    DieselError(#[error(source)] DieselError),
+    #[error(display = "error acquiring a database connection")]
+    PoolError(#[error(source)] PoolError),
+    #[error(display = "error running migrations")]
+    MigrationError(#[error(source)] RunMigrationsError),
+    #[error(display = "no database pool was registered with the application")]
+    MissingPool,
+    #[error(display = "the blocking thread pool is gone")]
+    Canceled,
+}
+
+impl<E: Into<DbError> + Debug> From<BlockingError<E>> for DbError {
+    fn from(err: BlockingError<E>) -> Self {
+        match err {
+            BlockingError::Error(e) => e.into(),
+            BlockingError::Canceled => DbError::Canceled,
+        }
+    }
+}
+
+/// Maps database errors onto HTTP statuses
+///
+/// Missing rows are reported as `404 Not Found`, unique constraint violations as
+/// `409 Conflict` and an exhausted connection pool as `503 Service Unavailable`.
+impl ResponseError for DbError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DbError::DieselError(DieselError::NotFound) => StatusCode::NOT_FOUND,
+            DbError::DieselError(DieselError::DatabaseError(
+                DatabaseErrorKind::UniqueViolation,
+                _,
+            )) => StatusCode::CONFLICT,
+            DbError::PoolError(_) | DbError::Canceled => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// A pooled database connection, used on actix's blocking thread pool
+///
+/// Connections are checked out of the pool, and queries are run with
+/// [run](DbConnection::run), on the blocking thread pool, so waiting for a
+/// free connection or a slow query never stalls the server's event loop.
+pub struct DbConnection<C: Connection + Send + 'static>(
+    Arc<Mutex<PooledConnection<ConnectionManager<C>>>>,
+);
+
+impl<C: Connection + Send + 'static> DbConnection<C> {
+    /// Check out a connection from the pool
+    pub async fn get(pool: &DbPool<C>) -> Result<Self, DbError> {
+        let pool = pool.clone();
+        let conn = web::block(move || pool.get()).await?;
+        Ok(DbConnection(Arc::new(Mutex::new(conn))))
+    }
+
+    /// Run `f` with the connection on the blocking thread pool
+    ///
+    /// ```ignore
+    /// let post: Post = conn.run(move |conn| posts.find(id).first(conn)).await?;
+    /// ```
+    pub async fn run<F, T, E>(&self, f: F) -> Result<T, DbError>
+    where
+        F: FnOnce(&C) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: Into<DbError> + Debug + Send + 'static,
+    {
+        let conn = self.0.clone();
+        // A panic in another closure leaves the connection usable, if mid-transaction
+        Ok(web::block(move || f(&conn.lock().unwrap_or_else(PoisonError::into_inner))).await?)
+    }
+}
+
+impl<C: Connection + Send + 'static> Clone for DbConnection<C> {
+    fn clone(&self) -> Self {
+        DbConnection(self.0.clone())
+    }
+}
+
+type DbConnectionFuture<C> = impl Future<Output = Result<DbConnection<C>, actix_web::Error>>;
+
+/// Connection extractor. Requires the pool to be registered with
+/// [App::data](actix_web::App::data).
+impl<C: Connection + Send + 'static> FromRequest for DbConnection<C> {
+    type Error = actix_web::Error;
+    type Future = DbConnectionFuture<C>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<web::Data<DbPool<C>>>().cloned();
+        async move {
+            let pool = pool.ok_or(DbError::MissingPool)?;
+            Ok(DbConnection::get(&pool).await?)
+        }
+    }
+}
+
+/// Run `f` inside a transaction on `conn`
+///
+/// The transaction is committed if the future returned by `f` resolves to `Ok`,
+/// and rolled back if it resolves to `Err` or is dropped before it resolves,
+/// such as when the client disconnects or the route times out. Used by
+/// routes marked `#[transactional]`.
+///
+/// Beginning, committing and rolling back the transaction run on the blocking
+/// thread pool, like the queries made with the connection. If committing or
+/// rolling back fails, that error is returned in place of the route's result,
+/// and the transaction is rolled back once more before the connection goes
+/// back to the pool.
+pub async fn transaction<C, F, Fut, T, E>(conn: DbConnection<C>, f: F) -> Result<T, E>
+where
+    C: Connection + Send + 'static,
+    F: FnOnce(DbConnection<C>) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: From<DbError>,
+{
+    // Armed first, as beginning the transaction may still finish on the
+    // blocking thread pool if this is dropped while waiting for it
+    let mut guard = TransactionGuard {
+        conn: conn.clone(),
+        finished: false,
+    };
+    conn.run(|conn| conn.transaction_manager().begin_transaction(conn))
+        .await?;
+
+    let res = f(conn).await;
+    let finish = match res {
+        Ok(_) => {
+            guard
+                .conn
+                .run(|conn| conn.transaction_manager().commit_transaction(conn))
+                .await
+        }
+        Err(_) => {
+            guard
+                .conn
+                .run(|conn| conn.transaction_manager().rollback_transaction(conn))
+                .await
+        }
+    };
+    // Transactions which fail to commit or roll back are rolled back by the guard
+    if finish.is_ok() {
+        guard.finished = true;
+    }
+    finish.map_err(E::from)?;
+    res
+}
+
+/// Rolls back the transaction on `conn` when dropped, unless it finished or
+/// never began
+struct TransactionGuard<C: Connection + Send + 'static> {
+    conn: DbConnection<C>,
+    finished: bool,
+}
+
+impl<C: Connection + Send + 'static> Drop for TransactionGuard<C> {
+    /// Rolled back on a thread of its own, as the blocking thread pool skips
+    /// work whose result nobody waits for, and the connection is only
+    /// returned to the pool afterwards
+    fn drop(&mut self) {
+        if !self.finished {
+            let conn = self.conn.0.clone();
+            thread::spawn(move || {
+                let conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+                let manager = conn.transaction_manager();
+                if manager.get_transaction_depth() > 0 {
+                    // Nothing can be done about a failed rollback while dropping
+                    let _ = manager.rollback_transaction(&**conn);
+                }
+            });
+        }
+    }
+}
+
+/// Run embedded migrations against a connection from `pool`
+///
+/// `migrate` is usually the `run` function generated by diesel_migrations'
+/// `embed_migrations!` macro, i.e. `run_migrations(&pool, embedded_migrations::run)`.
+pub async fn run_migrations<C, F>(pool: &DbPool<C>, migrate: F) -> Result<(), DbError>
+where
+    C: Connection + Send + 'static,
+    F: FnOnce(&C) -> Result<(), RunMigrationsError> + Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || -> Result<(), DbError> {
+        let conn = pool.get()?;
+        migrate(&conn)?;
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
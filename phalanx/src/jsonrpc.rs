@@ -0,0 +1,406 @@
+//! JSON-RPC 2.0 transport
+//!
+//! Services declared with `#[phalanx(BlogClient, rpc = BlogRpcClient)]`
+//! implement [JsonRpcService], which calls their routes by method name.
+//! Mount it with
+//! [phalanx_mount_rpc](crate::server::mount::PhalanxMount::phalanx_mount_rpc)
+//! to accept JSON-RPC requests, batches and notifications posted to `/rpc`.
+//! The `rpc` client gets the same methods as the REST client, sent as
+//! JSON-RPC calls.
+//!
+//! Parameters may be passed by name, using the names of the route's
+//! arguments, or by position in the order the arguments are declared.
+//! Wrapped payloads, such as `Json<T>` or `Form<T>`, are passed as their
+//! inner value. Routes whose arguments or return type can't be represented
+//! as JSON, such as streams, multipart forms, raw requests and responses, are
+//! left out, as are WebSockets, subscriptions and transactional routes.
+//!
+//! ```ignore
+//! #[phalanx(BlogClient, rpc = BlogRpcClient)]
+//! impl BlogServer {
+//!     #[get("/posts/{id}")]
+//!     async fn read_post(&self, id: u32) -> Result<Post, NotFound> {
+//!         ...
+//!     }
+//! }
+//!
+//! App::new().phalanx_mount_rpc(BlogServer)
+//!
+//! // --> {"jsonrpc": "2.0", "method": "read_post", "params": {"id": 1}, "id": 1}
+//! // <-- {"jsonrpc": "2.0", "result": {"title": ...}, "id": 1}
+//! ```
+
+use std::{convert::TryFrom, fmt, sync::Arc, time::Duration};
+
+use actix_web::{
+    http::StatusCode,
+    web::{self, Bytes, Data, ServiceConfig},
+    HttpRequest, HttpResponse,
+};
+use futures::future::{join_all, LocalBoxFuture};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    client::{Client, PhalanxClientError},
+    deadline::Deadline,
+    retry::RetryPolicy,
+    server::PhalanxServer,
+};
+
+/// The path JSON-RPC requests are posted to
+pub const PATH: &str = "/rpc";
+
+/// The protocol version sent in every request and response
+pub const VERSION: &str = "2.0";
+
+/// The request body wasn't valid JSON
+pub const PARSE_ERROR: i64 = -32700;
+/// The request wasn't a valid request object
+pub const INVALID_REQUEST: i64 = -32600;
+/// No route is named by the request's method
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The request's params couldn't be decoded as the route's arguments
+pub const INVALID_PARAMS: i64 = -32602;
+/// The call failed within the server, such as when its result couldn't be encoded
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The route returned an error, whose HTTP status is sent as the error's data
+pub const SERVER_ERROR: i64 = -32000;
+
+/// The most calls a batch may hold; larger batches are refused as invalid
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// The result of calling a route
+pub type CallFuture = LocalBoxFuture<'static, Result<Value, RpcError>>;
+
+/// A service whose routes can be called over JSON-RPC
+///
+/// Implemented by `#[phalanx]` for servers declared with an `rpc` client or
+/// with [batch](crate::batch).
+pub trait JsonRpcService: PhalanxServer {
+    /// Call the route named `method`
+    fn call(self: Arc<Self>, method: String, params: Params) -> CallFuture;
+}
+
+/// A call, or a notification if it has no id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Params,
+    /// Absent for notifications, which aren't responded to
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Value>,
+}
+
+impl Request {
+    pub fn new(method: &str, params: Params, id: Option<Value>) -> Self {
+        Request {
+            jsonrpc: String::from(VERSION),
+            method: String::from(method),
+            params,
+            id,
+        }
+    }
+}
+
+/// The parameters of a [Request], passed by name or by position
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Params {
+    ByPosition(Vec<Value>),
+    ByName(Map<String, Value>),
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params::ByName(Map::new())
+    }
+}
+
+impl Params {
+    /// Add the parameter `name`
+    pub fn with<T: Serialize>(mut self, name: &str, value: &T) -> serde_json::Result<Self> {
+        let value = serde_json::to_value(value)?;
+        match &mut self {
+            Params::ByPosition(values) => values.push(value),
+            Params::ByName(values) => {
+                values.insert(String::from(name), value);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Take the parameter `name`, or the one at `position` if passed by position
+    ///
+    /// Missing parameters are read as `null`, so optional arguments may be left out.
+    pub fn take<T: DeserializeOwned>(
+        &mut self,
+        name: &str,
+        position: usize,
+    ) -> Result<T, RpcError> {
+        let value = match self {
+            Params::ByPosition(values) => values.get_mut(position).map(Value::take),
+            Params::ByName(values) => values.remove(name),
+        };
+        serde_json::from_value(value.unwrap_or(Value::Null))
+            .map_err(|err| RpcError::invalid_params(format!("invalid `{}`: {}", name, err)))
+    }
+}
+
+/// The response to a call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    /// `null` if the request's id couldn't be read
+    pub id: Value,
+}
+
+impl Response {
+    pub fn new(result: Result<Value, RpcError>, id: Value) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Response {
+            jsonrpc: String::from(VERSION),
+            result,
+            error,
+            id,
+        }
+    }
+
+    pub fn into_result(self) -> Result<Value, RpcError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.result.unwrap_or(Value::Null)),
+        }
+    }
+}
+
+/// An error object sent in place of a result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new<S: Into<String>>(code: i64, message: S) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn parse_error<S: Into<String>>(message: S) -> Self {
+        Self::new(PARSE_ERROR, message)
+    }
+
+    pub fn invalid_request<S: Into<String>>(message: S) -> Self {
+        Self::new(INVALID_REQUEST, message)
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(METHOD_NOT_FOUND, format!("no method named `{}`", method))
+    }
+
+    pub fn invalid_params<S: Into<String>>(message: S) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    pub fn internal_error<S: Into<String>>(message: S) -> Self {
+        Self::new(INTERNAL_ERROR, message)
+    }
+
+    /// An error returned by a route, as a [SERVER_ERROR] carrying the status
+    /// it would have been sent with
+    ///
+    /// Routes' statuses are kept apart from the protocol's own errors, so a
+    /// route answering `404 Not Found` isn't taken for a missing method.
+    pub fn from_error<E: Into<actix_web::Error>>(err: E) -> Self {
+        let err = err.into();
+        let status = err.as_response_error().status_code();
+        Self::new(SERVER_ERROR, err.to_string())
+            .with_data(serde_json::json!({ "status": status.as_u16() }))
+    }
+
+    /// The limit and time until another call is allowed, if the call was
+    /// refused by the route's rate limit
+    fn rate_limit(&self) -> Option<(Option<u32>, Duration)> {
+        let data = self.data.as_ref()?;
+        if data["status"] != StatusCode::TOO_MANY_REQUESTS.as_u16() {
+            return None;
+        }
+        let limit = data["limit"]
+            .as_u64()
+            .and_then(|limit| u32::try_from(limit).ok());
+        let reset = Duration::from_secs(data["reset"].as_u64().unwrap_or(0));
+        Some((limit, reset))
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Encode a route's return value as a result
+pub fn to_result<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|err| RpcError::internal_error(err.to_string()))
+}
+
+/// Deserialize a field which may be `null`, but is `None` only when absent
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// Handle the body of a request to `/rpc`, returning the response to send
+///
+/// Batches are called concurrently, and those of more than [MAX_BATCH_SIZE]
+/// calls refused. Returns `None` if there's nothing to respond with, as the
+/// request was a notification or a batch of them.
+pub async fn handle<S: JsonRpcService>(server: Arc<S>, body: &[u8]) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(err) => {
+            let error = RpcError::parse_error(err.to_string());
+            return to_result(Response::new(Err(error), Value::Null)).ok();
+        }
+    };
+
+    match request {
+        Value::Array(batch) if batch.is_empty() => {
+            let error = RpcError::invalid_request("empty batch");
+            to_result(Response::new(Err(error), Value::Null)).ok()
+        }
+        Value::Array(batch) if batch.len() > MAX_BATCH_SIZE => {
+            let error = RpcError::invalid_request(format!(
+                "batches may have at most {} calls",
+                MAX_BATCH_SIZE
+            ));
+            to_result(Response::new(Err(error), Value::Null)).ok()
+        }
+        Value::Array(batch) => {
+            let calls = batch
+                .into_iter()
+                .map(|request| handle_one(server.clone(), request));
+            let responses: Vec<_> = join_all(calls).await.into_iter().flatten().collect();
+            if responses.is_empty() {
+                None
+            } else {
+                to_result(responses).ok()
+            }
+        }
+        request => handle_one(server, request)
+            .await
+            .and_then(|response| to_result(response).ok()),
+    }
+}
+
+async fn handle_one<S: JsonRpcService>(server: Arc<S>, request: Value) -> Option<Response> {
+    let id = request.get("id").cloned();
+    let request = match serde_json::from_value::<Request>(request) {
+        Ok(request) if request.jsonrpc == VERSION => request,
+        Ok(_) => {
+            let error = RpcError::invalid_request(format!("expected jsonrpc \"{}\"", VERSION));
+            return Some(Response::new(Err(error), id.unwrap_or_default()));
+        }
+        Err(err) => {
+            let error = RpcError::invalid_request(err.to_string());
+            return Some(Response::new(Err(error), id.unwrap_or_default()));
+        }
+    };
+
+    let result = server.call(request.method, request.params).await;
+    request.id.map(|id| Response::new(result, id))
+}
+
+/// Mount [JsonRpcService] `S` at [PATH]
+///
+/// The server must be registered as app data, as
+/// [phalanx_mount_rpc](crate::server::mount::PhalanxMount::phalanx_mount_rpc) does.
+pub fn mount<S: JsonRpcService + 'static>(config: &mut ServiceConfig) {
+    config.route(PATH, web::post().to(endpoint::<S>));
+}
+
+/// Calls are made with the request's deadline, failing on their own once it
+/// passes, and within the request, so limits are kept by its app and tell
+/// its caller apart
+async fn endpoint<S: JsonRpcService>(
+    req: HttpRequest,
+    server: Data<S>,
+    deadline: Deadline,
+    body: Bytes,
+) -> HttpResponse {
+    let calls = deadline.scope(handle(server.into_inner(), &body));
+    match crate::server::scope(req, calls).await {
+        Some(response) => HttpResponse::Ok().json(response),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+/// Call `method` on the server, decoding its result as `T`
+///
+/// Used by generated `rpc` clients.
+pub async fn call<T: DeserializeOwned>(
+    client: &Client,
+    method: &str,
+    params: Params,
+) -> Result<T, PhalanxClientError> {
+    let id = client.next_rpc_id();
+    let request = Request::new(method, params, Some(Value::from(id)));
+    let req = client.client.post(&client.format_url(PATH)).json(&request);
+    let response: Response = client
+        .send(method, req, &RetryPolicy::never())
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let result = response
+        .into_result()
+        .map_err(|err| match err.rate_limit() {
+            Some((limit, reset)) => PhalanxClientError::RateLimited { limit, reset },
+            None => PhalanxClientError::from(err),
+        })?;
+    Ok(serde_json::from_value(result)?)
+}
+
+/// Send `method` as a notification, which the server calls without responding
+pub async fn notify(
+    client: &Client,
+    method: &str,
+    params: Params,
+) -> Result<(), PhalanxClientError> {
+    let request = Request::new(method, params, None);
+    let req = client.client.post(&client.format_url(PATH)).json(&request);
+    client
+        .send(method, req, &RetryPolicy::never())
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
@@ -1,6 +1,19 @@
 #![feature(type_alias_impl_trait)]
 
+pub mod balance;
+pub mod batch;
+pub mod breaker;
 pub mod client;
+pub mod deadline;
+#[cfg(feature = "diesel")]
+pub mod diesel;
+pub mod jsonrpc;
+pub mod limit;
+pub mod loader;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+pub mod pubsub;
+pub mod retry;
 pub mod server;
 pub mod util;
 pub mod web;
@@ -10,14 +23,19 @@ pub use phalanx_codegen::{main, phalanx, PhalanxClient};
 pub mod prelude {
     pub use crate::server::{mount::PhalanxMount, PhalanxServer};
 
-    pub use phalanx_codegen::{connect, delete, get, head, options, patch, post, put, trace};
-    pub use phalanx_codegen::{phalanx, PhalanxClient};
+    pub use phalanx_codegen::{
+        batch_of, concurrency, phalanx, rate_limit, retry, timeout, transactional, PhalanxClient,
+    };
+    pub use phalanx_codegen::{
+        connect, delete, get, head, options, patch, post, put, subscribe, trace, ws,
+    };
 }
 
 pub mod reexports {
     pub use actix_web::{
-        guard, http, middleware, rt, web, App, HttpResponse, HttpServer, Resource, Responder,
+        dev, guard, http, middleware, rt, web, App, Error, FromRequest, HttpRequest, HttpResponse,
+        HttpServer, Resource, Responder, ResponseError,
     };
 
-    pub use reqwest::{Body, Client};
+    pub use reqwest::{Body, Client, Error as ReqwestError, RequestBuilder};
 }
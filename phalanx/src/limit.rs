@@ -0,0 +1,351 @@
+//! Limiting the requests a client has in flight
+//!
+//! A [Limiter] caps how many requests a [Client](crate::client::Client)
+//! sends at once, across all routes and to each route. Requests over the
+//! limit wait in a bounded queue, failing with
+//! [QueueFull](crate::client::PhalanxClientError::QueueFull) if it's full or
+//! [QueueTimeout](crate::client::PhalanxClientError::QueueTimeout) if they
+//! wait too long.
+//!
+//! ```ignore
+//! let limiter = Limiter::new()
+//!     .max(64)
+//!     .per_route(16)
+//!     .queue(1000)
+//!     .queue_timeout(Duration::from_secs(5));
+//! let client = BlogClient(Client::url("http://posts").concurrency_limit(limiter));
+//!
+//! // Thousands of calls, but never more than 16 at once
+//! join_all(ids.iter().map(|id| client.read_post(*id))).await;
+//! ```
+//!
+//! Adaptive limiters start at their maximum, halve their limits when a
+//! response is `429 Too Many Requests` or `503 Service Unavailable`, and
+//! raise them by about one for each limit's worth of other responses, never
+//! going below their minimum.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::rt::time;
+use futures::channel::oneshot;
+use reqwest::StatusCode;
+
+use crate::client::PhalanxClientError;
+
+/// How many requests may wait by default
+pub const DEFAULT_QUEUE: usize = 1024;
+
+/// How many requests are in flight and waiting, for metrics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitStats {
+    /// The route, named `Server::method` by generated clients, or `None` for
+    /// the limit across all routes
+    pub route: Option<String>,
+    /// The current limit, which changes if the limiter is adaptive
+    pub limit: usize,
+    pub in_flight: usize,
+    pub waiting: usize,
+}
+
+/// Limits the requests a client has in flight
+///
+/// Cloning a limiter is cheap, and clones share the same limits, so clients
+/// sharing a limiter are limited together.
+#[derive(Clone)]
+pub struct Limiter {
+    /// The limit across all routes has no route
+    gates: Arc<Mutex<HashMap<Option<String>, Arc<Gate>>>>,
+    max: Option<usize>,
+    per_route: Option<usize>,
+    queue: usize,
+    queue_timeout: Option<Duration>,
+    /// The lowest an adaptive limit goes
+    adaptive: Option<usize>,
+}
+
+impl Limiter {
+    /// A limiter without limits, until they're set
+    pub fn new() -> Self {
+        Limiter {
+            gates: Arc::default(),
+            max: None,
+            per_route: None,
+            queue: DEFAULT_QUEUE,
+            queue_timeout: None,
+            adaptive: None,
+        }
+    }
+
+    /// Set how many requests may be in flight across all routes
+    ///
+    /// # Panics
+    /// If `max` is zero
+    pub fn max(mut self, max: usize) -> Self {
+        assert!(max > 0, "at least one request must be let through");
+        self.max = Some(max);
+        self
+    }
+
+    /// Set how many requests may be in flight to each route
+    ///
+    /// # Panics
+    /// If `max` is zero
+    pub fn per_route(mut self, max: usize) -> Self {
+        assert!(max > 0, "at least one request must be let through");
+        self.per_route = Some(max);
+        self
+    }
+
+    /// Set how many requests may wait for each limit, which fail with
+    /// [QueueFull](PhalanxClientError::QueueFull) beyond it
+    pub fn queue(mut self, queue: usize) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Set how long requests may wait, after which they fail with
+    /// [QueueTimeout](PhalanxClientError::QueueTimeout)
+    ///
+    /// Requests wait until they're let through by default, unless their call times out.
+    pub fn queue_timeout(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    /// Adapt the limits to the responses, between `min` and the maximums set
+    ///
+    /// # Panics
+    /// If `min` is zero
+    pub fn adaptive(mut self, min: usize) -> Self {
+        assert!(min > 0, "at least one request must be let through");
+        self.adaptive = Some(min);
+        self
+    }
+
+    /// Every limit with requests sent through it
+    pub fn stats(&self) -> Vec<LimitStats> {
+        let gates = self.gates.lock().unwrap();
+        let mut stats: Vec<_> = gates
+            .iter()
+            .map(|(route, gate)| {
+                let mut state = gate.state.lock().unwrap();
+                state.prune();
+                LimitStats {
+                    route: route.clone(),
+                    limit: state.limit(),
+                    in_flight: state.in_flight,
+                    waiting: state.waiting.len(),
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| a.route.cmp(&b.route));
+        stats
+    }
+
+    /// Wait to send a request to the route named `route`
+    ///
+    /// The request counts as in flight until the returned [Permit] is dropped.
+    pub async fn acquire(&self, route: &str) -> Result<Permit, PhalanxClientError> {
+        let mut slots = Vec::with_capacity(2);
+        // Requests wait for their route first, so they don't hold on to the
+        // limit across routes while they do
+        if let Some(max) = self.per_route {
+            let gate = self.gate(Some(route), max);
+            slots.push(self.enter(gate, &format!("`{}`", route)).await?);
+        }
+        if let Some(max) = self.max {
+            let gate = self.gate(None, max);
+            slots.push(self.enter(gate, "the client").await?);
+        }
+        Ok(Permit { slots })
+    }
+
+    fn gate(&self, route: Option<&str>, max: usize) -> Arc<Gate> {
+        let mut gates = self.gates.lock().unwrap();
+        gates
+            .entry(route.map(String::from))
+            .or_insert_with(|| {
+                Arc::new(Gate {
+                    max,
+                    min: self.adaptive,
+                    state: Mutex::new(GateState {
+                        limit: max as f64,
+                        in_flight: 0,
+                        waiting: VecDeque::new(),
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    async fn enter(&self, gate: Arc<Gate>, name: &str) -> Result<Slot, PhalanxClientError> {
+        let rx = {
+            let mut state = gate.state.lock().unwrap();
+            state.prune();
+            if state.waiting.is_empty() && state.in_flight < state.limit() {
+                state.in_flight += 1;
+                return Ok(Slot(gate.clone()));
+            }
+            if state.waiting.len() >= self.queue {
+                return Err(PhalanxClientError::QueueFull(String::from(name)));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            rx
+        };
+
+        let mut waiting = Waiting {
+            gate: &gate,
+            rx,
+            entered: false,
+        };
+        if let Some(timeout) = self.queue_timeout {
+            if time::timeout(timeout, &mut waiting.rx).await.is_err() {
+                return Err(PhalanxClientError::QueueTimeout(timeout));
+            }
+        } else {
+            // The sender is only dropped once it's sent, as the gate is still held
+            let _ = (&mut waiting.rx).await;
+        }
+        waiting.entered = true;
+        Ok(Slot(gate.clone()))
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter::new()
+    }
+}
+
+impl fmt::Debug for Limiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Limiter")
+            .field("max", &self.max)
+            .field("per_route", &self.per_route)
+            .field("queue", &self.queue)
+            .field("queue_timeout", &self.queue_timeout)
+            .field("adaptive", &self.adaptive)
+            .finish()
+    }
+}
+
+/// One limit, with the requests waiting for it
+struct Gate {
+    max: usize,
+    min: Option<usize>,
+    state: Mutex<GateState>,
+}
+
+struct GateState {
+    /// Fractional, so adaptive limits can rise by less than one request at a time
+    limit: f64,
+    in_flight: usize,
+    /// Each waiting request is sent its turn
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+impl GateState {
+    fn limit(&self) -> usize {
+        (self.limit as usize).max(1)
+    }
+
+    /// Forget requests which stopped waiting
+    fn prune(&mut self) {
+        self.waiting.retain(|tx| !tx.is_canceled());
+    }
+
+    /// Let waiting requests through while there's room
+    fn wake(&mut self) {
+        while self.in_flight < self.limit() {
+            match self.waiting.pop_front() {
+                Some(tx) => {
+                    if tx.send(()).is_ok() {
+                        self.in_flight += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl Gate {
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.wake();
+    }
+
+    /// Adapt the limit to a response with `status`
+    fn record(&self, status: StatusCode) {
+        let min = match self.min {
+            Some(min) => min as f64,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let overloaded =
+            status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE;
+        state.limit = if overloaded {
+            (state.limit / 2.0).max(min)
+        } else {
+            (state.limit + 1.0 / state.limit).min(self.max as f64)
+        };
+        state.wake();
+    }
+}
+
+/// A request waiting for its turn
+struct Waiting<'a> {
+    gate: &'a Gate,
+    rx: oneshot::Receiver<()>,
+    entered: bool,
+}
+
+impl Drop for Waiting<'_> {
+    /// Requests which stop waiting just as they're let through pass their turn on
+    fn drop(&mut self) {
+        if !self.entered {
+            self.rx.close();
+            if let Ok(Some(())) = self.rx.try_recv() {
+                self.gate.release();
+            }
+        }
+    }
+}
+
+/// A request's place under one limit
+struct Slot(Arc<Gate>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Permission to send a request, which is in flight until this is dropped
+pub struct Permit {
+    slots: Vec<Slot>,
+}
+
+impl Permit {
+    /// Record the status the request was responded to with, adapting adaptive limits
+    pub fn record(&self, status: StatusCode) {
+        for slot in &self.slots {
+            slot.0.record(status);
+        }
+    }
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Permit")
+            .field("slots", &self.slots.len())
+            .finish()
+    }
+}
@@ -0,0 +1,352 @@
+//! Coalescing concurrent calls into list requests
+//!
+//! A route marked `#[batch_of(read_post)]` takes a list of `read_post`'s
+//! argument and returns a list of results in the same order. Generated
+//! clients then hold on to `read_post` calls made within a short window,
+//! send their arguments to the list route in one request and hand each
+//! caller its own result, so code calling `read_post` in a loop makes a
+//! single request.
+//!
+//! ```ignore
+//! #[phalanx(BlogClient)]
+//! impl BlogServer {
+//!     #[get("/posts/{id}")]
+//!     async fn read_post(&self, id: u32) -> Json<Post> {
+//!         ...
+//!     }
+//!
+//!     #[post("/posts/list")]
+//!     #[batch_of(read_post)]
+//!     async fn read_posts(&self, ids: Json<Vec<u32>>) -> Json<Vec<Option<Post>>> {
+//!         ...
+//!     }
+//! }
+//!
+//! // Sent as a single request to `/posts/list`
+//! let posts = join_all(ids.iter().map(|id| client.read_post(*id))).await;
+//! ```
+//!
+//! The batched route must take a single argument. If the list route returns
+//! `Option`s, missing values are returned to their callers as `404` errors.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::rt::time::delay_for;
+use futures::channel::oneshot;
+
+use crate::{batch::MAX_BATCH_SIZE, client::PhalanxClientError};
+
+/// How long calls are held on to by default, waiting for others to join them
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(2);
+
+/// The loaders of a [Client](crate::client::Client), one for each batched route
+#[derive(Default)]
+pub(crate) struct Loaders(Mutex<HashMap<&'static str, Arc<dyn Any + Send + Sync>>>);
+
+impl Loaders {
+    /// Get the loader of `route`, creating it if it doesn't exist
+    pub(crate) fn get<K, V>(&self, route: &'static str, window: Duration) -> Arc<Loader<K, V>>
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        let mut loaders = self.0.lock().unwrap();
+        let loader = loaders
+            .entry(route)
+            .or_insert_with(|| Arc::new(Loader::<K, V>::new(window)))
+            .clone();
+        match loader.downcast::<Loader<K, V>>() {
+            Ok(loader) => loader,
+            Err(_) => panic!(
+                "the loader of `{}` has a different key or value type",
+                route
+            ),
+        }
+    }
+}
+
+/// Coalesces the calls to one route
+pub struct Loader<K, V> {
+    window: Duration,
+    /// The batch new calls join, until it's sent or full
+    open: Mutex<Option<SharedBatch<K, V>>>,
+}
+
+type SharedBatch<K, V> = Arc<Mutex<Batch<K, V>>>;
+
+struct Batch<K, V> {
+    keys: Vec<K>,
+    /// The caller of each key, taken once it's been sent its outcome
+    callers: Vec<Option<oneshot::Sender<Outcome<K, V>>>>,
+}
+
+impl<K, V> Default for Batch<K, V> {
+    fn default() -> Self {
+        Batch {
+            keys: Vec::new(),
+            callers: Vec::new(),
+        }
+    }
+}
+
+/// What a caller waiting on a batch is sent
+enum Outcome<K, V> {
+    Loaded(Result<V, Failure>),
+    /// The batch's leader was dropped, so the caller in this slot sends the batch instead
+    Lead(Batch<K, V>, usize),
+}
+
+/// Why a batch failed, sent to each caller
+///
+/// [PhalanxClientError] can't be sent between threads, so it's made by each caller.
+#[derive(Clone)]
+enum Failure {
+    Fetch(String),
+    Decode(String),
+    Cancelled,
+}
+
+impl From<Failure> for PhalanxClientError {
+    fn from(failure: Failure) -> Self {
+        match failure {
+            Failure::Fetch(message) => PhalanxClientError::BatchLoadError(message),
+            Failure::Decode(message) => PhalanxClientError::DecodeError(message),
+            Failure::Cancelled => {
+                PhalanxClientError::BatchLoadError(String::from("the batch was cancelled"))
+            }
+        }
+    }
+}
+
+impl<K, V> Loader<K, V> {
+    pub fn new(window: Duration) -> Self {
+        Loader {
+            window,
+            open: Mutex::new(None),
+        }
+    }
+}
+
+impl<K: Clone, V> Loader<K, V> {
+    /// Load the value of `key`, along with any other keys requested within the window
+    ///
+    /// The first caller of a batch waits for the window to pass, then calls
+    /// `fetch` with every key and hands out the values, which must be in the
+    /// same order. If that caller is dropped first, another caller of the
+    /// batch sends it with its own `fetch` instead.
+    pub async fn load<F, Fut>(&self, key: K, fetch: F) -> Result<V, PhalanxClientError>
+    where
+        F: FnOnce(Vec<K>) -> Fut,
+        Fut: Future<Output = Result<Vec<V>, Box<dyn Error>>>,
+    {
+        let (tx, rx) = oneshot::channel();
+        let mut rx = Waiting(rx);
+        let leading = {
+            let mut open = self.open.lock().unwrap();
+            match open.as_ref() {
+                Some(batch) => {
+                    let mut batch = batch.lock().unwrap();
+                    batch.keys.push(key);
+                    batch.callers.push(Some(tx));
+                    // Full batches are sent as they are
+                    if batch.keys.len() >= MAX_BATCH_SIZE {
+                        drop(batch);
+                        *open = None;
+                    }
+                    None
+                }
+                None => {
+                    let batch = Arc::new(Mutex::new(Batch {
+                        keys: vec![key],
+                        callers: vec![Some(tx)],
+                    }));
+                    *open = Some(batch.clone());
+                    Some(batch)
+                }
+            }
+        };
+
+        let mut fetch = Some(fetch);
+        if let Some(batch) = leading {
+            let mut guard = CloseGuard {
+                open: &self.open,
+                batch: &batch,
+                waiting: true,
+            };
+            delay_for(self.window).await;
+            guard.waiting = false;
+            drop(guard);
+
+            let batch = std::mem::take(&mut *batch.lock().unwrap());
+            if let Some(fetch) = fetch.take() {
+                rx = Waiting(send(batch, 0, fetch).await);
+            }
+        }
+
+        loop {
+            match (&mut rx.0).await {
+                Ok(Outcome::Loaded(result)) => return Ok(result?),
+                Ok(Outcome::Lead(batch, slot)) => match fetch.take() {
+                    Some(fetch) => rx = Waiting(send(batch, slot, fetch).await),
+                    None => return Err(Failure::Cancelled.into()),
+                },
+                Err(_) => return Err(Failure::Cancelled.into()),
+            }
+        }
+    }
+}
+
+/// Send `batch` on behalf of the caller in `slot`, returning where that caller's outcome is sent
+async fn send<K, V, F, Fut>(
+    mut batch: Batch<K, V>,
+    slot: usize,
+    fetch: F,
+) -> oneshot::Receiver<Outcome<K, V>>
+where
+    K: Clone,
+    F: FnOnce(Vec<K>) -> Fut,
+    Fut: Future<Output = Result<Vec<V>, Box<dyn Error>>>,
+{
+    let (tx, rx) = oneshot::channel();
+    batch.callers[slot] = Some(tx);
+
+    // The keys are kept, so the batch can be handed on if this caller is dropped
+    let keys = batch.keys.clone();
+    let mut sending = SendGuard {
+        batch: Some(batch),
+        slot,
+    };
+    let result = fetch(keys).await;
+    let batch = match sending.batch.take() {
+        Some(batch) => batch,
+        None => return rx,
+    };
+
+    // Values are matched to callers by slot, as callers which have gone leave theirs empty
+    let count = batch.keys.len();
+    match result {
+        Ok(values) if values.len() == count => {
+            for (caller, value) in batch.callers.into_iter().zip(values) {
+                if let Some(caller) = caller {
+                    let _ = caller.send(Outcome::Loaded(Ok(value)));
+                }
+            }
+        }
+        Ok(values) => {
+            let message = format!("expected {} values, got {}", count, values.len());
+            for caller in batch.callers.into_iter().flatten() {
+                let _ = caller.send(Outcome::Loaded(Err(Failure::Decode(message.clone()))));
+            }
+        }
+        Err(err) => {
+            let failure = Failure::Fetch(err.to_string());
+            for caller in batch.callers.into_iter().flatten() {
+                let _ = caller.send(Outcome::Loaded(Err(failure.clone())));
+            }
+        }
+    }
+    rx
+}
+
+/// Hand `batch` to the first of its callers still waiting, other than the one in `slot`
+///
+/// Keys of callers which have gone are left out.
+fn hand_off<K, V>(batch: Batch<K, V>, slot: usize) {
+    let mut remaining = Batch::default();
+    for (i, (key, caller)) in batch.keys.into_iter().zip(batch.callers).enumerate() {
+        match caller {
+            Some(caller) if i != slot && !caller.is_canceled() => {
+                remaining.keys.push(key);
+                remaining.callers.push(Some(caller));
+            }
+            _ => {}
+        }
+    }
+
+    let mut leader = 0;
+    while leader < remaining.callers.len() {
+        let caller = match remaining.callers[leader].take() {
+            Some(caller) => caller,
+            None => {
+                leader += 1;
+                continue;
+            }
+        };
+        match caller.send(Outcome::Lead(remaining, leader)) {
+            Ok(()) => return,
+            // The caller went while the batch was being handed on, so its key is left out too
+            Err(Outcome::Lead(mut batch, _)) => {
+                batch.keys.remove(leader);
+                batch.callers.remove(leader);
+                remaining = batch;
+            }
+            Err(Outcome::Loaded(_)) => return,
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for Loader<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Loader")
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+/// Closes a batch to new callers once its window has passed, and hands it
+/// on if its leader is dropped during the window
+struct CloseGuard<'a, K, V> {
+    open: &'a Mutex<Option<SharedBatch<K, V>>>,
+    batch: &'a SharedBatch<K, V>,
+    /// Whether the window is still open
+    waiting: bool,
+}
+
+impl<K, V> Drop for CloseGuard<'_, K, V> {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if matches!(open.as_ref(), Some(open) if Arc::ptr_eq(open, self.batch)) {
+            *open = None;
+        }
+        drop(open);
+
+        if self.waiting {
+            hand_off(std::mem::take(&mut *self.batch.lock().unwrap()), 0);
+        }
+    }
+}
+
+/// Where a caller's outcome is sent, handing the batch on if the caller is
+/// dropped after being asked to send it
+struct Waiting<K, V>(oneshot::Receiver<Outcome<K, V>>);
+
+impl<K, V> Drop for Waiting<K, V> {
+    fn drop(&mut self) {
+        self.0.close();
+        if let Ok(Some(Outcome::Lead(batch, slot))) = self.0.try_recv() {
+            hand_off(batch, slot);
+        }
+    }
+}
+
+/// Hands a batch on if the caller sending it is dropped before it's sent
+struct SendGuard<K, V> {
+    batch: Option<Batch<K, V>>,
+    slot: usize,
+}
+
+impl<K, V> Drop for SendGuard<K, V> {
+    fn drop(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            hand_off(batch, self.slot);
+        }
+    }
+}
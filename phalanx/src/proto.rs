@@ -0,0 +1,188 @@
+use std::{collections::HashSet, fmt::Write, fs, io, path::Path};
+
+/// A message sent or received by an [Rpc]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtoMessage {
+    /// No message, described as `google.protobuf.Empty`
+    Empty,
+    /// A message type defined in an imported `.proto` file
+    Named(&'static str),
+    /// A request message written out for the RPC, holding its path arguments
+    /// followed by its payload, if it has one
+    Request(Vec<ProtoField>),
+}
+
+/// A field of a [ProtoMessage::Request]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtoField {
+    pub name: &'static str,
+    pub ty: &'static str,
+}
+
+/// A route of a phalanx service, described as an RPC
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rpc {
+    pub name: &'static str,
+    pub method: &'static str,
+    pub path: &'static str,
+    pub request: ProtoMessage,
+    pub response: ProtoMessage,
+}
+
+/// A route of a phalanx service which can't be described as an RPC
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedRoute {
+    pub name: &'static str,
+    pub method: &'static str,
+    pub path: &'static str,
+}
+
+/// A service which can be described in a `.proto` file
+///
+/// Implemented by `#[phalanx]` for every server type. Only routes whose
+/// bodies are protobuf messages on the wire are described: their payload, if
+/// any, is a [Protobuf](crate::web::Protobuf) value and they return either a
+/// `Protobuf` value or `()`, which is sent as `google.protobuf.Empty`. Routes
+/// with path arguments are described with a request message of their own,
+/// holding the path arguments and then the payload, even though the path
+/// arguments are sent in the URL. Other routes are listed in a comment at the
+/// end of the service, so readers of the file can tell it's incomplete.
+pub trait ProtoService {
+    fn name() -> &'static str;
+
+    fn rpcs() -> Vec<Rpc>;
+
+    /// The routes left out of [rpcs](ProtoService::rpcs)
+    fn skipped() -> Vec<SkippedRoute> {
+        Vec::new()
+    }
+}
+
+/// Writes `.proto` files describing phalanx services
+///
+/// ```ignore
+/// ProtoExporter::new()
+///     .package("blog")
+///     .import("post.proto")
+///     .service::<BlogServer>()
+///     .write("blog.proto")?;
+/// ```
+#[derive(Debug, Default)]
+pub struct ProtoExporter {
+    package: Option<String>,
+    imports: Vec<String>,
+    services: Vec<(&'static str, Vec<Rpc>, Vec<SkippedRoute>)>,
+}
+
+impl ProtoExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn package<S: Into<String>>(mut self, package: S) -> Self {
+        self.package = Some(package.into());
+        self
+    }
+
+    /// Import a `.proto` file defining message types used by the services
+    pub fn import<S: Into<String>>(mut self, path: S) -> Self {
+        self.imports.push(path.into());
+        self
+    }
+
+    pub fn service<S: ProtoService>(mut self) -> Self {
+        self.services.push((S::name(), S::rpcs(), S::skipped()));
+        self
+    }
+
+    /// Render the `.proto` file
+    pub fn export(&self) -> String {
+        let rpcs = self.services.iter().flat_map(|(_, rpcs, _)| rpcs.iter());
+        let uses_empty = rpcs
+            .flat_map(|rpc| vec![&rpc.request, &rpc.response])
+            .any(|message| *message == ProtoMessage::Empty);
+
+        let mut out = String::from("syntax = \"proto3\";\n");
+        if let Some(package) = &self.package {
+            let _ = write!(out, "\npackage {};\n", package);
+        }
+
+        if uses_empty || !self.imports.is_empty() {
+            out.push('\n');
+        }
+        if uses_empty {
+            out.push_str("import \"google/protobuf/empty.proto\";\n");
+        }
+        for import in self.imports.iter() {
+            let _ = writeln!(out, "import \"{}\";", import);
+        }
+
+        // Request messages are named after their RPC, and the service too
+        // if another service already has an RPC of the same name
+        let mut names = HashSet::new();
+        for (service, rpcs, skipped) in self.services.iter() {
+            let mut requests = Vec::with_capacity(rpcs.len());
+            for rpc in rpcs.iter() {
+                let fields = match &rpc.request {
+                    ProtoMessage::Request(fields) => fields,
+                    _ => {
+                        requests.push(None);
+                        continue;
+                    }
+                };
+                let mut name = format!("{}Request", rpc.name);
+                if !names.insert(name.clone()) {
+                    name = format!("{}{}", service, name);
+                    names.insert(name.clone());
+                }
+
+                let _ = write!(out, "\nmessage {} {{\n", name);
+                for (i, field) in fields.iter().enumerate() {
+                    let _ = writeln!(out, "  {} {} = {};", field.ty, field.name, i + 1);
+                }
+                out.push_str("}\n");
+                requests.push(Some(name));
+            }
+
+            let _ = write!(out, "\nservice {} {{\n", service);
+            for (rpc, request) in rpcs.iter().zip(requests) {
+                let request = request
+                    .as_deref()
+                    .unwrap_or_else(|| message_name(&rpc.request));
+                let response = message_name(&rpc.response);
+                let _ = writeln!(out, "  // {} {}", rpc.method, rpc.path);
+                let _ = writeln!(
+                    out,
+                    "  rpc {}({}) returns ({});",
+                    rpc.name, request, response
+                );
+            }
+            if !skipped.is_empty() {
+                if !rpcs.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str("  // Left out, as they don't send protobuf messages:\n");
+                for route in skipped.iter() {
+                    let _ = writeln!(out, "  // {} {} ({})", route.method, route.path, route.name);
+                }
+            }
+            out.push_str("}\n");
+        }
+
+        out
+    }
+
+    /// Write the `.proto` file to `path`
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.export())
+    }
+}
+
+/// The name of an RPC's message type, other than a request message
+fn message_name(message: &ProtoMessage) -> &'static str {
+    match message {
+        ProtoMessage::Empty => "google.protobuf.Empty",
+        ProtoMessage::Named(name) => name,
+        ProtoMessage::Request(_) => unreachable!("request messages are named by the exporter"),
+    }
+}
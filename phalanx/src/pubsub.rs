@@ -0,0 +1,304 @@
+//! In-process publish/subscribe
+//!
+//! A [Broker] holds named topics, such as `posts.created`. Server methods
+//! publish messages to a topic, and every current subscriber receives a copy.
+//! Routes marked `#[subscribe("/path")]` return a [Subscription], which is
+//! sent to clients as server-sent events; their generated `subscribe_*`
+//! method returns an [EventSource](crate::web::EventSource) of the messages.
+//!
+//! Each message is sent with its id in the topic. Clients reconnecting with
+//! a `Last-Event-ID` are first sent the messages they missed, as long as the
+//! topic still holds them: topics keep as many recent messages as each
+//! subscriber buffers. Older messages are lost, so delivery across
+//! reconnections is otherwise at most once.
+//!
+//! ```ignore
+//! #[derive(Clone)]
+//! struct BlogServer {
+//!     broker: Broker,
+//! }
+//!
+//! #[phalanx(BlogClient)]
+//! impl BlogServer {
+//!     #[post("/posts")]
+//!     async fn create_post(&self, post: Json<Post>) {
+//!         self.broker.topic("posts.created").publish(post.into_inner());
+//!     }
+//!
+//!     #[subscribe("/posts/created")]
+//!     async fn posts_created(&self) -> Subscription<Post> {
+//!         self.broker.topic("posts.created").subscribe()
+//!     }
+//! }
+//! ```
+
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, RecvError};
+
+use crate::web::{Event, EventStream};
+
+/// How many messages each subscriber buffers by default
+const DEFAULT_CAPACITY: usize = 64;
+
+/// What happens to a subscriber which falls more than its buffer behind
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Skip the messages the subscriber missed, continuing from the oldest buffered one
+    #[default]
+    Skip,
+    /// End the subscription. Clients subscribed over server-sent events reconnect,
+    /// and are sent the messages they missed which the topic still holds.
+    Disconnect,
+}
+
+/// Holds a server's topics
+///
+/// Cloning a broker is cheap, and clones share the same topics.
+#[derive(Clone)]
+pub struct Broker {
+    topics: Arc<Mutex<HashMap<String, Box<dyn Any + Send>>>>,
+    capacity: usize,
+    lag_policy: LagPolicy,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Broker {
+            topics: Arc::default(),
+            capacity: DEFAULT_CAPACITY,
+            lag_policy: LagPolicy::default(),
+        }
+    }
+
+    /// Set how many messages each subscriber of a new topic buffers, which
+    /// is also how many recent messages the topic keeps for reconnecting clients
+    ///
+    /// # Panics
+    /// If `capacity` is zero
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "subscribers must buffer at least one message");
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set what happens to subscribers of new topics which fall behind
+    pub fn lag_policy(mut self, lag_policy: LagPolicy) -> Self {
+        self.lag_policy = lag_policy;
+        self
+    }
+
+    /// Get the topic `name`, creating it if it doesn't exist
+    ///
+    /// # Panics
+    /// If the topic was created with a different message type
+    pub fn topic<T>(&self, name: &str) -> Topic<T>
+    where
+        T: Clone + Send + 'static,
+    {
+        let mut topics = self.topics.lock().unwrap();
+        let topic = topics.entry(String::from(name)).or_insert_with(|| {
+            let (sender, _) = broadcast::channel::<(u64, T)>(self.capacity);
+            Box::new(Topic {
+                name: Arc::from(name),
+                sender,
+                history: Arc::new(Mutex::new(History {
+                    next_id: 1,
+                    messages: VecDeque::with_capacity(self.capacity),
+                })),
+                capacity: self.capacity,
+                lag_policy: self.lag_policy,
+            })
+        });
+
+        match topic.downcast_ref::<Topic<T>>() {
+            Some(topic) => topic.clone(),
+            None => panic!(
+                "topic `{}` was created with a different message type than {}",
+                name,
+                std::any::type_name::<T>()
+            ),
+        }
+    }
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Broker::new()
+    }
+}
+
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let topics = self.topics.lock().unwrap();
+        f.debug_struct("Broker")
+            .field("topics", &topics.keys().collect::<Vec<_>>())
+            .field("capacity", &self.capacity)
+            .field("lag_policy", &self.lag_policy)
+            .finish()
+    }
+}
+
+/// A named topic carrying messages of `T`
+pub struct Topic<T> {
+    name: Arc<str>,
+    /// Sends each message along with its id
+    sender: broadcast::Sender<(u64, T)>,
+    history: Arc<Mutex<History<T>>>,
+    capacity: usize,
+    lag_policy: LagPolicy,
+}
+
+/// The most recent messages of a topic, replayed to reconnecting clients
+struct History<T> {
+    next_id: u64,
+    messages: VecDeque<(u64, T)>,
+}
+
+impl<T> Clone for Topic<T> {
+    fn clone(&self) -> Self {
+        Topic {
+            name: self.name.clone(),
+            sender: self.sender.clone(),
+            history: self.history.clone(),
+            capacity: self.capacity,
+            lag_policy: self.lag_policy,
+        }
+    }
+}
+
+impl<T> Topic<T> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+impl<T: Clone> Topic<T> {
+    /// Send `message` to every current subscriber, returning how many there were
+    pub fn publish(&self, message: T) -> usize {
+        // Messages are sent while the history is locked, so subscribers see them in order
+        let mut history = self.history.lock().unwrap();
+        let id = history.next_id;
+        history.next_id += 1;
+        if history.messages.len() == self.capacity {
+            history.messages.pop_front();
+        }
+        history.messages.push_back((id, message.clone()));
+
+        self.sender.send((id, message)).unwrap_or(0)
+    }
+
+    pub fn subscribe(&self) -> Subscription<T> {
+        let history = self.history.lock().unwrap();
+        Subscription {
+            receiver: Some(self.sender.subscribe()),
+            history: self.history.clone(),
+            subscribed_at: history.next_id,
+            missed: VecDeque::new(),
+            lag_policy: self.lag_policy,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Topic<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Topic: {}", self.name)
+    }
+}
+
+/// The messages published to a [Topic] after subscribing
+pub struct Subscription<T> {
+    /// Dropped once the subscription ends
+    receiver: Option<broadcast::Receiver<(u64, T)>>,
+    history: Arc<Mutex<History<T>>>,
+    /// The id of the first message the receiver gets
+    subscribed_at: u64,
+    /// Messages published before subscribing, sent first
+    missed: VecDeque<(u64, T)>,
+    lag_policy: LagPolicy,
+}
+
+impl<T: Clone> Subscription<T> {
+    /// Also send the messages after the one with id `last_id` which were
+    /// published before subscribing, as far as the topic still holds them
+    pub fn resume(mut self, last_id: u64) -> Self {
+        let history = self.history.lock().unwrap();
+        let subscribed_at = self.subscribed_at;
+        self.missed = history
+            .messages
+            .iter()
+            .filter(|(id, _)| *id > last_id && *id < subscribed_at)
+            .cloned()
+            .collect();
+        drop(history);
+        self
+    }
+
+    /// The next message along with its id
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<Option<(u64, T)>> {
+        if let Some(message) = self.missed.pop_front() {
+            return Poll::Ready(Some(message));
+        }
+        loop {
+            let receiver = match self.receiver.as_mut() {
+                Some(receiver) => receiver,
+                None => return Poll::Ready(None),
+            };
+            match Pin::new(receiver).poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => return Poll::Ready(Some(message)),
+                Poll::Ready(Some(Err(RecvError::Lagged(_))))
+                    if self.lag_policy == LagPolicy::Skip => {}
+                Poll::Ready(_) => self.receiver = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+// Messages are never pinned
+impl<T> Unpin for Subscription<T> {}
+
+impl<T: Clone> Stream for Subscription<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.poll_message(cx)
+            .map(|message| message.map(|(_, message)| message))
+    }
+}
+
+/// Sends each message with its id, resuming after the client's `Last-Event-ID`
+impl<T: Clone + Serialize + 'static> From<Subscription<T>> for EventStream<T> {
+    fn from(subscription: Subscription<T>) -> Self {
+        EventStream::resume(move |last_event_id| {
+            let mut subscription = match last_event_id.and_then(|id| id.parse().ok()) {
+                Some(last_id) => subscription.resume(last_id),
+                None => subscription,
+            };
+            stream::poll_fn(move |cx| {
+                subscription
+                    .poll_message(cx)
+                    .map(|message| message.map(|(id, message)| Event::new(message).with_id(id)))
+            })
+        })
+    }
+}
+
+impl<T> fmt::Debug for Subscription<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Subscription")
+    }
+}
@@ -0,0 +1,235 @@
+//! Retrying failed requests
+//!
+//! Every [Client](crate::client::Client) has a [RetryPolicy], which
+//! generated clients follow when a request fails to connect or is responded
+//! to with a transient status, such as `503 Service Unavailable`. By default
+//! only idempotent requests are retried, up to three attempts in all.
+//! Routes override their client's policy with `#[retry(...)]`:
+//!
+//! ```ignore
+//! #[get("/posts/{id}")]
+//! #[retry(attempts = 5, backoff_ms = 50, max_backoff_ms = 2000, statuses = [503])]
+//! async fn read_post(&self, id: u32) -> Json<Post> { ... }
+//!
+//! // Posting a comment twice is harmless, so it's retried like a GET would be
+//! #[post("/posts/{id}/comments")]
+//! #[retry(non_idempotent)]
+//! async fn comment(&self, id: u32, comment: Json<Comment>) { ... }
+//!
+//! #[get("/posts/{id}/views")]
+//! #[retry(never)]
+//! async fn views(&self, id: u32) -> u64 { ... }
+//! ```
+//!
+//! Requests are cloned for each attempt, including their bodies. Requests
+//! with streaming bodies, such as [BodyStream](crate::web::BodyStream)
+//! uploads, can't be cloned and are only sent once.
+
+use std::{
+    future::Future,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{http::header::HttpDate, rt::time::delay_for};
+use futures::FutureExt;
+use reqwest::{
+    header::RETRY_AFTER, Client as ReqwestClient, Error as ReqwestError, Method, Request, Response,
+    StatusCode,
+};
+
+use crate::deadline::Deadline;
+
+/// When and how often to retry failed requests
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    statuses: Vec<StatusCode>,
+    retry_after: bool,
+    non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: true,
+            // Callers over a rate limit are told so, rather than trying again straight away
+            statuses: vec![
+                StatusCode::REQUEST_TIMEOUT,
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            retry_after: true,
+            non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy sending every request once
+    pub fn never() -> Self {
+        Self::default().attempts(1)
+    }
+
+    /// Set how many times a request is sent at most, including the first attempt
+    ///
+    /// # Panics
+    /// If `attempts` is zero
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        assert!(attempts > 0, "requests must be attempted at least once");
+        self.attempts = attempts;
+        self
+    }
+
+    /// Set the delay before the first retry, which doubles after each attempt
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set the longest delay between attempts, including delays asked for with `Retry-After`
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Set whether delays are randomized, between zero and the full delay,
+    /// so clients failing together don't retry together
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Set the response statuses which are retried
+    ///
+    /// Requests which fail to connect or time out are always retried.
+    pub fn statuses(mut self, statuses: &[u16]) -> Self {
+        self.statuses = statuses
+            .iter()
+            .filter_map(|status| StatusCode::from_u16(*status).ok())
+            .collect();
+        self
+    }
+
+    /// Set whether the delay asked for by a `Retry-After` header is waited
+    /// for, instead of the backoff
+    pub fn retry_after(mut self, retry_after: bool) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Set whether requests with methods which aren't idempotent, such as
+    /// `POST` and `PATCH`, are retried
+    pub fn non_idempotent(mut self, non_idempotent: bool) -> Self {
+        self.non_idempotent = non_idempotent;
+        self
+    }
+
+    /// Send `request` with `client`, retrying it until it succeeds or runs out of attempts
+    ///
+    /// Returns the last response or error.
+    pub async fn send(
+        &self,
+        client: &ReqwestClient,
+        request: Request,
+    ) -> Result<Response, ReqwestError> {
+        self.send_each(request, |request| client.execute(request).map(Ok))
+            .await
+    }
+
+    /// Send `request` as [send](RetryPolicy::send) does, making each attempt with `send`
+    ///
+    /// Attempts may fail without sending the request, which stops the retries.
+    pub(crate) async fn send_each<F, Fut, E>(
+        &self,
+        mut request: Request,
+        mut send: F,
+    ) -> Result<Response, E>
+    where
+        F: FnMut(Request) -> Fut,
+        Fut: Future<Output = Result<Result<Response, ReqwestError>, E>>,
+        E: From<ReqwestError>,
+    {
+        let retried = self.non_idempotent || is_idempotent(request.method());
+
+        let mut attempt = 1;
+        loop {
+            let next = if retried && attempt < self.attempts {
+                request.try_clone()
+            } else {
+                None
+            };
+
+            // Each attempt is sent with the time left by then
+            Deadline::current().stamp(&mut request);
+            let result = send(request).await?;
+            let next = match next {
+                Some(next) => next,
+                None => return Ok(result?),
+            };
+
+            let delay = match &result {
+                Ok(res) if self.statuses.contains(&res.status()) => self.delay(attempt, Some(res)),
+                Err(err) if is_transient(err) => self.delay(attempt, None),
+                _ => return Ok(result?),
+            };
+            delay_for(delay).await;
+
+            request = next;
+            attempt += 1;
+        }
+    }
+
+    /// The delay after the failed `attempt`, which was responded to with `res`
+    fn delay(&self, attempt: u32, res: Option<&Response>) -> Duration {
+        if let Some(delay) = res.filter(|_| self.retry_after).and_then(retry_after) {
+            return delay.min(self.max_backoff);
+        }
+
+        let delay = self
+            .backoff
+            .checked_mul(1 << (attempt - 1).min(31))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        if self.jitter {
+            delay.mul_f64(rand::random())
+        } else {
+            delay
+        }
+    }
+}
+
+/// Whether sending a request with `method` more than once has the same effect as sending it once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Whether the request failed to get a response, rather than getting an unusable one
+fn is_transient(err: &ReqwestError) -> bool {
+    !(err.is_builder() || err.is_redirect() || err.is_status() || err.is_decode())
+}
+
+/// The delay asked for by a response's `Retry-After` header, in seconds or as a date
+fn retry_after(res: &Response) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = SystemTime::from(HttpDate::from_str(value).ok()?);
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
@@ -0,0 +1,324 @@
+//! Limiting the requests a route handles at once
+//!
+//! Routes marked with `#[concurrency(max = 4, queue = 16)]`, or in services
+//! with a default of `#[phalanx(MyClient, concurrency(max = 16))]`, handle at
+//! most `max` requests at once. Requests over the limit wait briefly in a
+//! queue of at most `queue`, and are responded to with
+//! `503 Service Unavailable` and a `Retry-After` header if the queue is full
+//! or they don't get a turn in time.
+//!
+//! ```ignore
+//! #[phalanx(BlogClient, concurrency(max = 64))]
+//! impl BlogServer {
+//!     // A costly route, limited further than the rest of the service
+//!     #[concurrency(max = 4, queue = 16, wait_ms = 200, retry_after = 2)]
+//!     #[get("/search")]
+//!     async fn search(&self, query: String) -> Json<Vec<Post>> { ... }
+//! }
+//! ```
+//!
+//! Limits are kept in the app's [ConcurrencyLimits]. Each app mounting a
+//! service gets its own, so every worker of an `HttpServer` limits its
+//! requests on its own, unless one is registered with
+//! [App::app_data](actix_web::App::app_data) to share between them:
+//!
+//! ```ignore
+//! let limits = web::Data::new(ConcurrencyLimits::new());
+//! let app_limits = limits.clone();
+//! HttpServer::new(move || {
+//!     App::new()
+//!         .app_data(app_limits.clone())
+//!         .phalanx_mount(BlogServer::new())
+//! });
+//!
+//! for route in limits.stats() {
+//!     metrics.gauge(route.route, route.in_flight as f64);
+//! }
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    rt::time,
+    web::Data,
+    HttpRequest, HttpResponse, ResponseError,
+};
+use futures::channel::oneshot;
+
+use super::rate_limit::seconds;
+
+/// How long requests wait for a turn by default
+pub const DEFAULT_WAIT: Duration = Duration::from_millis(500);
+
+/// How long rejected requests are told to wait before retrying by default
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// How many requests a route handles at once, and how the rest wait
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Concurrency {
+    max: usize,
+    queue: usize,
+    wait: Duration,
+    retry_after: Duration,
+}
+
+impl Concurrency {
+    /// Handle at most `max` requests at once, with as many waiting
+    ///
+    /// # Panics
+    /// If `max` is zero
+    pub fn new(max: usize) -> Self {
+        assert!(max > 0, "at least one request must be handled at once");
+        Concurrency {
+            max,
+            queue: max,
+            wait: DEFAULT_WAIT,
+            retry_after: DEFAULT_RETRY_AFTER,
+        }
+    }
+
+    /// Set how many requests may wait, with any more rejected straight away
+    pub fn queue(mut self, queue: usize) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Set how long requests may wait before they're rejected
+    pub fn wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    /// Set how long rejected requests are told to wait with `Retry-After`,
+    /// rounded up to whole seconds
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+}
+
+/// How many requests a route is handling and has waiting, for metrics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteStats {
+    /// The route's server type and method, i.e. `BlogServer::search`
+    pub route: &'static str,
+    pub max: usize,
+    pub in_flight: usize,
+    pub waiting: usize,
+}
+
+/// The limits of an app's routes
+///
+/// Routes are limited by the name and limit they're entered with, so
+/// services mounted in the same app with routes of the same name keep their
+/// own limits.
+#[derive(Default)]
+pub struct ConcurrencyLimits {
+    routes: Mutex<HashMap<(&'static str, Concurrency), Arc<Semaphore>>>,
+}
+
+impl ConcurrencyLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every limited route which has been requested
+    pub fn stats(&self) -> Vec<RouteStats> {
+        let routes = self.routes.lock().unwrap();
+        let mut stats: Vec<_> = routes
+            .iter()
+            .map(|((route, concurrency), semaphore)| {
+                let mut state = semaphore.state.lock().unwrap();
+                state.waiting.retain(|waiter| !waiter.is_canceled());
+                let (in_flight, waiting) = (state.in_flight, state.waiting.len());
+                RouteStats {
+                    route,
+                    max: concurrency.max,
+                    in_flight,
+                    waiting,
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| (a.route, a.max).cmp(&(b.route, b.max)));
+        stats
+    }
+
+    /// Wait for a turn to handle a request to `route`, limited by `concurrency`
+    ///
+    /// The request counts as in flight until the returned [Permit] is dropped.
+    pub async fn enter(
+        &self,
+        route: &'static str,
+        concurrency: Concurrency,
+    ) -> Result<Permit, Overloaded> {
+        let semaphore = {
+            let mut routes = self.routes.lock().unwrap();
+            let semaphore = routes
+                .entry((route, concurrency))
+                .or_insert_with(|| Arc::new(Semaphore::new(concurrency)));
+            semaphore.clone()
+        };
+        semaphore.acquire().await
+    }
+}
+
+impl fmt::Debug for ConcurrencyLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConcurrencyLimits")
+            .field("routes", &self.routes.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// The turns of a route, handed to waiting requests in the order they arrived
+struct Semaphore {
+    concurrency: Concurrency,
+    state: Mutex<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    in_flight: usize,
+    /// Where each waiting request is sent its turn
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+impl Semaphore {
+    fn new(concurrency: Concurrency) -> Self {
+        Semaphore {
+            concurrency,
+            state: Mutex::new(SemaphoreState {
+                in_flight: 0,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    async fn acquire(self: Arc<Self>) -> Result<Permit, Overloaded> {
+        let turn = {
+            let mut state = self.state.lock().unwrap();
+            state.waiting.retain(|waiter| !waiter.is_canceled());
+            if state.in_flight < self.concurrency.max && state.waiting.is_empty() {
+                state.in_flight += 1;
+                drop(state);
+                return Ok(Permit(Some(self)));
+            }
+            if state.waiting.len() >= self.concurrency.queue {
+                return Err(self.overloaded(false));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            rx
+        };
+
+        // A turn handed over as the wait ends is passed on when the receiver is dropped
+        match time::timeout(self.concurrency.wait, turn).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(self.overloaded(true)),
+        }
+    }
+
+    fn overloaded(&self, waited: bool) -> Overloaded {
+        Overloaded {
+            waited,
+            retry_after: self.concurrency.retry_after,
+        }
+    }
+
+    /// Hand a finished request's turn to the next one waiting
+    fn release(self: Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiting.pop_front() {
+            match waiter.send(Permit(Some(self.clone()))) {
+                Ok(()) => return,
+                // The request stopped waiting, so its turn isn't released again
+                Err(mut permit) => permit.0 = None,
+            }
+        }
+        state.in_flight -= 1;
+    }
+}
+
+/// A request's turn to be handled, given up when it's dropped
+pub struct Permit(Option<Arc<Semaphore>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(semaphore) = self.0.take() {
+            semaphore.release();
+        }
+    }
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Permit")
+    }
+}
+
+/// A request turned away by its route's concurrency limit, responded to with
+/// `503 Service Unavailable` and a `Retry-After` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overloaded {
+    /// Whether the request waited for a turn, rather than finding the queue full
+    pub waited: bool,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the server is busy, retry in {} seconds",
+            seconds(self.retry_after)
+        )
+    }
+}
+
+impl ResponseError for Overloaded {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable()
+            .header(RETRY_AFTER, seconds(self.retry_after).to_string())
+            .body(self.to_string())
+    }
+}
+
+/// Wait for a turn to handle `req`, a request to `route`, limited by `concurrency`
+///
+/// Fails with `503 Service Unavailable` if it doesn't get a turn. Used by
+/// generated handlers, within an app with [ConcurrencyLimits], as
+/// [phalanx_mount](crate::server::mount::PhalanxMount::phalanx_mount) makes sure of.
+pub async fn enter(
+    route: &'static str,
+    concurrency: Concurrency,
+    req: &HttpRequest,
+) -> Result<Option<Permit>, Overloaded> {
+    match req.app_data::<Data<ConcurrencyLimits>>() {
+        Some(limits) => limits.enter(route, concurrency).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Wait for a turn to handle a call to `route`, as [enter] does for the
+/// request being handled
+///
+/// Used by generated JSON-RPC and batch calls. Calls made outside of a
+/// request aren't limited.
+pub async fn enter_current(
+    route: &'static str,
+    concurrency: Concurrency,
+) -> Result<Option<Permit>, Overloaded> {
+    match super::with_request(|req| req.app_data::<Data<ConcurrencyLimits>>().cloned()) {
+        Some(Some(limits)) => limits.enter(route, concurrency).await.map(Some),
+        _ => Ok(None),
+    }
+}
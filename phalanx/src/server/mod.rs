@@ -1,12 +1,46 @@
-use actix_web::{dev::Body, error::Error, HttpRequest, HttpResponse, Responder};
-use futures::future::{ok, Ready};
+use std::{future::Future, time::Duration};
 
+use actix_web::{
+    dev::{Body, Payload},
+    error::{Error, ErrorGatewayTimeout, ErrorUnsupportedMediaType},
+    http::header::CONTENT_TYPE,
+    rt::time,
+    web::Bytes,
+    FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures::{
+    future::{ok, MapOk, Ready},
+    TryFutureExt,
+};
+
+use crate::{client::PhalanxPayload, deadline::Deadline};
+
+pub mod concurrency;
 pub mod mount;
+pub mod rate_limit;
 
 pub trait PhalanxServer: Clone {
     fn mount(config: &mut actix_web::web::ServiceConfig);
 }
 
+tokio::task_local! {
+    static REQUEST: HttpRequest;
+}
+
+/// Run `future` within the request `req`, for generated JSON-RPC and batch
+/// calls, which are made within the request to their endpoint
+pub(crate) async fn scope<F: Future>(req: HttpRequest, future: F) -> F::Output {
+    REQUEST.scope(req, future).await
+}
+
+/// Call `f` with the request the current call is made within, if any
+pub(crate) fn with_request<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&HttpRequest) -> R,
+{
+    REQUEST.try_with(f).ok()
+}
+
 /// A special responder for the unit type
 /// Used for convenience in phalanx_codegen
 pub struct UnitResponder;
@@ -20,3 +54,109 @@ impl Responder for UnitResponder {
         ok(HttpResponse::build(actix_web::http::StatusCode::OK).body(Body::Empty))
     }
 }
+
+/// A route's payload, extracted with its [PhalanxPayload] implementation
+///
+/// Used by generated handlers.
+pub struct Extract<T>(pub T);
+
+impl<T> Extract<T> {
+    /// Deconstruct to an inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: PhalanxPayload> FromRequest for Extract<T> {
+    type Error = Error;
+    type Future = MapOk<T::ExtractFuture, fn(T) -> Self>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        T::extract(req, payload).map_ok(Extract as fn(T) -> Self)
+    }
+}
+
+/// Extract `T` from `req` once a handler has let the request through, rather
+/// than before it's run
+///
+/// Used by generated handlers.
+pub async fn extract<T: FromRequest>(req: &HttpRequest, payload: &mut Payload) -> Result<T, Error> {
+    T::from_request(req, payload).await.map_err(Into::into)
+}
+
+/// Run the handler `handler`, responding with `504 Gateway Timeout` if it
+/// doesn't finish within `timeout`
+///
+/// The handler is dropped once it times out, cancelling any work it's waiting on.
+/// The same as [within] with a deadline `timeout` from now.
+pub async fn timeout<F: Future>(timeout: Duration, handler: F) -> Result<F::Output, Error> {
+    within(Deadline::after(timeout), handler).await
+}
+
+/// Run the handler `handler` with `deadline` as the current
+/// [Deadline](crate::deadline::Deadline), responding with
+/// `504 Gateway Timeout` if it doesn't finish before the deadline
+///
+/// The handler is dropped once the deadline passes, cancelling any work it's
+/// waiting on, and isn't run at all if it has already passed.
+/// Used by generated handlers.
+///
+/// Only the handler is timed: its arguments, including the request body, are
+/// extracted before it runs. A caller's deadline is read from the request
+/// first, so the time taken reading the body counts against it and a handler
+/// whose body arrived too late isn't run, but a route's own `#[timeout]` is
+/// only counted from here on and doesn't cover a slow upload.
+pub async fn within<F: Future>(deadline: Deadline, handler: F) -> Result<F::Output, Error> {
+    let remaining = match deadline.remaining() {
+        Some(remaining) if remaining == Duration::from_secs(0) => {
+            return Err(ErrorGatewayTimeout(
+                "the deadline passed before the handler ran",
+            ))
+        }
+        Some(remaining) => remaining,
+        None => return Ok(deadline.scope(handler).await),
+    };
+    time::timeout(remaining, deadline.scope(handler))
+        .await
+        .map_err(|_| ErrorGatewayTimeout(format!("the handler timed out after {:?}", remaining)))
+}
+
+/// Read a request body sent with the content type `content_type`
+///
+/// Requests declaring any other content type are rejected with
+/// `415 Unsupported Media Type`, while requests without one are accepted.
+/// The body's size is limited by [PayloadConfig](actix_web::web::PayloadConfig).
+///
+/// Used to implement [FromRequest] for custom payload types.
+pub fn read_payload(
+    req: &HttpRequest,
+    payload: &mut Payload,
+    content_type: &str,
+) -> impl Future<Output = Result<Bytes, Error>> {
+    let checked = check_content_type(req, content_type);
+    let bytes = Bytes::from_request(req, payload);
+    async move {
+        checked?;
+        bytes.await
+    }
+}
+
+fn check_content_type(req: &HttpRequest, expected: &str) -> Result<(), Error> {
+    let expected = expected.split(';').next().unwrap_or_default().trim();
+    match req.headers().get(CONTENT_TYPE) {
+        None => Ok(()),
+        Some(value) => {
+            let mime = value.to_str().unwrap_or_default();
+            let essence = mime.split(';').next().unwrap_or_default().trim();
+            if essence.eq_ignore_ascii_case(expected) {
+                Ok(())
+            } else {
+                Err(ErrorUnsupportedMediaType(format!(
+                    "expected a content type of {}, got {}",
+                    expected, mime
+                )))
+            }
+        }
+    }
+}
@@ -4,12 +4,23 @@ use actix_web::{
     App,
 };
 
-use super::PhalanxServer;
+use super::{concurrency::ConcurrencyLimits, rate_limit::RateLimits, PhalanxServer};
+use crate::jsonrpc::{self, JsonRpcService};
 
 /// This trait adds a configuration method to [App](actix_web::App)
 /// specifically for configuring Phalanx services
 pub trait PhalanxMount: Sized {
+    /// Mount the service's routes
+    ///
+    /// Unless the app has [ConcurrencyLimits] or [RateLimits] registered
+    /// already, it gets its own.
     fn phalanx_mount<S: PhalanxServer + 'static>(self, service: S) -> Self;
+
+    /// Mount the service's JSON-RPC endpoint at `/rpc`
+    ///
+    /// Only the JSON-RPC endpoint is mounted; use [phalanx_mount](PhalanxMount::phalanx_mount)
+    /// as well to also serve the service's REST routes.
+    fn phalanx_mount_rpc<S: JsonRpcService + 'static>(self, service: S) -> Self;
 }
 
 impl<T, B> PhalanxMount for App<T, B>
@@ -24,6 +35,17 @@ where
     >,
 {
     fn phalanx_mount<S: PhalanxServer + 'static>(self, service: S) -> Self {
-        self.configure(S::mount).data(service)
+        // Data only applies if the app has none of its type already
+        self.configure(S::mount)
+            .data(service)
+            .data(ConcurrencyLimits::new())
+            .data(RateLimits::default())
+    }
+
+    fn phalanx_mount_rpc<S: JsonRpcService + 'static>(self, service: S) -> Self {
+        self.configure(jsonrpc::mount::<S>)
+            .data(service)
+            .data(ConcurrencyLimits::new())
+            .data(RateLimits::default())
     }
 }
@@ -0,0 +1,433 @@
+//! Limiting how often each caller may request a route
+//!
+//! Routes marked with `#[rate_limit(per_minute = 60, key = "ip")]` take a
+//! token from a bucket for each request, keyed by the route and the caller.
+//! Buckets hold up to the limit and refill steadily over its period, so
+//! callers may burst up to the limit and then make requests as fast as
+//! they refill. Requests finding their bucket empty are responded to with
+//! `429 Too Many Requests`, with the `RateLimit-Limit`, `RateLimit-Remaining`
+//! and `RateLimit-Reset` headers and `Retry-After`. Generated clients fail
+//! with [RateLimited](crate::client::PhalanxClientError::RateLimited).
+//!
+//! Callers are told apart by their `key`:
+//! - `"ip"`, the peer address of the connection
+//! - `"header:X-Api-Key"`, the value of the header, such as an API key
+//! - `"user"`, the [User] authentication middleware put in the request's extensions
+//!
+//! Requests without the header or [User] are keyed by their IP. Behind a
+//! proxy, every request comes from the proxy's address, so a header set by
+//! the proxy should be used instead.
+//!
+//! Header values are taken as they're sent, so a caller sending a new API key
+//! with each request gets a fresh bucket each time. Unless something in front
+//! of the route checks the header, limit callers by `"user"`, with the
+//! identity authentication middleware has checked, or by `"ip"`.
+//!
+//! ```ignore
+//! #[phalanx(BlogClient)]
+//! impl BlogServer {
+//!     #[rate_limit(per_minute = 60, key = "header:X-Api-Key")]
+//!     #[get("/search")]
+//!     async fn search(&self, query: String) -> Json<Vec<Post>> { ... }
+//! }
+//!
+//! ```
+//!
+//! Buckets are kept in the app's [RateLimits]. Each app mounting a service
+//! keeps them in memory on its own, so every worker of an `HttpServer` has
+//! its own buckets, unless one is registered with
+//! [App::app_data](actix_web::App::app_data) to share between them or to keep
+//! them in another store:
+//!
+//! ```ignore
+//! let limits = web::Data::new(RateLimits::new(RedisStore::new(redis)));
+//! HttpServer::new(move || {
+//!     App::new()
+//!         .app_data(limits.clone())
+//!         .phalanx_mount(BlogServer::new())
+//! });
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    web::Data,
+    HttpRequest, HttpResponse, ResponseError,
+};
+use futures::future::{ready, FutureExt, LocalBoxFuture};
+
+use crate::{
+    breaker::{Clock, SystemClock},
+    jsonrpc::{RpcError, SERVER_ERROR},
+};
+
+/// The header the number of requests allowed in a period is sent in
+pub const LIMIT_HEADER: &str = "ratelimit-limit";
+/// The header the number of requests left is sent in
+pub const REMAINING_HEADER: &str = "ratelimit-remaining";
+/// The header the seconds until another request is allowed is sent in
+pub const RESET_HEADER: &str = "ratelimit-reset";
+
+/// How often a [MemoryStore] forgets buckets which have refilled
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many requests each caller may make in a period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    limit: u32,
+    period: Duration,
+}
+
+impl RateLimit {
+    /// Allow `limit` requests every `period`
+    ///
+    /// # Panics
+    /// If `limit` or `period` is zero
+    pub fn new(limit: u32, period: Duration) -> Self {
+        assert!(limit > 0, "at least one request must be allowed");
+        assert!(period > Duration::from_secs(0), "the period can't be zero");
+        RateLimit { limit, period }
+    }
+
+    pub fn per_second(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(60))
+    }
+
+    pub fn per_hour(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(60 * 60))
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// What tells callers apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// The peer address of the connection
+    Ip,
+    /// The value of the named header, or the IP without one
+    Header(&'static str),
+    /// The [User] in the request's extensions, or the IP without one
+    User,
+}
+
+impl Key {
+    /// The caller of `req`
+    pub fn caller(&self, req: &HttpRequest) -> String {
+        let ip = || match req.peer_addr() {
+            Some(addr) => format!("ip:{}", addr.ip()),
+            None => String::from("ip:unknown"),
+        };
+        match self {
+            Key::Ip => ip(),
+            Key::Header(name) => match req.headers().get(*name).and_then(|v| v.to_str().ok()) {
+                Some(value) => format!("{}:{}", name.to_ascii_lowercase(), value),
+                None => ip(),
+            },
+            Key::User => match req.extensions().get::<User>() {
+                Some(User(user)) => format!("user:{}", user),
+                None => ip(),
+            },
+        }
+    }
+}
+
+/// The authenticated caller of a request, which authentication middleware
+/// puts in its extensions for routes limited per `"user"`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct User(pub String);
+
+/// Whether a request is allowed, and how many more are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    /// Requests left before the bucket is empty
+    pub remaining: u32,
+    /// How long until another request is allowed, once the bucket is empty,
+    /// or until the bucket is full again otherwise
+    pub reset: Duration,
+}
+
+/// The result of taking a token
+pub type TakeFuture = LocalBoxFuture<'static, Decision>;
+
+/// Where buckets are kept
+///
+/// Stores shared by several processes, such as one on Redis, limit callers
+/// across all of them. Stores which can't be reached decide for themselves
+/// whether requests are allowed.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Take a token from the bucket `key`, which holds up to `limit`
+    fn take(&self, key: String, limit: RateLimit) -> TakeFuture;
+}
+
+/// Keeps buckets in this process's memory
+///
+/// Cloning a store is cheap, and clones share the same buckets.
+#[derive(Clone)]
+pub struct MemoryStore {
+    buckets: Arc<Mutex<Buckets>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    /// When buckets which had refilled were last forgotten
+    swept: Option<Instant>,
+}
+
+impl Buckets {
+    /// Forget buckets which have refilled since they were last taken from,
+    /// as they're the same as new ones, at most every [SWEEP_INTERVAL]
+    fn sweep(&mut self, now: Instant) {
+        let swept = *self.swept.get_or_insert(now);
+        if now.saturating_duration_since(swept) < SWEEP_INTERVAL {
+            return;
+        }
+        self.swept = Some(now);
+        self.buckets.retain(|_, bucket| !bucket.is_full(now));
+    }
+}
+
+struct Bucket {
+    /// Fractional, as tokens are refilled continuously
+    tokens: f64,
+    updated: Instant,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    /// Tokens refilled each second
+    fn rate(&self) -> f64 {
+        f64::from(self.limit.limit) / self.limit.period.as_secs_f64()
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate()).min(f64::from(self.limit.limit));
+        self.updated = now;
+    }
+
+    /// How long until the bucket holds `tokens`
+    fn until(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64(((tokens - self.tokens) / self.rate()).max(0.0))
+    }
+
+    /// Whether the bucket has refilled by `now`
+    fn is_full(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.updated) >= self.until(f64::from(self.limit.limit))
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            buckets: Arc::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Set the clock buckets are refilled by
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// How many buckets are kept, for metrics
+    ///
+    /// Buckets are forgotten once they've refilled, every minute or so.
+    pub fn buckets(&self) -> usize {
+        self.buckets.lock().unwrap().buckets.len()
+    }
+
+    /// Take a token from the bucket `key` straight away
+    pub fn take_now(&self, key: String, limit: RateLimit) -> Decision {
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.sweep(now);
+
+        let bucket = buckets.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: f64::from(limit.limit),
+            updated: now,
+            limit,
+        });
+        bucket.limit = limit;
+        bucket.refill(now);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Decision {
+            allowed,
+            limit: limit.limit,
+            remaining: bucket.tokens as u32,
+            reset: if allowed {
+                bucket.until(f64::from(limit.limit))
+            } else {
+                bucket.until(1.0)
+            },
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
+impl fmt::Debug for MemoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStore")
+            .field("buckets", &self.buckets())
+            .finish()
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take(&self, key: String, limit: RateLimit) -> TakeFuture {
+        ready(self.take_now(key, limit)).boxed_local()
+    }
+}
+
+/// The store an app's buckets are kept in
+pub struct RateLimits {
+    store: Box<dyn RateLimitStore>,
+}
+
+impl RateLimits {
+    /// Keep buckets in `store`
+    pub fn new<S: RateLimitStore>(store: S) -> Self {
+        RateLimits {
+            store: Box::new(store),
+        }
+    }
+
+    /// Take a token for `bucket`, limited by `limit`
+    pub async fn take(&self, bucket: String, limit: RateLimit) -> Result<(), Limited> {
+        let decision = self.store.take(bucket, limit).await;
+        if decision.allowed {
+            Ok(())
+        } else {
+            Err(Limited(decision))
+        }
+    }
+}
+
+/// Buckets are kept in memory by default
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits::new(MemoryStore::new())
+    }
+}
+
+impl fmt::Debug for RateLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RateLimits")
+    }
+}
+
+/// A request refused by its route's rate limit, responded to with
+/// `429 Too Many Requests`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limited(pub Decision);
+
+impl Limited {
+    /// The call's error over JSON-RPC, carrying the limit alongside the status
+    pub fn to_rpc_error(&self) -> RpcError {
+        RpcError::new(SERVER_ERROR, self.to_string()).with_data(serde_json::json!({
+            "status": StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            "limit": self.0.limit,
+            "reset": seconds(self.0.reset),
+        }))
+    }
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rate limited, retry in {} seconds",
+            seconds(self.0.reset)
+        )
+    }
+}
+
+impl ResponseError for Limited {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let reset = seconds(self.0.reset);
+        HttpResponse::TooManyRequests()
+            .header(LIMIT_HEADER, self.0.limit.to_string())
+            .header(REMAINING_HEADER, self.0.remaining.to_string())
+            .header(RESET_HEADER, reset.to_string())
+            .header(RETRY_AFTER, reset.to_string())
+            .body(self.to_string())
+    }
+}
+
+/// Whole seconds, rounded up so callers don't retry too soon
+pub(super) fn seconds(duration: Duration) -> u64 {
+    let secs = duration.as_secs();
+    if duration.subsec_nanos() > 0 {
+        secs + 1
+    } else {
+        secs
+    }
+}
+
+/// Take a token for the caller of `req` to `route`, limited by `limit`
+///
+/// Used by generated handlers, within an app with [RateLimits], as
+/// [phalanx_mount](crate::server::mount::PhalanxMount::phalanx_mount) makes sure of.
+pub async fn check(
+    route: &'static str,
+    limit: RateLimit,
+    key: Key,
+    req: &HttpRequest,
+) -> Result<(), Limited> {
+    match req.app_data::<Data<RateLimits>>() {
+        Some(limits) => {
+            let bucket = format!("{} {}", route, key.caller(req));
+            limits.take(bucket, limit).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Take a token for the caller of the request being handled, as [check] does
+///
+/// Used by generated JSON-RPC and batch calls. Calls made outside of a
+/// request aren't limited.
+pub async fn check_current(route: &'static str, limit: RateLimit, key: Key) -> Result<(), Limited> {
+    let current = super::with_request(|req| {
+        let limits = req.app_data::<Data<RateLimits>>().cloned();
+        limits.map(|limits| (limits, format!("{} {}", route, key.caller(req))))
+    });
+    match current {
+        Some(Some((limits, bucket))) => limits.take(bucket, limit).await,
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,201 @@
+use std::{fmt, ops};
+
+use actix_web::{
+    dev::Payload,
+    http::header::CONTENT_TYPE,
+    web::{Bytes, PayloadConfig},
+    FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures::future::{ok, Ready};
+use reqwest::RequestBuilder;
+
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Raw binary data with a configurable content type
+///
+/// Defaults to `application/octet-stream`
+#[derive(Clone, PartialEq, Eq)]
+pub struct Binary {
+    data: Bytes,
+    content_type: String,
+}
+
+impl Binary {
+    pub fn new<B: Into<Bytes>>(data: B) -> Self {
+        Binary {
+            data: data.into(),
+            content_type: String::from(OCTET_STREAM),
+        }
+    }
+
+    /// Set the content type sent along with the data
+    pub fn with_content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Deconstruct to the inner bytes
+    pub fn into_inner(self) -> Bytes {
+        self.data
+    }
+}
+
+impl From<Bytes> for Binary {
+    fn from(data: Bytes) -> Self {
+        Binary::new(data)
+    }
+}
+
+impl From<Vec<u8>> for Binary {
+    fn from(data: Vec<u8>) -> Self {
+        Binary::new(data)
+    }
+}
+
+impl ops::Deref for Binary {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for Binary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Binary: {} bytes of {}",
+            self.data.len(),
+            self.content_type
+        )
+    }
+}
+
+impl Responder for Binary {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, _: &HttpRequest) -> Self::Future {
+        ok(HttpResponse::Ok()
+            .content_type(self.content_type)
+            .body(self.data))
+    }
+}
+
+type BinaryFromRequestFuture = impl std::future::Future<Output = Result<Binary, actix_web::Error>>;
+
+/// Binary extractor. Keeps the request's content type alongside the data.
+impl FromRequest for Binary {
+    type Error = actix_web::Error;
+    type Future = BinaryFromRequestFuture;
+    type Config = PayloadConfig;
+
+    #[inline]
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = header_content_type(req.headers().get(CONTENT_TYPE));
+        let data = Bytes::from_request(req, payload);
+        async move {
+            let binary = Binary::new(data.await?);
+            Ok(match content_type {
+                Some(content_type) => binary.with_content_type(content_type),
+                None => binary,
+            })
+        }
+    }
+}
+
+type BinaryDecodeFuture = impl std::future::Future<Output = Result<Binary, PhalanxClientError>>;
+
+impl PhalanxPayload for Binary {
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = BinaryDecodeFuture;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        Ok(req
+            .header(CONTENT_TYPE, self.content_type.as_str())
+            .body(self.data))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let res = res.0.error_for_status()?;
+            let content_type = header_content_type(res.headers().get(CONTENT_TYPE));
+            let binary = Binary::new(res.bytes().await?);
+            Ok(match content_type {
+                Some(content_type) => binary.with_content_type(content_type),
+                None => binary,
+            })
+        }
+    }
+}
+
+type BytesDecodeFuture = impl std::future::Future<Output = Result<Bytes, PhalanxClientError>>;
+
+impl PhalanxPayload for Bytes {
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = BytesDecodeFuture;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        Ok(req.header(CONTENT_TYPE, OCTET_STREAM).body(self))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let res = res.0.error_for_status()?;
+            Ok(res.bytes().await?)
+        }
+    }
+}
+
+type VecExtractFuture = impl std::future::Future<Output = Result<Vec<u8>, actix_web::Error>>;
+type VecDecodeFuture = impl std::future::Future<Output = Result<Vec<u8>, PhalanxClientError>>;
+
+impl PhalanxPayload for Vec<u8> {
+    type ExtractFuture = VecExtractFuture;
+    type DecodeFuture = VecDecodeFuture;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        Ok(req.header(CONTENT_TYPE, OCTET_STREAM).body(self))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        let bytes = Bytes::from_request(req, payload);
+        async move { Ok(bytes.await?.to_vec()) }
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let bytes = Bytes::decode(res).await?;
+            Ok(bytes.to_vec())
+        }
+    }
+}
+
+fn header_content_type(value: Option<&actix_web::http::HeaderValue>) -> Option<String> {
+    value
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
@@ -0,0 +1,38 @@
+use ::bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::Format;
+
+/// The bincode format, sent as `application/x-bincode`
+///
+/// Bincode is not self-describing: both ends must agree on the exact type
+/// being sent, so it is best suited to services built from the same source.
+pub struct BincodeFormat;
+
+impl Format for BincodeFormat {
+    type EncodeError = ::bincode::Error;
+    type DecodeError = ::bincode::Error;
+
+    fn content_type() -> &'static str {
+        "application/x-bincode"
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::EncodeError> {
+        ::bincode::serialize(value)
+    }
+
+    /// Payloads come from untrusted clients, so decoding is limited to the
+    /// bytes received, rather than trusting the lengths they're prefixed with
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::DecodeError> {
+        ::bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(bytes.len() as u64)
+            .deserialize(bytes)
+    }
+}
+
+format_wrapper! {
+    /// A payload or response encoded with bincode
+    Bincode(BincodeFormat), BincodeFromRequestFuture, BincodeDecodeFuture
+}
@@ -0,0 +1,187 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::Payload, error::PayloadError, http::header::CONTENT_TYPE, web::Bytes, FromRequest,
+    HttpRequest, HttpResponse, Responder,
+};
+use futures::{
+    future::{ok, ready, Ready},
+    stream::{self, LocalBoxStream},
+    Stream, TryStreamExt,
+};
+use reqwest::{Body, RequestBuilder};
+
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// A body which is sent and received in chunks, without being held in memory
+///
+/// Clients create a `BodyStream` from a stream of chunks or an
+/// [AsyncRead](tokio::io::AsyncRead), which is read as the request is sent.
+/// Servers receive the chunks as they arrive; the client is only sent more
+/// data once the handler has polled for it. Unlike other payloads, the body's
+/// size isn't limited by [PayloadConfig](actix_web::web::PayloadConfig).
+///
+/// A `BodyStream` received by a server can't be sent on by its client, as
+/// the request it's read from is bound to the server's thread.
+pub struct BodyStream {
+    inner: Inner,
+    content_type: String,
+}
+
+enum Inner {
+    /// Created to be sent by a client
+    Shared(Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + Sync>>),
+    /// Received in a request or response
+    Local(LocalBoxStream<'static, io::Result<Bytes>>),
+}
+
+impl BodyStream {
+    pub fn new<S, B, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Into<Bytes> + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let stream = stream.map_ok(Into::into).map_err(cut_short);
+        BodyStream {
+            inner: Inner::Shared(Box::pin(stream)),
+            content_type: String::from(OCTET_STREAM),
+        }
+    }
+
+    /// Read the body from `reader` as it is sent
+    pub fn from_reader<R>(reader: R) -> Self
+    where
+        R: tokio::io::AsyncRead + Send + Sync + 'static,
+    {
+        BodyStream::new(tokio::io::reader_stream(reader))
+    }
+
+    fn local<S>(stream: S, content_type: Option<String>) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + 'static,
+    {
+        BodyStream {
+            inner: Inner::Local(Box::pin(stream)),
+            content_type: content_type.unwrap_or_else(|| String::from(OCTET_STREAM)),
+        }
+    }
+
+    /// Set the content type sent along with the body
+    pub fn with_content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut self.inner {
+            Inner::Shared(stream) => stream.as_mut().poll_next(cx),
+            Inner::Local(stream) => stream.as_mut().poll_next(cx),
+        }
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BodyStream: {}", self.content_type)
+    }
+}
+
+impl Responder for BodyStream {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, _: &HttpRequest) -> Self::Future {
+        ok(HttpResponse::Ok()
+            .content_type(self.content_type.as_str())
+            .streaming(self))
+    }
+}
+
+/// Streaming extractor. Keeps the request's content type alongside the body.
+impl FromRequest for BodyStream {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<BodyStream, actix_web::Error>>;
+    type Config = ();
+
+    #[inline]
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let chunks = payload.take().map_err(payload_error);
+        ok(BodyStream::local(chunks, header_content_type(req)))
+    }
+}
+
+impl PhalanxPayload for BodyStream {
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = Ready<Result<BodyStream, PhalanxClientError>>;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        match self.inner {
+            Inner::Shared(stream) => Ok(req
+                .header(CONTENT_TYPE, self.content_type)
+                .body(Body::wrap_stream(stream))),
+            Inner::Local(_) => Err("a received BodyStream can't be sent by a client".into()),
+        }
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        ready(res.0.error_for_status().map_err(Into::into).map(|res| {
+            let content_type = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from);
+            let chunks = stream::try_unfold(res, |mut res| async move {
+                let chunk = res.chunk().await.map_err(cut_short)?;
+                Ok(chunk.map(|chunk| (chunk, res)))
+            });
+            BodyStream::local(chunks, content_type)
+        }))
+    }
+}
+
+fn payload_error(err: PayloadError) -> io::Error {
+    match err {
+        PayloadError::Io(err) => err,
+        err => cut_short(err.to_string()),
+    }
+}
+
+/// The error for a body which couldn't be read to its end, keeping io errors as they are
+fn cut_short<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    match err.into().downcast::<io::Error>() {
+        Ok(err) => *err,
+        Err(err) => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+    }
+}
+
+fn header_content_type(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
@@ -0,0 +1,28 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::Format;
+
+/// The CBOR format, sent as `application/cbor`
+pub struct CborFormat;
+
+impl Format for CborFormat {
+    type EncodeError = serde_cbor::Error;
+    type DecodeError = serde_cbor::Error;
+
+    fn content_type() -> &'static str {
+        "application/cbor"
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::EncodeError> {
+        serde_cbor::to_vec(value)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::DecodeError> {
+        serde_cbor::from_slice(bytes)
+    }
+}
+
+format_wrapper! {
+    /// A payload or response encoded as CBOR
+    Cbor(CborFormat), CborFromRequestFuture, CborDecodeFuture
+}
@@ -0,0 +1,411 @@
+use std::{
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use actix_web::{
+    rt::time::delay_for,
+    web::{Bytes, BytesMut},
+    HttpRequest, HttpResponse, Responder,
+};
+use futures::{
+    future::{ok, Ready},
+    stream::{self, LocalBoxStream},
+    Stream, StreamExt,
+};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    client::{Client, PhalanxClientError},
+    retry::RetryPolicy,
+};
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// How long clients wait before reconnecting, unless the server says otherwise
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+/// The longest clients wait between failed attempts to reconnect
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How many attempts in a row clients make to reconnect before giving up
+const MAX_RECONNECTS: u32 = 8;
+
+/// The most data clients read into an event, or a line of one
+const MAX_EVENT_SIZE: usize = 1024 * 1024;
+
+/// A server-sent event carrying a value of `T`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event<T> {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: T,
+}
+
+impl<T> Event<T> {
+    pub fn new(data: T) -> Self {
+        Event {
+            id: None,
+            event: None,
+            data,
+        }
+    }
+
+    /// Set the id clients resume from after reconnecting
+    pub fn with_id<S: ToString>(mut self, id: S) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Set the event's type
+    pub fn with_event<S: Into<String>>(mut self, event: S) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+}
+
+impl<T> From<T> for Event<T> {
+    fn from(data: T) -> Self {
+        Event::new(data)
+    }
+}
+
+type Resume<T> = Box<dyn FnOnce(Option<String>) -> LocalBoxStream<'static, Event<T>>>;
+
+/// A stream of events sent as `text/event-stream`
+///
+/// Each event's data is sent as JSON. Clients reconnect when the connection
+/// drops, sending the id of the last event they received in the
+/// `Last-Event-ID` header; streams created with [EventStream::resume] are
+/// passed that id so they can continue where the client left off.
+///
+/// Generated clients receive an [EventSource] of `T`.
+pub struct EventStream<T> {
+    resume: Option<Resume<T>>,
+    retry: Option<Duration>,
+}
+
+impl<T: 'static> EventStream<T> {
+    pub fn new<S, E>(events: S) -> Self
+    where
+        S: Stream<Item = E> + 'static,
+        E: Into<Event<T>> + 'static,
+    {
+        EventStream::resume(move |_| events)
+    }
+
+    /// Create the stream from the id of the last event the client received
+    pub fn resume<F, S, E>(resume: F) -> Self
+    where
+        F: FnOnce(Option<String>) -> S + 'static,
+        S: Stream<Item = E> + 'static,
+        E: Into<Event<T>> + 'static,
+    {
+        EventStream {
+            resume: Some(Box::new(move |last_event_id| {
+                resume(last_event_id).map(Into::into).boxed_local()
+            })),
+            retry: None,
+        }
+    }
+
+    /// Respond with `204 No Content`, telling clients to stop reconnecting
+    pub fn closed() -> Self {
+        EventStream {
+            resume: None,
+            retry: None,
+        }
+    }
+
+    /// Set how long clients wait before reconnecting
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl<T> fmt::Debug for EventStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventStream")
+    }
+}
+
+impl<T: Serialize + 'static> Responder for EventStream<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        let resume = match self.resume {
+            Some(resume) => resume,
+            None => return ok(HttpResponse::NoContent().finish()),
+        };
+        let last_event_id = req
+            .headers()
+            .get(LAST_EVENT_ID)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        let retry = self
+            .retry
+            .map(|retry| Ok(Bytes::from(format!("retry: {}\n\n", retry.as_millis()))));
+        let events = resume(last_event_id).map(|event| encode(&event).map(Bytes::from));
+
+        ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header("cache-control", "no-cache")
+            .streaming(stream::iter(retry).chain(events)))
+    }
+}
+
+fn encode<T: Serialize>(event: &Event<T>) -> Result<String, actix_web::Error> {
+    let mut out = String::new();
+    if let Some(id) = &event.id {
+        out.push_str(&format!("id: {}\n", single_line(id)));
+    }
+    if let Some(name) = &event.event {
+        out.push_str(&format!("event: {}\n", single_line(name)));
+    }
+    out.push_str(&format!(
+        "data: {}\n\n",
+        serde_json::to_string(&event.data)?
+    ));
+    Ok(out)
+}
+
+/// Ids and event types can't span lines, as each line is a separate field
+fn single_line(value: &str) -> String {
+    value.replace(['\n', '\r'], " ")
+}
+
+/// Receives the events of an [EventStream]
+///
+/// Connects through the [Client] like any other call, within its limits and
+/// circuits and at the replica picked for each attempt. Reconnects whenever
+/// the connection drops, waiting for the delay requested by the server and
+/// sending the id of the last event received.
+/// Failed attempts to reconnect are retried with exponential backoff, and the
+/// error of the last is returned as the stream's final item if none of 8 in a
+/// row succeed. The stream ends if the server responds with `204 No Content`;
+/// any other error response, or an event of over 1 MiB, is returned as the
+/// stream's final item.
+pub struct EventSource<T>(LocalBoxStream<'static, Result<T, PhalanxClientError>>);
+
+impl<T: DeserializeOwned + 'static> EventSource<T> {
+    /// Send `req` to the route named `route`, retrying it as `policy` allows,
+    /// and receive its events
+    ///
+    /// The request must not have a streaming body, as it is sent again
+    /// to reconnect.
+    pub async fn connect(
+        client: &Client,
+        route: &str,
+        req: RequestBuilder,
+        policy: &RetryPolicy,
+    ) -> Result<Self, PhalanxClientError> {
+        let req = req.header("accept", "text/event-stream");
+        let template = req.try_clone().ok_or_else(uncloneable)?;
+        let res = client.send(route, req, policy).await?.error_for_status()?;
+
+        let done = res.status() == StatusCode::NO_CONTENT;
+        let state = Source {
+            client: client.clone(),
+            route: String::from(route),
+            req: template,
+            res: if done { None } else { Some(res) },
+            buf: BytesMut::new(),
+            data: None,
+            id: None,
+            last_event_id: None,
+            retry: DEFAULT_RETRY,
+            failures: 0,
+            done,
+            _data: PhantomData,
+        };
+        Ok(EventSource(
+            stream::unfold(state, Source::next).boxed_local(),
+        ))
+    }
+}
+
+impl<T> Stream for EventSource<T> {
+    type Item = Result<T, PhalanxClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+impl<T> fmt::Debug for EventSource<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventSource")
+    }
+}
+
+/// The state of an [EventSource]'s connection
+struct Source<T> {
+    client: Client,
+    route: String,
+    req: RequestBuilder,
+    res: Option<Response>,
+    buf: BytesMut,
+    /// The data of the event being read
+    data: Option<String>,
+    /// The id of the event being read
+    id: Option<String>,
+    last_event_id: Option<String>,
+    retry: Duration,
+    /// How many attempts to reconnect have failed in a row
+    failures: u32,
+    done: bool,
+    _data: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Source<T> {
+    async fn next(mut self) -> Option<(Result<T, PhalanxClientError>, Self)> {
+        loop {
+            if let Some(line) = self.next_line() {
+                match self.field(&line) {
+                    Ok(Some(data)) => {
+                        let event = serde_json::from_str(&data).map_err(PhalanxClientError::from);
+                        return Some((event, self));
+                    }
+                    Ok(None) => {}
+                    Err(err) => return Some((Err(err), self.close())),
+                }
+                continue;
+            }
+            // What's left is part of a line
+            if self.buf.len() > MAX_EVENT_SIZE {
+                return Some((Err(too_large()), self.close()));
+            }
+
+            if let Some(res) = self.res.as_mut() {
+                match res.chunk().await {
+                    Ok(Some(chunk)) => self.buf.extend_from_slice(&chunk),
+                    // Events which weren't completed before the connection dropped are discarded
+                    Ok(None) | Err(_) => {
+                        self.res = None;
+                        self.buf.clear();
+                        self.data = None;
+                        self.id = None;
+                    }
+                }
+                continue;
+            }
+
+            if self.done {
+                return None;
+            }
+
+            // Failed attempts wait twice as long as the last, up to a point
+            let backoff = self
+                .retry
+                .checked_mul(1 << self.failures.min(16))
+                .unwrap_or(MAX_BACKOFF)
+                .min(MAX_BACKOFF.max(self.retry));
+            delay_for(backoff).await;
+            let mut req = match self.req.try_clone() {
+                Some(req) => req,
+                None => return Some((Err(uncloneable()), self.close())),
+            };
+            if let Some(id) = &self.last_event_id {
+                req = req.header(LAST_EVENT_ID, id.as_str());
+            }
+            // Attempts are retried here, with the delay the server asked for
+            let sent = self
+                .client
+                .send(&self.route, req, &RetryPolicy::never())
+                .await;
+            match sent {
+                Ok(res) if res.status() == StatusCode::NO_CONTENT => return None,
+                Ok(res) => match res.error_for_status() {
+                    Ok(res) => {
+                        self.failures = 0;
+                        self.res = Some(res);
+                    }
+                    Err(err) => return Some((Err(err.into()), self.close())),
+                },
+                // The server may be restarting, so it's tried again a few times
+                Err(err) => {
+                    self.failures += 1;
+                    if self.failures >= MAX_RECONNECTS {
+                        return Some((Err(err), self.close()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stop reading events and stop reconnecting, once the current item's returned
+    fn close(mut self) -> Self {
+        self.res = None;
+        self.buf.clear();
+        self.data = None;
+        self.done = true;
+        self
+    }
+
+    fn next_line(&mut self) -> Option<String> {
+        let end = self.buf.iter().position(|b| *b == b'\n')?;
+        let line = self.buf.split_to(end + 1);
+        let line = String::from_utf8_lossy(&line);
+        Some(String::from(line.trim_end_matches(['\n', '\r'])))
+    }
+
+    /// Read a line of the stream, returning the data of a completed event
+    fn field(&mut self, line: &str) -> Result<Option<String>, PhalanxClientError> {
+        if line.is_empty() {
+            // An empty id clears the last one, so reconnecting starts afresh
+            if let Some(id) = self.id.take() {
+                self.last_event_id = Some(id).filter(|id| !id.is_empty());
+            }
+            return Ok(self.data.take());
+        }
+
+        let (name, value) = match line.find(':') {
+            Some(0) => return Ok(None),
+            Some(i) => (
+                &line[..i],
+                line[i + 1..].strip_prefix(' ').unwrap_or(&line[i + 1..]),
+            ),
+            None => (line, ""),
+        };
+        match name {
+            "data" => {
+                let size = self.data.as_ref().map_or(0, |data| data.len() + 1) + value.len();
+                if size > MAX_EVENT_SIZE {
+                    return Err(too_large());
+                }
+                match &mut self.data {
+                    Some(data) => {
+                        data.push('\n');
+                        data.push_str(value);
+                    }
+                    None => self.data = Some(String::from(value)),
+                }
+            }
+            "id" => self.id = Some(String::from(value)),
+            "retry" => {
+                if let Ok(millis) = value.parse() {
+                    self.retry = Duration::from_millis(millis);
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+}
+
+fn too_large() -> PhalanxClientError {
+    PhalanxClientError::DecodeError(format!("event exceeds {} bytes", MAX_EVENT_SIZE))
+}
+
+fn uncloneable() -> PhalanxClientError {
+    PhalanxClientError::DecodeError(String::from(
+        "event requests must be cloneable to reconnect",
+    ))
+}
@@ -0,0 +1,113 @@
+use std::{fmt, ops};
+
+use actix_web::{dev::Payload, web::FormConfig, FromRequest, HttpRequest, HttpResponse, Responder};
+use futures::{future::Ready, FutureExt};
+use reqwest::{header::CONTENT_TYPE, RequestBuilder};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
+
+/// Struct wrapping actix_web's [Form](actix_web::web::Form) struct
+///
+/// Payloads are encoded as `application/x-www-form-urlencoded`
+pub struct Form<T>(pub T);
+
+impl<T> Form<T> {
+    /// Deconstruct to an inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ops::Deref for Form<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> ops::DerefMut for Form<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> fmt::Debug for Form<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Form: {:?}", self.0)
+    }
+}
+
+impl<T> fmt::Display for Form<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl<T: Serialize> Responder for Form<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        actix_web::web::Form(self.0).respond_to(req)
+    }
+}
+
+type FormFromRequestFuture<T> =
+    impl std::future::Future<Output = Result<Form<T>, actix_web::Error>>;
+
+/// Form extractor. Allow to extract typed information from request's payload.
+impl<T> FromRequest for Form<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = FormFromRequestFuture<T>;
+    type Config = FormConfig;
+
+    #[inline]
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        actix_web::web::Form::<T>::from_request(req, payload)
+            .map(|res| res.map(|form| Form(form.into_inner())))
+    }
+}
+
+type FormDecodeFuture<T> = impl std::future::Future<Output = Result<Form<T>, PhalanxClientError>>;
+
+impl<T> PhalanxPayload for Form<T>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = FormDecodeFuture<T>;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        let string = serde_urlencoded::to_string(&self.0)?;
+        Ok(req
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(string))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let res = res.0.error_for_status()?;
+            let bytes = res.bytes().await?;
+            let form = serde_urlencoded::from_bytes(&bytes)?;
+            Ok(Form(form))
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError},
+    HttpResponse,
+};
+use futures::future::{ready, Ready};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::client::PhalanxClientError;
+
+/// A serialization format for structured payloads
+///
+/// Each format has a wrapper type, such as [Json](super::Json) or
+/// [Cbor](super::Cbor), which is used as a route payload or return value.
+pub trait Format {
+    type EncodeError: std::error::Error + 'static;
+    type DecodeError: std::error::Error + Into<PhalanxClientError> + 'static;
+
+    /// The content type payloads in this format are sent with
+    fn content_type() -> &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::EncodeError>;
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::DecodeError>;
+}
+
+#[cfg_attr(
+    not(any(feature = "cbor", feature = "msgpack", feature = "bincode")),
+    allow(dead_code)
+)]
+pub(crate) fn decode_payload<F: Format, T: DeserializeOwned>(
+    bytes: &[u8],
+) -> Result<T, actix_web::Error> {
+    F::decode(bytes).map_err(ErrorBadRequest)
+}
+
+#[cfg_attr(
+    not(any(feature = "cbor", feature = "msgpack", feature = "bincode")),
+    allow(dead_code)
+)]
+pub(crate) fn respond<F: Format, T: Serialize>(
+    value: &T,
+) -> Ready<Result<HttpResponse, actix_web::Error>> {
+    ready(
+        F::encode(value)
+            .map(|body| {
+                HttpResponse::Ok()
+                    .content_type(F::content_type())
+                    .body(body)
+            })
+            .map_err(ErrorInternalServerError),
+    )
+}
+
+/// Define a wrapper type sending its contents in a [Format]
+///
+/// The names of the wrapper's extractor and client futures are passed in,
+/// as each needs its own type alias. [Json](super::Json) is written out by
+/// hand instead, to keep extracting its payloads with actix.
+#[cfg_attr(
+    not(any(feature = "cbor", feature = "msgpack", feature = "bincode")),
+    allow(unused_macros)
+)]
+macro_rules! format_wrapper {
+    (
+        $(#[$attr:meta])*
+        $name:ident($format:ty), $from_request_future:ident, $decode_future:ident
+    ) => {
+        $(#[$attr])*
+        pub struct $name<T>(pub T);
+
+        impl<T> $name<T> {
+            /// Deconstruct to an inner value
+            pub fn into_inner(self) -> T {
+                self.0
+            }
+        }
+
+        impl<T> std::ops::Deref for $name<T> {
+            type Target = T;
+
+            fn deref(&self) -> &T {
+                &self.0
+            }
+        }
+
+        impl<T> std::ops::DerefMut for $name<T> {
+            fn deref_mut(&mut self) -> &mut T {
+                &mut self.0
+            }
+        }
+
+        impl<T> std::fmt::Debug for $name<T>
+        where
+            T: std::fmt::Debug,
+        {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, concat!(stringify!($name), ": {:?}"), self.0)
+            }
+        }
+
+        impl<T> std::fmt::Display for $name<T>
+        where
+            T: std::fmt::Display,
+        {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl<T: serde::Serialize> actix_web::Responder for $name<T> {
+            type Error = actix_web::Error;
+            type Future = futures::future::Ready<Result<actix_web::HttpResponse, actix_web::Error>>;
+
+            fn respond_to(self, _: &actix_web::HttpRequest) -> Self::Future {
+                $crate::web::format::respond::<$format, T>(&self.0)
+            }
+        }
+
+        type $from_request_future<T> =
+            impl std::future::Future<Output = Result<$name<T>, actix_web::Error>>;
+
+        /// Extractor decoding the request's payload. Payload size limits are
+        /// configured with [PayloadConfig](actix_web::web::PayloadConfig).
+        impl<T> actix_web::FromRequest for $name<T>
+        where
+            T: serde::de::DeserializeOwned + 'static,
+        {
+            type Error = actix_web::Error;
+            type Future = $from_request_future<T>;
+            type Config = actix_web::web::PayloadConfig;
+
+            #[inline]
+            fn from_request(
+                req: &actix_web::HttpRequest,
+                payload: &mut actix_web::dev::Payload,
+            ) -> Self::Future {
+                let bytes = $crate::server::read_payload(
+                    req,
+                    payload,
+                    <$format as $crate::web::Format>::content_type(),
+                );
+                async move {
+                    let bytes = bytes.await?;
+                    $crate::web::format::decode_payload::<$format, T>(&bytes).map($name)
+                }
+            }
+        }
+
+        type $decode_future<T> = impl std::future::Future<
+            Output = Result<$name<T>, $crate::client::PhalanxClientError>,
+        >;
+
+        impl<T> $crate::client::PhalanxPayload for $name<T>
+        where
+            T: serde::Serialize + serde::de::DeserializeOwned + 'static,
+        {
+            type ExtractFuture = <Self as actix_web::FromRequest>::Future;
+            type DecodeFuture = $decode_future<T>;
+
+            fn into_request(
+                self,
+                req: reqwest::RequestBuilder,
+            ) -> Result<reqwest::RequestBuilder, Box<dyn std::error::Error>> {
+                let vec = <$format as $crate::web::Format>::encode(&self.0)?;
+                Ok(req
+                    .header(
+                        reqwest::header::CONTENT_TYPE,
+                        <$format as $crate::web::Format>::content_type(),
+                    )
+                    .body(vec))
+            }
+
+            fn extract(
+                req: &actix_web::HttpRequest,
+                payload: &mut actix_web::dev::Payload,
+            ) -> Self::ExtractFuture {
+                <Self as actix_web::FromRequest>::from_request(req, payload)
+            }
+
+            fn decode(res: $crate::client::PhalanxResponse) -> Self::DecodeFuture {
+                async {
+                    let res = res.0.error_for_status()?;
+                    let bytes = res.bytes().await?;
+                    let value = <$format as $crate::web::Format>::decode(&bytes)
+                        .map_err(Into::<$crate::client::PhalanxClientError>::into)?;
+                    Ok($name(value))
+                }
+            }
+        }
+    };
+}
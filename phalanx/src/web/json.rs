@@ -1,27 +1,39 @@
-use std::{convert::TryFrom, fmt, ops};
+use std::{fmt, ops};
 
-use actix_web::{web::JsonConfig, FromRequest, HttpRequest, HttpResponse, Responder};
+use actix_web::{dev::Payload, web::JsonConfig, FromRequest, HttpRequest, HttpResponse, Responder};
 use futures::{future::Ready, FutureExt};
-use reqwest::Body;
-use serde::Serialize;
+use reqwest::{header::CONTENT_TYPE, RequestBuilder};
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::{
-    client::{PhalanxClientError, PhalanxResponse},
-    util::AsyncTryFrom,
-};
+use super::Format;
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
 
-/// Struct wrapping actix_web's [Json](actix_web::web::Json) struct
-pub struct Json<T>(pub T);
+/// The JSON format, sent as `application/json`
+pub struct JsonFormat;
 
-impl<T: Serialize> TryFrom<Json<T>> for Body {
-    type Error = serde_json::Error;
+impl Format for JsonFormat {
+    type EncodeError = serde_json::Error;
+    type DecodeError = serde_json::Error;
 
-    fn try_from(value: Json<T>) -> Result<Self, Self::Error> {
-        let vec = serde_json::to_vec(&value.0)?;
-        Ok(Body::from(vec))
+    fn content_type() -> &'static str {
+        "application/json"
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::EncodeError> {
+        serde_json::to_vec(value)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::DecodeError> {
+        serde_json::from_slice(bytes)
     }
 }
 
+/// Struct wrapping actix_web's [Json](actix_web::web::Json) struct
+///
+/// Unlike the other [Format] wrappers, payloads are extracted by actix, so
+/// they're configured with [JsonConfig] and accept `application/*+json`.
+pub struct Json<T>(pub T);
+
 impl<T> Json<T> {
     /// Deconstruct to an inner value
     pub fn into_inner(self) -> T {
@@ -29,7 +41,7 @@ impl<T> Json<T> {
     }
 }
 
-impl<T> std::ops::Deref for Json<T> {
+impl<T> ops::Deref for Json<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -76,37 +88,48 @@ type JsonFromRequestFuture<T> =
 /// Json extractor. Allow to extract typed information from request's payload.
 impl<T> FromRequest for Json<T>
 where
-    T: serde::de::DeserializeOwned + 'static,
+    T: DeserializeOwned + 'static,
 {
     type Error = actix_web::Error;
     type Future = JsonFromRequestFuture<T>;
     type Config = JsonConfig;
 
     #[inline]
-    fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         actix_web::web::Json::<T>::from_request(req, payload)
             .map(|res| res.map(|json| Json(json.into_inner())))
     }
 }
 
-type JsonAsyncTryFrom<T> = impl std::future::Future<Output = Result<Json<T>, PhalanxClientError>>;
+type JsonDecodeFuture<T> = impl std::future::Future<Output = Result<Json<T>, PhalanxClientError>>;
 
-impl<T: serde::de::DeserializeOwned> AsyncTryFrom<PhalanxResponse> for Json<T> {
-    type Error = PhalanxClientError;
-    type Future = JsonAsyncTryFrom<T>;
+impl<T> PhalanxPayload for Json<T>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = JsonDecodeFuture<T>;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        let vec = JsonFormat::encode(&self.0)?;
+        Ok(req
+            .header(CONTENT_TYPE, JsonFormat::content_type())
+            .body(vec))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
 
-    fn try_from(res: PhalanxResponse) -> Self::Future {
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
         async {
             let res = res.0.error_for_status()?;
             let bytes = res.bytes().await?;
-            let json = serde_json::from_slice(&bytes)?;
+            let json = JsonFormat::decode(&bytes)?;
             Ok(Json(json))
         }
     }
 }
-
-impl<T> From<&Json<T>> for crate::client::ContentType {
-    fn from(_: &Json<T>) -> Self {
-        Self::APPLICATION_JSON
-    }
-}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    web::{Bytes, BytesMut},
+    HttpRequest, HttpResponse, Responder,
+};
+use futures::{
+    future::{ok, ready, Ready},
+    stream::{self, LocalBoxStream},
+    Stream, StreamExt,
+};
+use reqwest::Response;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    client::{PhalanxClientError, PhalanxResponse},
+    util::AsyncTryFrom,
+};
+
+/// A stream of values sent as newline delimited JSON
+///
+/// The server writes each item as it is produced, in a chunked
+/// `application/x-ndjson` response. Route methods may also return
+/// `impl Stream<Item = T>`, which is sent as a `JsonStream<T>`.
+///
+/// Clients receive a `JsonStream<Result<T, PhalanxClientError>>`, which
+/// decodes each item as soon as its line has arrived.
+pub struct JsonStream<T>(LocalBoxStream<'static, T>);
+
+impl<T> JsonStream<T> {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = T> + 'static,
+    {
+        JsonStream(Box::pin(stream))
+    }
+}
+
+impl<T> Stream for JsonStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+impl<T> fmt::Debug for JsonStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "JsonStream")
+    }
+}
+
+impl<T: Serialize + 'static> Responder for JsonStream<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, _: &HttpRequest) -> Self::Future {
+        let lines = self.0.map(|item| {
+            let mut line = serde_json::to_vec(&item)?;
+            line.push(b'\n');
+            Ok::<_, actix_web::Error>(Bytes::from(line))
+        });
+
+        ok(HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .streaming(lines))
+    }
+}
+
+impl<T> AsyncTryFrom<PhalanxResponse> for JsonStream<Result<T, PhalanxClientError>>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = PhalanxClientError;
+    type Future = Ready<Result<Self, PhalanxClientError>>;
+
+    fn try_from(res: PhalanxResponse) -> Self::Future {
+        ready(
+            res.0
+                .error_for_status()
+                .map(|res| JsonStream::new(lines(res)))
+                .map_err(PhalanxClientError::from),
+        )
+    }
+}
+
+/// The state of a response being split into lines
+struct Lines {
+    res: Option<Response>,
+    buf: BytesMut,
+}
+
+/// Decode each line of `res` as it arrives
+fn lines<T: DeserializeOwned>(res: Response) -> impl Stream<Item = Result<T, PhalanxClientError>> {
+    let state = Lines {
+        res: Some(res),
+        buf: BytesMut::new(),
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(end) = state.buf.iter().position(|b| *b == b'\n') {
+                let line = state.buf.split_to(end + 1);
+                if is_blank(&line) {
+                    continue;
+                }
+                return Some((decode(&line), state));
+            }
+
+            let res = state.res.as_mut()?;
+            match res.chunk().await {
+                Ok(Some(chunk)) => state.buf.extend_from_slice(&chunk),
+                Ok(None) => {
+                    // The last line may not be terminated
+                    state.res = None;
+                    let line = state.buf.split();
+                    if is_blank(&line) {
+                        return None;
+                    }
+                    return Some((decode(&line), state));
+                }
+                Err(err) => {
+                    state.res = None;
+                    state.buf.clear();
+                    return Some((Err(err.into()), state));
+                }
+            }
+        }
+    })
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter().all(u8::is_ascii_whitespace)
+}
+
+fn decode<T: DeserializeOwned>(line: &[u8]) -> Result<T, PhalanxClientError> {
+    Ok(serde_json::from_slice(line)?)
+}
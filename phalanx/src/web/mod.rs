@@ -1,3 +1,44 @@
+#[macro_use]
+mod format;
+
+mod binary;
+#[cfg(feature = "bincode")]
+mod bincode;
+mod body_stream;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod event_stream;
+mod form;
 mod json;
+mod json_stream;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+#[cfg(feature = "multipart")]
+pub mod multipart;
+mod negotiated;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+mod text;
+pub mod websocket;
 
-pub use json::Json;
+#[cfg(feature = "bincode")]
+pub use self::bincode::{Bincode, BincodeFormat};
+pub use actix_web::web::Bytes;
+pub use binary::Binary;
+pub use body_stream::BodyStream;
+#[cfg(feature = "cbor")]
+pub use cbor::{Cbor, CborFormat};
+pub use event_stream::{Event, EventSource, EventStream};
+pub use form::Form;
+pub use format::Format;
+pub use json::{Json, JsonFormat};
+pub use json_stream::JsonStream;
+#[cfg(feature = "msgpack")]
+pub use msgpack::{MsgPack, MsgPackFormat};
+#[cfg(feature = "multipart")]
+pub use multipart::Multipart;
+pub use negotiated::{Negotiated, NegotiatedFormat, NegotiationConfig};
+#[cfg(feature = "protobuf")]
+pub use protobuf::Protobuf;
+pub use text::{Text, TextParseError};
+pub use websocket::{WsSink, WsStream};
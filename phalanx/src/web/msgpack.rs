@@ -0,0 +1,31 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::Format;
+
+/// The MessagePack format, sent as `application/msgpack`
+///
+/// Structs are encoded as maps rather than arrays, so fields may be added
+/// or reordered without breaking older clients.
+pub struct MsgPackFormat;
+
+impl Format for MsgPackFormat {
+    type EncodeError = rmp_serde::encode::Error;
+    type DecodeError = rmp_serde::decode::Error;
+
+    fn content_type() -> &'static str {
+        "application/msgpack"
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::EncodeError> {
+        rmp_serde::to_vec_named(value)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::DecodeError> {
+        rmp_serde::from_slice(bytes)
+    }
+}
+
+format_wrapper! {
+    /// A payload or response encoded as MessagePack
+    MsgPack(MsgPackFormat), MsgPackFromRequestFuture, MsgPackDecodeFuture
+}
@@ -0,0 +1,524 @@
+use std::{
+    collections::HashMap,
+    fmt, mem, ops,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use actix_multipart::MultipartError;
+use actix_web::{
+    dev::Payload,
+    http::StatusCode,
+    web::{Bytes, BytesMut},
+    FromRequest, HttpRequest, ResponseError,
+};
+use err_derive::Error;
+use futures::{
+    future::{err, Ready},
+    stream::{self, LocalBoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
+use reqwest::{Body, RequestBuilder};
+
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
+
+pub use phalanx_codegen::MultipartForm;
+pub use reqwest::multipart::{Form, Part};
+
+/// A `multipart/form-data` payload
+///
+/// `T` is a struct of named parts, usually implemented with
+/// `#[derive(MultipartForm)]`.
+pub struct Multipart<T>(pub T);
+
+impl<T> Multipart<T> {
+    /// Deconstruct to an inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ops::Deref for Multipart<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> ops::DerefMut for Multipart<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> fmt::Debug for Multipart<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Multipart: {:?}", self.0)
+    }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A file uploaded as part of a multipart form
+///
+/// Files are streams of their contents. A file sent once every required field
+/// of its form has been received is streamed straight from the request as the
+/// route reads it, with its limit checked as it goes, so it should be the last
+/// field of its form, and optional fields left out don't hold it back. Files
+/// sent before required fields are read into memory first, up to their limit.
+pub struct File {
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    data: Data,
+}
+
+enum Data {
+    Bytes(Bytes),
+    /// Sent by a client as it's read
+    Sent(Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send + Sync>>),
+    /// Streamed from a request
+    Received(LocalBoxStream<'static, Result<Bytes, MultipartFormError>>),
+}
+
+impl File {
+    pub fn new<B: Into<Bytes>>(data: B) -> Self {
+        File {
+            file_name: None,
+            content_type: None,
+            data: Data::Bytes(data.into()),
+        }
+    }
+
+    /// A file sent a chunk at a time as `stream` yields them, such as one read from disk
+    pub fn stream<S, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Sync + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        File {
+            file_name: None,
+            content_type: None,
+            data: Data::Sent(Box::pin(stream.map_err(Into::into))),
+        }
+    }
+
+    pub fn file_name<S: Into<String>>(mut self, file_name: S) -> Self {
+        self.file_name = Some(file_name.into());
+        self
+    }
+
+    pub fn content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Read the whole file into memory
+    pub async fn bytes(self) -> Result<Bytes, MultipartFormError> {
+        if let Data::Bytes(data) = self.data {
+            return Ok(data);
+        }
+        let mut data = BytesMut::new();
+        let mut chunks = self;
+        while let Some(chunk) = chunks.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data.freeze())
+    }
+}
+
+impl Stream for File {
+    type Item = Result<Bytes, MultipartFormError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut self.data {
+            Data::Bytes(data) if data.is_empty() => Poll::Ready(None),
+            Data::Bytes(data) => Poll::Ready(Some(Ok(mem::take(data)))),
+            Data::Sent(stream) => match stream.as_mut().poll_next(cx) {
+                Poll::Ready(item) => {
+                    Poll::Ready(item.map(|res| res.map_err(MultipartFormError::Read)))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            Data::Received(stream) => stream.as_mut().poll_next(cx),
+        }
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data = match &self.data {
+            Data::Bytes(data) => format!("{} bytes", data.len()),
+            Data::Sent(_) | Data::Received(_) => String::from("stream"),
+        };
+        f.debug_struct("File")
+            .field("file_name", &self.file_name)
+            .field("content_type", &self.content_type)
+            .field("data", &data)
+            .finish()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MultipartFormError {
+    #[error(display = "error reading multipart payload: {}", _0)]
+    Multipart(MultipartError),
+    #[error(display = "multipart part is missing a name")]
+    UnnamedPart,
+    #[error(display = "unexpected part `{}`", _0)]
+    UnknownPart(String),
+    #[error(display = "missing part `{}`", _0)]
+    MissingPart(String),
+    #[error(display = "part `{}` was sent more than once", _0)]
+    DuplicatePart(String),
+    #[error(display = "part `{}` was sent more than {} times", _0, _1)]
+    TooManyParts(String, usize),
+    #[error(display = "part `{}` exceeds the limit of {} bytes", _0, _1)]
+    PartTooLarge(String, usize),
+    #[error(display = "part `{}` is not valid UTF-8", _0)]
+    InvalidUtf8(String),
+    #[error(display = "part `{}` was sent after the streamed file `{}`", _0, _1)]
+    PartAfterStream(String, String),
+    #[error(
+        display = "part `{}` is streamed from a request, and must be read before it's sent",
+        _0
+    )]
+    Forwarded(String),
+    #[error(display = "error reading file: {}", _0)]
+    Read(BoxError),
+}
+
+impl From<MultipartError> for MultipartFormError {
+    fn from(err: MultipartError) -> Self {
+        MultipartFormError::Multipart(err)
+    }
+}
+
+impl ResponseError for MultipartFormError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MultipartFormError::Multipart(err) => err.status_code(),
+            MultipartFormError::PartTooLarge(_, _) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// The parts of a received multipart payload, grouped by name
+pub struct Parts(HashMap<String, Vec<File>>);
+
+impl Parts {
+    /// Remove all parts sent under `name`
+    pub fn take(&mut self, name: &str) -> Vec<File> {
+        self.0.remove(name).unwrap_or_default()
+    }
+}
+
+/// A struct of named multipart parts
+pub trait MultipartForm: Sized {
+    /// The maximum size in bytes of the part `name`, or `None` if the part is unexpected
+    fn limit(name: &str) -> Option<usize>;
+
+    /// Whether the part `name` is streamed to the route rather than read into memory
+    fn streamed(name: &str) -> bool;
+
+    /// Whether the part `name` must be sent
+    fn required(name: &str) -> bool;
+
+    /// How many times the part `name` may be sent
+    fn max_count(name: &str) -> usize;
+
+    /// The names of the form's fields
+    fn fields() -> &'static [&'static str];
+
+    fn from_parts(parts: &mut Parts) -> Result<Self, MultipartFormError>;
+
+    fn into_form(self) -> Result<Form, Box<dyn std::error::Error>>;
+}
+
+/// A single field of a [MultipartForm]
+pub trait MultipartField: Sized {
+    /// The default maximum size of a part, in bytes
+    const DEFAULT_LIMIT: usize;
+
+    /// Whether the field is streamed when it's the last part of a payload
+    const STREAMED: bool = false;
+
+    /// Whether a payload without the field is rejected
+    const REQUIRED: bool = true;
+
+    /// The default number of times the field's part may be sent
+    const DEFAULT_MAX_COUNT: usize = 1;
+
+    fn from_parts(name: &str, parts: Vec<File>) -> Result<Self, MultipartFormError>;
+
+    fn add_to_form(
+        self,
+        name: &'static str,
+        form: Form,
+    ) -> Result<Form, Box<dyn std::error::Error>>;
+}
+
+fn single_part(name: &str, parts: Vec<File>) -> Result<File, MultipartFormError> {
+    let mut parts = parts.into_iter();
+    match (parts.next(), parts.next()) {
+        (Some(part), None) => Ok(part),
+        (None, _) => Err(MultipartFormError::MissingPart(name.into())),
+        (Some(_), Some(_)) => Err(MultipartFormError::DuplicatePart(name.into())),
+    }
+}
+
+impl MultipartField for String {
+    const DEFAULT_LIMIT: usize = 64 * 1024;
+
+    fn from_parts(name: &str, parts: Vec<File>) -> Result<Self, MultipartFormError> {
+        let data = match single_part(name, parts)?.data {
+            Data::Bytes(data) => data,
+            _ => unreachable!("parts which aren't streamed are read into memory"),
+        };
+        String::from_utf8(data.to_vec()).map_err(|_| MultipartFormError::InvalidUtf8(name.into()))
+    }
+
+    fn add_to_form(
+        self,
+        name: &'static str,
+        form: Form,
+    ) -> Result<Form, Box<dyn std::error::Error>> {
+        Ok(form.text(name, self))
+    }
+}
+
+impl MultipartField for File {
+    const DEFAULT_LIMIT: usize = 16 * 1024 * 1024;
+    const STREAMED: bool = true;
+
+    fn from_parts(name: &str, parts: Vec<File>) -> Result<Self, MultipartFormError> {
+        single_part(name, parts)
+    }
+
+    fn add_to_form(
+        self,
+        name: &'static str,
+        form: Form,
+    ) -> Result<Form, Box<dyn std::error::Error>> {
+        let mut part = match self.data {
+            Data::Bytes(data) => {
+                let length = data.len() as u64;
+                Part::stream_with_length(data, length)
+            }
+            Data::Sent(stream) => Part::stream(Body::wrap_stream(stream)),
+            Data::Received(_) => return Err(MultipartFormError::Forwarded(name.into()).into()),
+        };
+        if let Some(file_name) = self.file_name {
+            part = part.file_name(file_name);
+        }
+        if let Some(content_type) = self.content_type {
+            part = part.mime_str(&content_type)?;
+        }
+        Ok(form.part(name, part))
+    }
+}
+
+impl<T: MultipartField> MultipartField for Option<T> {
+    const DEFAULT_LIMIT: usize = T::DEFAULT_LIMIT;
+    const STREAMED: bool = T::STREAMED;
+    const REQUIRED: bool = false;
+    const DEFAULT_MAX_COUNT: usize = T::DEFAULT_MAX_COUNT;
+
+    fn from_parts(name: &str, parts: Vec<File>) -> Result<Self, MultipartFormError> {
+        if parts.is_empty() {
+            Ok(None)
+        } else {
+            T::from_parts(name, parts).map(Some)
+        }
+    }
+
+    fn add_to_form(
+        self,
+        name: &'static str,
+        form: Form,
+    ) -> Result<Form, Box<dyn std::error::Error>> {
+        match self {
+            Some(value) => value.add_to_form(name, form),
+            None => Ok(form),
+        }
+    }
+}
+
+/// Lists are limited to 16 parts by default, each up to the limit of `T`
+impl<T: MultipartField> MultipartField for Vec<T> {
+    const DEFAULT_LIMIT: usize = T::DEFAULT_LIMIT;
+    const REQUIRED: bool = false;
+    const DEFAULT_MAX_COUNT: usize = 16;
+
+    fn from_parts(name: &str, parts: Vec<File>) -> Result<Self, MultipartFormError> {
+        parts
+            .into_iter()
+            .map(|part| T::from_parts(name, vec![part]))
+            .collect()
+    }
+
+    fn add_to_form(
+        self,
+        name: &'static str,
+        mut form: Form,
+    ) -> Result<Form, Box<dyn std::error::Error>> {
+        for value in self {
+            form = value.add_to_form(name, form)?;
+        }
+        Ok(form)
+    }
+}
+
+impl<T: MultipartForm + 'static> PhalanxPayload for Multipart<T> {
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = Ready<Result<Self, PhalanxClientError>>;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        Ok(req.multipart(self.0.into_form()?))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    /// Multipart forms are only sent as payloads, so responses always fail to decode
+    fn decode(_: PhalanxResponse) -> Self::DecodeFuture {
+        err(PhalanxClientError::DecodeError(String::from(
+            "multipart responses aren't supported",
+        )))
+    }
+}
+
+type MultipartFromRequestFuture<T> =
+    impl std::future::Future<Output = Result<Multipart<T>, actix_web::Error>>;
+
+/// Multipart extractor. Each part is read incrementally and rejected as soon
+/// as it grows past its limit or is sent too many times, until a streamed part
+/// is reached after every other required field, which is left for the route
+/// to read.
+impl<T> FromRequest for Multipart<T>
+where
+    T: MultipartForm + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = MultipartFromRequestFuture<T>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let mut multipart = actix_multipart::Multipart::new(req.headers(), payload.take());
+        async move {
+            let mut parts: HashMap<String, Vec<File>> = HashMap::new();
+
+            while let Some(field) = multipart.next().await {
+                let mut field = field.map_err(MultipartFormError::from)?;
+
+                let disposition = field.content_disposition();
+                let name = disposition
+                    .as_ref()
+                    .and_then(|disposition| disposition.get_name())
+                    .ok_or(MultipartFormError::UnnamedPart)?
+                    .to_string();
+                let file_name = disposition
+                    .as_ref()
+                    .and_then(|disposition| disposition.get_filename())
+                    .map(String::from);
+
+                let limit =
+                    T::limit(&name).ok_or_else(|| MultipartFormError::UnknownPart(name.clone()))?;
+                let content_type = Some(field.content_type().to_string());
+
+                let max_count = T::max_count(&name);
+                let count = parts.get(&name).map_or(0, Vec::len);
+                if count >= max_count {
+                    return Err(match max_count {
+                        1 => MultipartFormError::DuplicatePart(name),
+                        _ => MultipartFormError::TooManyParts(name, max_count),
+                    }
+                    .into());
+                }
+
+                // Other fields can only be read once a streamed part is, so
+                // it's left to the route only if the required ones have all
+                // been received
+                let last = T::fields().iter().all(|field| {
+                    *field == name || !T::required(field) || parts.contains_key(*field)
+                });
+                if T::streamed(&name) && last {
+                    let data = Data::Received(stream_part(multipart, field, name.clone(), limit));
+                    parts.entry(name).or_default().push(File {
+                        file_name,
+                        content_type,
+                        data,
+                    });
+                    break;
+                }
+
+                let mut data = BytesMut::new();
+                while let Some(chunk) = field.next().await {
+                    let chunk = chunk.map_err(MultipartFormError::from)?;
+                    if data.len() + chunk.len() > limit {
+                        return Err(MultipartFormError::PartTooLarge(name, limit).into());
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+
+                parts.entry(name).or_default().push(File {
+                    file_name,
+                    content_type,
+                    data: Data::Bytes(data.freeze()),
+                });
+            }
+
+            Ok(Multipart(T::from_parts(&mut Parts(parts))?))
+        }
+    }
+}
+
+/// Stream the rest of `field`, the part `name`, failing if it grows past
+/// `limit` or another part follows it
+fn stream_part(
+    multipart: actix_multipart::Multipart,
+    field: actix_multipart::Field,
+    name: String,
+    limit: usize,
+) -> LocalBoxStream<'static, Result<Bytes, MultipartFormError>> {
+    stream::unfold(Some((multipart, field, 0)), move |state| {
+        let name = name.clone();
+        async move {
+            let (mut multipart, mut field, read) = state?;
+            match field.next().await {
+                Some(Ok(chunk)) if read + chunk.len() > limit => {
+                    Some((Err(MultipartFormError::PartTooLarge(name, limit)), None))
+                }
+                Some(Ok(chunk)) => {
+                    let read = read + chunk.len();
+                    Some((Ok(chunk), Some((multipart, field, read))))
+                }
+                Some(Err(err)) => Some((Err(err.into()), None)),
+                None => {
+                    // The next part can only be read once the field is gone
+                    drop(field);
+                    match multipart.next().await {
+                        None => None,
+                        Some(Ok(next)) => {
+                            let next = next
+                                .content_disposition()
+                                .and_then(|disposition| disposition.get_name().map(String::from))
+                                .unwrap_or_default();
+                            Some((Err(MultipartFormError::PartAfterStream(next, name)), None))
+                        }
+                        Some(Err(err)) => Some((Err(err.into()), None)),
+                    }
+                }
+            }
+        }
+    })
+    .boxed_local()
+}
@@ -0,0 +1,405 @@
+use std::{fmt, ops};
+
+use actix_web::{
+    dev::Payload,
+    error::{
+        ErrorBadRequest, ErrorInternalServerError, ErrorNotAcceptable, ErrorUnsupportedMediaType,
+    },
+    http::header::{ACCEPT, CONTENT_TYPE, VARY},
+    web::Bytes,
+    FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures::future::{ready, Ready};
+use reqwest::RequestBuilder;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "bincode")]
+use super::BincodeFormat;
+#[cfg(feature = "cbor")]
+use super::CborFormat;
+#[cfg(feature = "msgpack")]
+use super::MsgPackFormat;
+use super::{Format, JsonFormat};
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
+
+/// A payload or response sent in whichever format both ends support
+///
+/// The server decodes payloads according to their `Content-Type` and
+/// responds in the best match for the request's `Accept` header, answering
+/// `415 Unsupported Media Type` or `406 Not Acceptable` when no registered
+/// format matches. Routes returning negotiated values check `Accept` before
+/// they're run. Clients send payloads as JSON, which servers accept unless
+/// configured otherwise, and decode responses in whatever format the server
+/// picked.
+pub struct Negotiated<T>(pub T);
+
+impl<T> Negotiated<T> {
+    /// Deconstruct to an inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ops::Deref for Negotiated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> ops::DerefMut for Negotiated<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> fmt::Debug for Negotiated<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Negotiated: {:?}", self.0)
+    }
+}
+
+impl<T> fmt::Display for Negotiated<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// A format [Negotiated] values can be sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegotiatedFormat {
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+impl NegotiatedFormat {
+    /// Every compiled in format, in the order clients prefer them
+    ///
+    /// Compact binary formats come first, with JSON as the fallback.
+    pub fn client_preference() -> Vec<Self> {
+        vec![
+            #[cfg(feature = "msgpack")]
+            NegotiatedFormat::MsgPack,
+            #[cfg(feature = "cbor")]
+            NegotiatedFormat::Cbor,
+            #[cfg(feature = "bincode")]
+            NegotiatedFormat::Bincode,
+            NegotiatedFormat::Json,
+        ]
+    }
+
+    /// The `Accept` header sent by clients, weighting formats by [client_preference](Self::client_preference)
+    pub fn accept_header() -> String {
+        Self::client_preference()
+            .into_iter()
+            .enumerate()
+            .map(|(i, format)| match i {
+                0 => format.content_type().to_string(),
+                _ => format!("{};q=0.{}", format.content_type(), 10 - i.min(9)),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            NegotiatedFormat::Json => JsonFormat::content_type(),
+            #[cfg(feature = "cbor")]
+            NegotiatedFormat::Cbor => CborFormat::content_type(),
+            #[cfg(feature = "msgpack")]
+            NegotiatedFormat::MsgPack => MsgPackFormat::content_type(),
+            #[cfg(feature = "bincode")]
+            NegotiatedFormat::Bincode => BincodeFormat::content_type(),
+        }
+    }
+
+    /// The format to respond to `req` in, or `406 Not Acceptable` if the
+    /// request accepts none of those registered with [NegotiationConfig]
+    ///
+    /// Used by generated handlers to refuse requests before running the route.
+    pub fn accepted(req: &HttpRequest) -> Result<Self, actix_web::Error> {
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .map(|accept| accept.to_str().unwrap_or_default());
+
+        NegotiationConfig::from_req(req)
+            .select(accept)
+            .ok_or_else(|| {
+                ErrorNotAcceptable(format!(
+                    "no supported format matches {}",
+                    accept.unwrap_or_default()
+                ))
+            })
+    }
+
+    /// Find the format sent with the media type `mime`, ignoring any parameters
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        Self::client_preference()
+            .into_iter()
+            .find(|format| essence.eq_ignore_ascii_case(format.content_type()))
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(match self {
+            NegotiatedFormat::Json => JsonFormat::encode(value)?,
+            #[cfg(feature = "cbor")]
+            NegotiatedFormat::Cbor => CborFormat::encode(value)?,
+            #[cfg(feature = "msgpack")]
+            NegotiatedFormat::MsgPack => MsgPackFormat::encode(value)?,
+            #[cfg(feature = "bincode")]
+            NegotiatedFormat::Bincode => BincodeFormat::encode(value)?,
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, PhalanxClientError> {
+        Ok(match self {
+            NegotiatedFormat::Json => JsonFormat::decode(bytes)?,
+            #[cfg(feature = "cbor")]
+            NegotiatedFormat::Cbor => CborFormat::decode(bytes)?,
+            #[cfg(feature = "msgpack")]
+            NegotiatedFormat::MsgPack => MsgPackFormat::decode(bytes)?,
+            #[cfg(feature = "bincode")]
+            NegotiatedFormat::Bincode => BincodeFormat::decode(bytes)?,
+        })
+    }
+}
+
+/// The formats a server accepts and responds with for [Negotiated] values
+///
+/// Register with [App::app_data](actix_web::App::app_data). Defaults to
+/// every compiled in format, preferring JSON when the client accepts anything.
+#[derive(Debug, Clone)]
+pub struct NegotiationConfig {
+    formats: Vec<NegotiatedFormat>,
+}
+
+impl NegotiationConfig {
+    /// Set the accepted formats, in the order the server prefers them
+    pub fn formats(mut self, formats: &[NegotiatedFormat]) -> Self {
+        self.formats = formats.to_vec();
+        self
+    }
+
+    fn from_req(req: &HttpRequest) -> Self {
+        req.app_data::<Self>().cloned().unwrap_or_default()
+    }
+
+    /// Pick the best registered format for an `Accept` header
+    fn select(&self, accept: Option<&str>) -> Option<NegotiatedFormat> {
+        let accept = match accept {
+            Some(accept) if !accept.trim().is_empty() => accept,
+            _ => return self.formats.first().copied(),
+        };
+
+        let ranges = parse_accept(accept);
+        // The best format, with its quality and the position of its range.
+        // Formats of equal quality are picked in the client's order, then the server's.
+        let mut best: Option<(NegotiatedFormat, f32, usize)> = None;
+        for format in self.formats.iter() {
+            // Formats take the quality of the most specific range matching them,
+            // so `*/*;q=0` refuses every format not named otherwise
+            let matched = ranges
+                .iter()
+                .enumerate()
+                .filter_map(|(i, (range, quality))| {
+                    specificity(range, *format).map(|specificity| (specificity, i, *quality))
+                })
+                .max_by_key(|(specificity, i, _)| (*specificity, std::cmp::Reverse(*i)));
+
+            if let Some((_, i, quality)) = matched {
+                let better = match best {
+                    Some((_, best_quality, best_i)) => {
+                        quality > best_quality || (quality == best_quality && i < best_i)
+                    }
+                    None => true,
+                };
+                if quality > 0.0 && better {
+                    best = Some((*format, quality, i));
+                }
+            }
+        }
+
+        best.map(|(format, _, _)| format)
+    }
+}
+
+impl Default for NegotiationConfig {
+    fn default() -> Self {
+        let mut formats = vec![NegotiatedFormat::Json];
+        formats.extend(
+            NegotiatedFormat::client_preference()
+                .into_iter()
+                .filter(|format| *format != NegotiatedFormat::Json),
+        );
+        NegotiationConfig { formats }
+    }
+}
+
+/// How specifically the media range `range` matches `format`, if at all
+fn specificity(range: &str, format: NegotiatedFormat) -> Option<u8> {
+    let content_type = format.content_type();
+    if range == "*/*" {
+        Some(0)
+    } else if let Some(prefix) = range.strip_suffix("/*") {
+        content_type
+            .split('/')
+            .next()
+            .filter(|ty| *ty == prefix)
+            .map(|_| 1)
+    } else if range.eq_ignore_ascii_case(content_type) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// Split an `Accept` header into media ranges and their quality
+fn parse_accept(accept: &str) -> Vec<(String, f32)> {
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let mime = params.next()?.trim().to_ascii_lowercase();
+            if mime.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| {
+                    let mut param = param.splitn(2, '=');
+                    match param.next()?.trim() {
+                        "q" => param.next()?.trim().parse().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((mime, quality))
+        })
+        .collect()
+}
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        let format = match NegotiatedFormat::accepted(req) {
+            Ok(format) => format,
+            Err(err) => return ready(Err(err)),
+        };
+
+        ready(match format.encode(&self.0) {
+            Ok(body) => Ok(HttpResponse::Ok()
+                .content_type(format.content_type())
+                .header(VARY, "accept")
+                .body(body)),
+            Err(err) => Err(ErrorInternalServerError(err.to_string())),
+        })
+    }
+}
+
+type NegotiatedFromRequestFuture<T> =
+    impl std::future::Future<Output = Result<Negotiated<T>, actix_web::Error>>;
+
+/// Extractor decoding the payload in the format named by its `Content-Type`.
+/// Payloads without a content type are read in the server's preferred format.
+impl<T> FromRequest for Negotiated<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = NegotiatedFromRequestFuture<T>;
+    type Config = NegotiationConfig;
+
+    #[inline]
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let config = NegotiationConfig::from_req(req);
+        let format = match req.headers().get(CONTENT_TYPE) {
+            None => config
+                .formats
+                .first()
+                .copied()
+                .ok_or_else(|| ErrorUnsupportedMediaType("no formats are accepted")),
+            Some(content_type) => {
+                let content_type = content_type.to_str().unwrap_or_default();
+                NegotiatedFormat::from_mime(content_type)
+                    .filter(|format| config.formats.contains(format))
+                    .ok_or_else(|| {
+                        ErrorUnsupportedMediaType(format!(
+                            "unsupported content type {}",
+                            content_type
+                        ))
+                    })
+            }
+        };
+        let bytes = Bytes::from_request(req, payload);
+        async move {
+            let format = format?;
+            let bytes = bytes.await?;
+            format
+                .decode(&bytes)
+                .map(Negotiated)
+                .map_err(ErrorBadRequest)
+        }
+    }
+}
+
+type NegotiatedDecodeFuture<T> =
+    impl std::future::Future<Output = Result<Negotiated<T>, PhalanxClientError>>;
+
+impl<T> PhalanxPayload for Negotiated<T>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = NegotiatedDecodeFuture<T>;
+
+    /// Send the payload as JSON, as clients can't know which other formats
+    /// the server accepts before sending it
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        let format = NegotiatedFormat::Json;
+        let body = format.encode(&self.0)?;
+        Ok(req.header(CONTENT_TYPE, format.content_type()).body(body))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let res = res.0.error_for_status()?;
+            let content_type = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let format = NegotiatedFormat::from_mime(&content_type).ok_or_else(|| {
+                PhalanxClientError::DecodeError(format!(
+                    "unsupported content type {:?}",
+                    content_type
+                ))
+            })?;
+            let bytes = res.bytes().await?;
+            Ok(Negotiated(format.decode(&bytes)?))
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::{fmt, ops};
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorBadRequest, ErrorInternalServerError},
+    http::header::CONTENT_TYPE,
+    FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures::future::{ready, Ready};
+use prost::Message;
+use reqwest::RequestBuilder;
+
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
+
+const PROTOBUF: &str = "application/x-protobuf";
+
+/// A payload or response encoded as a Protocol Buffers message
+///
+/// `T` is usually generated from a `.proto` file by prost. Routes using
+/// `Protobuf` are described with their message types by the
+/// [ProtoExporter](crate::proto::ProtoExporter).
+pub struct Protobuf<T>(pub T);
+
+impl<T> Protobuf<T> {
+    /// Deconstruct to an inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ops::Deref for Protobuf<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> ops::DerefMut for Protobuf<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> fmt::Debug for Protobuf<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Protobuf: {:?}", self.0)
+    }
+}
+
+fn encode<T: Message>(message: &T) -> Result<Vec<u8>, prost::EncodeError> {
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut buf)?;
+    Ok(buf)
+}
+
+impl<T: Message> Responder for Protobuf<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, _: &HttpRequest) -> Self::Future {
+        ready(
+            encode(&self.0)
+                .map(|body| HttpResponse::Ok().content_type(PROTOBUF).body(body))
+                .map_err(ErrorInternalServerError),
+        )
+    }
+}
+
+type ProtobufFromRequestFuture<T> =
+    impl std::future::Future<Output = Result<Protobuf<T>, actix_web::Error>>;
+
+/// Protobuf extractor. Payload size limits are configured with
+/// [PayloadConfig](actix_web::web::PayloadConfig).
+impl<T> FromRequest for Protobuf<T>
+where
+    T: Message + Default + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = ProtobufFromRequestFuture<T>;
+    type Config = actix_web::web::PayloadConfig;
+
+    #[inline]
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let bytes = crate::server::read_payload(req, payload, PROTOBUF);
+        async move {
+            let bytes = bytes.await?;
+            T::decode(bytes).map(Protobuf).map_err(ErrorBadRequest)
+        }
+    }
+}
+
+type ProtobufDecodeFuture<T> =
+    impl std::future::Future<Output = Result<Protobuf<T>, PhalanxClientError>>;
+
+impl<T: Message + Default + 'static> PhalanxPayload for Protobuf<T> {
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = ProtobufDecodeFuture<T>;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        Ok(req.header(CONTENT_TYPE, PROTOBUF).body(encode(&self.0)?))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let res = res.0.error_for_status()?;
+            let bytes = res.bytes().await?;
+            Ok(Protobuf(T::decode(bytes)?))
+        }
+    }
+}
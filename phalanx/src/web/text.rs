@@ -0,0 +1,155 @@
+use std::{error::Error as StdError, fmt, ops, str::FromStr};
+
+use actix_web::{
+    dev::Payload, error::ErrorBadRequest, web::PayloadConfig, FromRequest, HttpRequest,
+    HttpResponse, Responder,
+};
+use futures::future::{ok, Ready};
+use reqwest::{header::CONTENT_TYPE, RequestBuilder};
+
+use crate::client::{PhalanxClientError, PhalanxPayload, PhalanxResponse};
+
+/// A scalar value sent as `text/plain`
+///
+/// Works for any type implementing [FromStr] and [Display](fmt::Display), such as
+/// numbers, booleans or UUIDs. Route arguments and return values which are
+/// primitive scalars are sent as `Text` automatically.
+pub struct Text<T>(pub T);
+
+impl<T> Text<T> {
+    /// Deconstruct to an inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ops::Deref for Text<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> ops::DerefMut for Text<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> fmt::Debug for Text<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Text: {:?}", self.0)
+    }
+}
+
+impl<T> fmt::Display for Text<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl<T: fmt::Display> Responder for Text<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<HttpResponse, actix_web::Error>>;
+
+    fn respond_to(self, _: &HttpRequest) -> Self::Future {
+        ok(HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(self.0.to_string()))
+    }
+}
+
+type TextFromRequestFuture<T> =
+    impl std::future::Future<Output = Result<Text<T>, actix_web::Error>>;
+
+/// Text extractor. Parses the request's payload with [FromStr].
+impl<T> FromRequest for Text<T>
+where
+    T: FromStr + 'static,
+    T::Err: StdError + Send + Sync + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = TextFromRequestFuture<T>;
+    type Config = PayloadConfig;
+
+    #[inline]
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let text = String::from_request(req, payload);
+        async move {
+            let text = text.await?;
+            let value = parse(&text).map_err(ErrorBadRequest)?;
+            Ok(Text(value))
+        }
+    }
+}
+
+type TextDecodeFuture<T> = impl std::future::Future<Output = Result<Text<T>, PhalanxClientError>>;
+
+impl<T> PhalanxPayload for Text<T>
+where
+    T: fmt::Display + FromStr + 'static,
+    T::Err: StdError + Send + Sync + 'static,
+{
+    type ExtractFuture = <Self as FromRequest>::Future;
+    type DecodeFuture = TextDecodeFuture<T>;
+
+    fn into_request(
+        self,
+        req: RequestBuilder,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        Ok(req
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(self.0.to_string()))
+    }
+
+    fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+        Self::from_request(req, payload)
+    }
+
+    fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+        async {
+            let text = String::decode(res).await?;
+            let value = parse(&text)?;
+            Ok(Text(value))
+        }
+    }
+}
+
+/// A text payload which couldn't be parsed, with the [FromStr] error as its source
+#[derive(Debug)]
+pub struct TextParseError {
+    text: String,
+    type_name: &'static str,
+    source: Box<dyn StdError + Send + Sync>,
+}
+
+impl fmt::Display for TextParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not parse {:?} as {}", self.text, self.type_name)
+    }
+}
+
+impl StdError for TextParseError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+fn parse<T>(text: &str) -> Result<T, TextParseError>
+where
+    T: FromStr,
+    T::Err: StdError + Send + Sync + 'static,
+{
+    text.trim().parse().map_err(|err: T::Err| TextParseError {
+        text: text.to_string(),
+        type_name: std::any::type_name::<T>(),
+        source: Box::new(err),
+    })
+}
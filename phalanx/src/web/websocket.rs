@@ -0,0 +1,525 @@
+//! Typed WebSocket routes
+//!
+//! Routes marked `#[ws("/path")]` take a [WsStream] of incoming messages
+//! and return an `impl Stream` of outgoing messages. Each message is sent as
+//! a JSON text frame. Pings are answered with pongs, and the socket is closed
+//! once either side's stream ends. Servers ping their clients every five
+//! seconds, disconnecting those which haven't sent anything for ten.
+//! Fragmented messages over 1 MiB close the socket with [CloseCode::Size],
+//! and the socket isn't read while a handler has 16 messages left to take.
+//!
+//! Generated clients open the socket with [connect], returning a [WsSink]
+//! for outgoing messages and a [WsStream] of incoming ones. Clients ping
+//! their servers every five seconds whether or not the stream is polled,
+//! but only answer the server's pings while it is.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use actix::{Actor, ActorContext, ActorFuture, AsyncContext, StreamHandler, WrapFuture};
+use actix_http::ws::{CloseCode, CloseReason, Frame, Item, Message, ProtocolError};
+use actix_web::{
+    rt::time,
+    web::{Bytes, BytesMut, Payload},
+    HttpRequest, HttpResponse,
+};
+use actix_web_actors::ws;
+use futures::{
+    channel::mpsc,
+    future::{self, FutureExt},
+    stream::{self, LocalBoxStream},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::client::{Client, PhalanxClientError};
+
+/// A stream of messages received over a WebSocket
+///
+/// Servers receive a `WsStream<T>`, which ends when the client closes the
+/// socket. Messages which can't be decoded close the socket with
+/// [CloseCode::Invalid]. Clients receive a
+/// `WsStream<Result<T, PhalanxClientError>>`.
+pub struct WsStream<T>(LocalBoxStream<'static, T>);
+
+impl<T> WsStream<T> {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = T> + 'static,
+    {
+        WsStream(Box::pin(stream))
+    }
+}
+
+impl<T> Stream for WsStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+impl<T> fmt::Debug for WsStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WsStream")
+    }
+}
+
+/// Sends messages over a WebSocket opened by a client
+///
+/// Closing the sink closes the socket.
+pub struct WsSink<T> {
+    tx: mpsc::Sender<Message>,
+    _message: PhantomData<fn(T)>,
+}
+
+impl<T> fmt::Debug for WsSink<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WsSink")
+    }
+}
+
+impl<T: Serialize> Sink<T> for WsSink<T> {
+    type Error = PhalanxClientError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.tx
+            .poll_ready(cx)
+            .map_err(|_| PhalanxClientError::WsClosed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let text = serde_json::to_string(&item)?;
+        self.tx
+            .start_send(Message::Text(text))
+            .map_err(|_| PhalanxClientError::WsClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.tx.is_closed() {
+            return Poll::Ready(Ok(()));
+        }
+        match self.tx.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {
+                let close = Message::Close(Some(CloseCode::Normal.into()));
+                let _ = self.tx.start_send(close);
+                self.tx.close_channel();
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// How often servers ping their clients, and clients their servers
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a client may go without sending anything, including pongs, before it's disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest message which may be reassembled from fragments
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// How many decoded messages may wait for the handler before the socket stops being read
+const INCOMING_BUFFER: usize = 16;
+
+/// Accept a WebSocket connection, passing its incoming messages to `handler`
+/// and sending the messages of the stream it returns
+pub fn serve<In, Out, F, Fut, S>(
+    req: &HttpRequest,
+    payload: Payload,
+    handler: F,
+) -> Result<HttpResponse, actix_web::Error>
+where
+    In: DeserializeOwned + 'static,
+    Out: Serialize + 'static,
+    F: FnOnce(WsStream<In>) -> Fut,
+    Fut: Future<Output = S> + 'static,
+    S: Stream<Item = Out> + 'static,
+{
+    let (incoming, messages) = mpsc::channel(INCOMING_BUFFER);
+    let outgoing = handler(WsStream::new(messages))
+        .into_stream()
+        .flatten()
+        .boxed_local();
+
+    let gate = Gate::default();
+    let session = Session {
+        incoming: Some(incoming),
+        backlog: VecDeque::new(),
+        gate: gate.clone(),
+        outgoing: Some(outgoing),
+        fragments: None,
+        heartbeat: Instant::now(),
+    };
+    ws::start(session, req, Gated { payload, gate })
+}
+
+/// Holds back a socket's incoming bytes while its handler is behind
+#[derive(Clone, Default)]
+struct Gate(Rc<RefCell<GateState>>);
+
+#[derive(Default)]
+struct GateState {
+    closed: bool,
+    /// The payload's task, woken once the gate opens
+    waker: Option<Waker>,
+}
+
+impl Gate {
+    fn close(&self) {
+        self.0.borrow_mut().closed = true;
+    }
+
+    fn open(&self) {
+        let mut state = self.0.borrow_mut();
+        state.closed = false;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A socket's payload, which is only read while its [Gate] is open
+struct Gated {
+    payload: Payload,
+    gate: Gate,
+}
+
+impl Stream for Gated {
+    type Item = <Payload as Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        {
+            let mut state = self.gate.0.borrow_mut();
+            if state.closed {
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        self.payload.poll_next_unpin(cx)
+    }
+}
+
+/// The actor serving a socket, between its client and the route's handler
+struct Session<In, Out> {
+    /// Decoded messages, passed to the handler's [WsStream]
+    ///
+    /// Taken while a message waits for room in the handler's buffer.
+    incoming: Option<mpsc::Sender<In>>,
+    /// Messages read while another waits, in the order they arrived
+    backlog: VecDeque<Message>,
+    /// Closed while a message waits, so no more of the socket is read
+    gate: Gate,
+    /// The handler's messages, taken once the socket is started
+    outgoing: Option<LocalBoxStream<'static, Out>>,
+    /// The frames of a fragmented message
+    fragments: Option<BytesMut>,
+    /// When the client last sent anything
+    heartbeat: Instant,
+}
+
+/// A message from the route's handler, to be sent to the client
+struct Outgoing<T>(T);
+
+impl<In, Out> Actor for Session<In, Out>
+where
+    In: DeserializeOwned + 'static,
+    Out: Serialize + 'static,
+{
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |session, ctx| {
+            // Clients aren't heard from while the socket isn't read
+            let waiting = session.incoming.is_none();
+            if session.heartbeat.elapsed() > CLIENT_TIMEOUT && !waiting {
+                ctx.close(Some(CloseCode::Away.into()));
+                ctx.stop();
+            } else {
+                ctx.ping(b"");
+            }
+        });
+
+        if let Some(outgoing) = self.outgoing.take() {
+            ctx.add_stream(outgoing.map(Outgoing));
+        }
+    }
+}
+
+impl<In, Out> StreamHandler<Result<Message, ProtocolError>> for Session<In, Out>
+where
+    In: DeserializeOwned + 'static,
+    Out: Serialize + 'static,
+{
+    fn handle(&mut self, message: Result<Message, ProtocolError>, ctx: &mut Self::Context) {
+        self.heartbeat = Instant::now();
+        let message = match message {
+            Ok(message) if self.incoming.is_none() => return self.backlog.push_back(message),
+            message => message,
+        };
+        let data = match message {
+            Ok(Message::Text(text)) => Bytes::from(text),
+            Ok(Message::Binary(data)) => data,
+            Ok(Message::Continuation(item)) => match item {
+                Item::FirstText(data) | Item::FirstBinary(data) => {
+                    self.fragments = Some(BytesMut::from(&data[..]));
+                    return;
+                }
+                Item::Continue(data) => {
+                    if let Some(fragments) = &mut self.fragments {
+                        if fragments.len() + data.len() > MAX_MESSAGE_SIZE {
+                            ctx.close(Some(CloseCode::Size.into()));
+                            ctx.stop();
+                            return;
+                        }
+                        fragments.extend_from_slice(&data);
+                    }
+                    return;
+                }
+                Item::Last(data) => match self.fragments.take() {
+                    Some(fragments) if fragments.len() + data.len() > MAX_MESSAGE_SIZE => {
+                        ctx.close(Some(CloseCode::Size.into()));
+                        ctx.stop();
+                        return;
+                    }
+                    Some(mut fragments) => {
+                        fragments.extend_from_slice(&data);
+                        fragments.freeze()
+                    }
+                    None => return,
+                },
+            },
+            Ok(Message::Ping(data)) => {
+                ctx.pong(&data);
+                return;
+            }
+            Ok(Message::Pong(_)) | Ok(Message::Nop) => return,
+            Ok(Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+                return;
+            }
+            Err(_) => {
+                ctx.stop();
+                return;
+            }
+        };
+
+        self.forward(data, ctx);
+    }
+}
+
+impl<In, Out> Session<In, Out>
+where
+    In: DeserializeOwned + 'static,
+    Out: Serialize + 'static,
+{
+    /// Pass a message to the handler, or wait for room in its buffer without reading the socket
+    fn forward(&mut self, data: Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+        let message = match serde_json::from_slice(&data) {
+            Ok(message) => message,
+            Err(_) => {
+                ctx.close(Some(CloseCode::Invalid.into()));
+                ctx.stop();
+                return;
+            }
+        };
+        let mut incoming = match self.incoming.take() {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        let message = match incoming.try_send(message) {
+            Err(err) if err.is_full() => err.into_inner(),
+            _ => {
+                self.incoming = Some(incoming);
+                return;
+            }
+        };
+
+        self.gate.close();
+        let send = async move {
+            let sent = incoming.send(message).await;
+            (incoming, sent)
+        };
+        ctx.spawn(send.into_actor(self).map(|(incoming, _), session, ctx| {
+            session.heartbeat = Instant::now();
+            session.incoming = Some(incoming);
+
+            // Messages held back are handled until one has to wait again
+            while session.incoming.is_some() && ctx.state().alive() {
+                match session.backlog.pop_front() {
+                    Some(message) => StreamHandler::handle(session, Ok(message), ctx),
+                    None => return session.gate.open(),
+                }
+            }
+        }));
+    }
+}
+
+impl<In, Out> StreamHandler<Outgoing<Out>> for Session<In, Out>
+where
+    In: DeserializeOwned + 'static,
+    Out: Serialize + 'static,
+{
+    fn handle(&mut self, Outgoing(message): Outgoing<Out>, ctx: &mut Self::Context) {
+        match serde_json::to_string(&message) {
+            Ok(text) => ctx.text(text),
+            Err(err) => {
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Error,
+                    description: Some(err.to_string()),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+
+    /// The socket is closed once the handler's stream ends
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.close(Some(CloseCode::Normal.into()));
+        ctx.stop();
+    }
+}
+
+/// Open a WebSocket to `relative_url`, at `client`'s server
+///
+/// The socket is opened by the actix client [Client::ws_client] builds, at
+/// the replica picked for it if the client is balanced.
+pub async fn connect<In, Out>(
+    client: &Client,
+    relative_url: &str,
+) -> Result<(WsSink<In>, WsStream<Result<Out, PhalanxClientError>>), PhalanxClientError>
+where
+    In: Serialize,
+    Out: DeserializeOwned + 'static,
+{
+    let url = client.format_url(relative_url);
+    let (_, framed) = client.ws_client().ws(url).connect().await?;
+    let (sink, frames) = framed.split();
+
+    // Messages are written by a task, so the sink and stream can be used independently.
+    // Each is flushed before the next, as the socket isn't ready for more while its buffer is full.
+    // The task pings the server too, so it doesn't time the client out while the stream isn't polled.
+    let (tx, rx) = mpsc::channel(16);
+    actix_web::rt::spawn(async move {
+        let mut sink = sink;
+        let start = time::Instant::now() + HEARTBEAT_INTERVAL;
+        let pings =
+            time::interval_at(start, HEARTBEAT_INTERVAL).map(|_| Some(Message::Ping(Bytes::new())));
+        // Pings never end, so the task ends with the messages, once every sender is gone
+        let messages = rx.map(Some).chain(stream::once(future::ready(None)));
+        let mut outgoing = stream::select(messages, pings);
+        while let Some(Some(message)) = outgoing.next().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let sink = WsSink {
+        tx: tx.clone(),
+        _message: PhantomData,
+    };
+    Ok((sink, WsStream::new(messages(frames, tx))))
+}
+
+/// The state of a stream of incoming messages
+struct Messages<S> {
+    frames: S,
+    control: mpsc::Sender<Message>,
+    /// The frames of a fragmented message
+    fragments: Option<BytesMut>,
+    done: bool,
+}
+
+impl<S> Messages<S> {
+    /// Close the socket over a message too large to reassemble
+    async fn overflow<T>(mut self) -> Option<(Result<T, PhalanxClientError>, Self)> {
+        let _ = self
+            .control
+            .send(Message::Close(Some(CloseCode::Size.into())))
+            .await;
+        self.done = true;
+        Some((Err(ProtocolError::Overflow.into()), self))
+    }
+}
+
+/// Decode the messages sent over a socket, replying to pings and closes through `control`
+fn messages<T, S>(
+    frames: S,
+    control: mpsc::Sender<Message>,
+) -> impl Stream<Item = Result<T, PhalanxClientError>>
+where
+    T: DeserializeOwned,
+    S: Stream<Item = Result<Frame, ProtocolError>> + Unpin,
+{
+    let state = Messages {
+        frames,
+        control,
+        fragments: None,
+        done: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+        loop {
+            let data = match state.frames.next().await? {
+                Ok(Frame::Text(data)) | Ok(Frame::Binary(data)) => data,
+                Ok(Frame::Continuation(item)) => match item {
+                    Item::FirstText(data) | Item::FirstBinary(data) => {
+                        state.fragments = Some(BytesMut::from(&data[..]));
+                        continue;
+                    }
+                    Item::Continue(data) => {
+                        if let Some(fragments) = &mut state.fragments {
+                            if fragments.len() + data.len() > MAX_MESSAGE_SIZE {
+                                return state.overflow().await;
+                            }
+                            fragments.extend_from_slice(&data);
+                        }
+                        continue;
+                    }
+                    Item::Last(data) => match state.fragments.take() {
+                        Some(fragments) if fragments.len() + data.len() > MAX_MESSAGE_SIZE => {
+                            return state.overflow().await;
+                        }
+                        Some(mut fragments) => {
+                            fragments.extend_from_slice(&data);
+                            fragments.freeze()
+                        }
+                        None => continue,
+                    },
+                },
+                Ok(Frame::Ping(data)) => {
+                    let _ = state.control.send(Message::Pong(data)).await;
+                    continue;
+                }
+                Ok(Frame::Pong(_)) => continue,
+                Ok(Frame::Close(reason)) => {
+                    let _ = state.control.send(Message::Close(reason)).await;
+                    return None;
+                }
+                Err(err) => {
+                    state.done = true;
+                    return Some((Err(err.into()), state));
+                }
+            };
+            let message = serde_json::from_slice(&data).map_err(PhalanxClientError::from);
+            return Some((message, state));
+        }
+    })
+}
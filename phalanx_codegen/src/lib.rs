@@ -4,6 +4,7 @@ use proc_macro_error::proc_macro_error;
 use syn::{parse_macro_input, DeriveInput};
 
 mod derive;
+mod multipart;
 mod route;
 mod service;
 
@@ -31,6 +32,44 @@ method_macro! {
     Options,   options,
     Trace,     trace,
     Patch,     patch,
+    Ws,        ws,
+    Subscribe, subscribe,
+}
+
+#[proc_macro_attribute]
+pub fn transactional(_: TokenStream, input: TokenStream) -> TokenStream {
+    // Parsed and used by the phalanx proc_macro
+    input
+}
+
+#[proc_macro_attribute]
+pub fn retry(_: TokenStream, input: TokenStream) -> TokenStream {
+    // Parsed and used by the phalanx proc_macro
+    input
+}
+
+#[proc_macro_attribute]
+pub fn timeout(_: TokenStream, input: TokenStream) -> TokenStream {
+    // Parsed and used by the phalanx proc_macro
+    input
+}
+
+#[proc_macro_attribute]
+pub fn concurrency(_: TokenStream, input: TokenStream) -> TokenStream {
+    // Parsed and used by the phalanx proc_macro
+    input
+}
+
+#[proc_macro_attribute]
+pub fn rate_limit(_: TokenStream, input: TokenStream) -> TokenStream {
+    // Parsed and used by the phalanx proc_macro
+    input
+}
+
+#[proc_macro_attribute]
+pub fn batch_of(_: TokenStream, input: TokenStream) -> TokenStream {
+    // Parsed and used by the phalanx proc_macro
+    input
 }
 
 #[proc_macro_attribute]
@@ -49,6 +88,16 @@ pub fn derive_serialize(input: TokenStream) -> TokenStream {
     }
 }
 
+#[proc_macro_derive(MultipartForm, attributes(multipart))]
+pub fn derive_multipart_form(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match multipart::derive_multipart_form_inner(input) {
+        Err(e) => e.to_compile_error().into(),
+        Ok(s) => s,
+    }
+}
+
 #[proc_macro_attribute]
 pub fn main(_: TokenStream, item: TokenStream) -> TokenStream {
     use quote::quote;
@@ -0,0 +1,147 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{Data, DeriveInput, Error, Lit, Meta, NestedMeta};
+
+pub fn derive_multipart_form_inner(input: DeriveInput) -> Result<TokenStream, Error> {
+    let form_type = input.ident;
+    let fields = match input.data {
+        Data::Struct(s) => match s.fields {
+            syn::Fields::Named(named) => named.named,
+            _ => {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "MultipartForm can only be derived on structs with named fields",
+                ))
+            }
+        },
+        Data::Enum(_) | Data::Union(_) => {
+            return Err(Error::new(
+                Span::call_site(),
+                "MultipartForm can only be derived on structs",
+            ))
+        }
+    };
+
+    let mut names = Vec::with_capacity(fields.len());
+    let mut idents = Vec::with_capacity(fields.len());
+    let mut limits = Vec::with_capacity(fields.len());
+    let mut max_counts = Vec::with_capacity(fields.len());
+    let mut types = Vec::with_capacity(fields.len());
+
+    for field in fields.iter() {
+        let ident = field.ident.clone().unwrap();
+        let ty = &field.ty;
+
+        let mut limit = quote! { <#ty as phalanx::web::multipart::MultipartField>::DEFAULT_LIMIT };
+        let mut max_count =
+            quote! { <#ty as phalanx::web::multipart::MultipartField>::DEFAULT_MAX_COUNT };
+        for attr in field.attrs.iter() {
+            if attr.path.is_ident("multipart") {
+                let (field_limit, field_max_count) = parse_attr(attr)?;
+                limit = field_limit.unwrap_or(limit);
+                max_count = field_max_count.unwrap_or(max_count);
+            }
+        }
+
+        names.push(ident.to_string());
+        idents.push(ident);
+        limits.push(limit);
+        max_counts.push(max_count);
+        types.push(ty);
+    }
+
+    let output = quote! {
+        impl phalanx::web::multipart::MultipartForm for #form_type {
+            fn limit(name: &str) -> Option<usize> {
+                match name {
+                    #(#names => Some(#limits),)*
+                    _ => None,
+                }
+            }
+
+            fn streamed(name: &str) -> bool {
+                match name {
+                    #(#names => <#types as phalanx::web::multipart::MultipartField>::STREAMED,)*
+                    _ => false,
+                }
+            }
+
+            fn required(name: &str) -> bool {
+                match name {
+                    #(#names => <#types as phalanx::web::multipart::MultipartField>::REQUIRED,)*
+                    _ => false,
+                }
+            }
+
+            fn max_count(name: &str) -> usize {
+                match name {
+                    #(#names => #max_counts,)*
+                    _ => 0,
+                }
+            }
+
+            fn fields() -> &'static [&'static str] {
+                &[#(#names),*]
+            }
+
+            fn from_parts(
+                parts: &mut phalanx::web::multipart::Parts,
+            ) -> Result<Self, phalanx::web::multipart::MultipartFormError> {
+                Ok(Self {
+                    #(#idents: phalanx::web::multipart::MultipartField::from_parts(#names, parts.take(#names))?,)*
+                })
+            }
+
+            fn into_form(self) -> Result<phalanx::web::multipart::Form, Box<dyn std::error::Error>> {
+                let form = phalanx::web::multipart::Form::new();
+                #(let form = phalanx::web::multipart::MultipartField::add_to_form(self.#idents, #names, form)?;)*
+                Ok(form)
+            }
+        }
+    };
+    Ok(output.into())
+}
+
+/// Parse `#[multipart(limit = 1024, max_count = 8)]` into the part size limit
+/// and the number of times the part may be sent, either of which may be left out
+fn parse_attr(
+    attr: &syn::Attribute,
+) -> Result<
+    (
+        Option<proc_macro2::TokenStream>,
+        Option<proc_macro2::TokenStream>,
+    ),
+    Error,
+> {
+    let mut limit = None;
+    let mut max_count = None;
+    if let Meta::List(list) = attr.parse_meta()? {
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(name_value)) => match &name_value.lit {
+                    Lit::Int(value) if name_value.path.is_ident("limit") => {
+                        limit = Some(quote! { #value });
+                    }
+                    Lit::Int(value) if name_value.path.is_ident("max_count") => {
+                        max_count = Some(quote! { #value });
+                    }
+                    _ => return Err(invalid_attr(attr)),
+                },
+                _ => return Err(invalid_attr(attr)),
+            }
+        }
+    }
+
+    if limit.is_none() && max_count.is_none() {
+        return Err(invalid_attr(attr));
+    }
+    Ok((limit, max_count))
+}
+
+fn invalid_attr(attr: &syn::Attribute) -> Error {
+    Error::new_spanned(
+        attr,
+        "Expected a part size limit or count, i.e. `#[multipart(limit = 1024, max_count = 8)]`",
+    )
+}
@@ -12,8 +12,14 @@ impl From<Route> for ClientRoute {
 
 impl ToTokens for ClientRoute {
     fn to_tokens(&self, tokens: &mut TokenStream2) {
+        if self.0.route_attr.is_ws() {
+            return self.ws_to_tokens(tokens);
+        }
+        if let Some(list_route) = &self.0.batched_by {
+            return self.batched_to_tokens(list_route, tokens);
+        }
+
         let args = &self.0.args;
-        let fn_name = &self.0.ident;
         let raw_ret_type = &self.0.ret_type;
         let attrs = &self.0.attrs;
         let route = &self.0.route_attr.route;
@@ -33,47 +39,225 @@ impl ToTokens for ClientRoute {
             quote! { #route }
         };
 
-        // Ensure the type is a result type
-        let ret_type = match raw_ret_type {
-            syn::ReturnType::Default => {
-                quote! { () }
+        // Subscriptions are received as server-sent events, through a `subscribe_` method
+        let subscription = self.0.subscription_type();
+        let fn_name = match subscription {
+            Some(_) => quote::format_ident!("subscribe_{}", self.0.ident),
+            None => self.0.ident.clone(),
+        };
+
+        // Errors returned by the server are reported through the response status
+        let ret_type: syn::Type = match raw_ret_type {
+            _ if subscription.is_some() => {
+                syn::parse_quote! { phalanx::web::EventSource<#subscription> }
             }
+            syn::ReturnType::Default => syn::parse_quote! { () },
             syn::ReturnType::Type(_, ty) => {
-                quote! { #ty }
+                let ty = super::result_ok_type(ty).unwrap_or(ty);
+                // Streams are received as a stream of results, decoded as each item arrives
+                match (super::stream_item_type(ty), super::event_type(ty)) {
+                    (Some(item), _) => syn::parse_quote! {
+                        phalanx::web::JsonStream<Result<#item, phalanx::client::PhalanxClientError>>
+                    },
+                    (_, Some(event)) => syn::parse_quote! { phalanx::web::EventSource<#event> },
+                    _ => ty.clone(),
+                }
             }
         };
 
-        let (content_type, payload) = if let Some(payload) = &self.0.payload_arg {
-            match payload.pat.as_ref() {
-                syn::Pat::Ident(ident) => (
-                    quote! {
-                        let __content_type = phalanx::client::ContentType::from(&#ident);
-                    },
-                    quote! {
-                        .header("content-type", __content_type.header_value())
-                        .body({
-                            let body: phalanx::reexports::Body = std::convert::TryFrom::try_from(#ident)?;
-                            body
-                        })
-                    },
-                ),
+        // Values which aren't sent as written are received as their wrapper
+        let decode = match self.0.wrapper(&ret_type) {
+            Some(wrapper) => {
+                let wrapped = wrapper.ty(&ret_type);
+                wrapper.unwrap(quote! {
+                    <#wrapped as phalanx::util::AsyncTryFrom<phalanx::client::PhalanxResponse>>::try_from(__res).await?
+                })
+            }
+            None => quote! {
+                <#ret_type as phalanx::util::AsyncTryFrom<phalanx::client::PhalanxResponse>>::try_from(__res).await?
+            },
+        };
+
+        // Negotiated responses advertise the formats the client can decode
+        let accept = if self.0.is_negotiated(&ret_type) {
+            quote! {
+                let __req = __req.header("accept", phalanx::web::NegotiatedFormat::accept_header());
+            }
+        } else {
+            quote! {}
+        };
+
+        let payload = if let Some(payload) = &self.0.payload_arg {
+            let ident = match payload.pat.as_ref() {
+                syn::Pat::Ident(ident) => &ident.ident,
                 pat => panic!("Unknown pattern: {:?}", pat),
+            };
+            let payload = match self.0.wrapper(&payload.ty) {
+                Some(wrapper) => {
+                    let wrap = wrapper.wrap();
+                    quote! { #wrap(#ident) }
+                }
+                None => quote! { #ident },
+            };
+            quote! {
+                let __req = phalanx::client::PhalanxPayload::into_request(#payload, __req)?;
+            }
+        } else {
+            quote! {}
+        };
+
+        // Event streams hold on to the request, to send it again when reconnecting
+        let is_event_stream = match raw_ret_type {
+            _ if subscription.is_some() => true,
+            syn::ReturnType::Type(_, ty) => {
+                super::event_type(super::result_ok_type(ty).unwrap_or(ty)).is_some()
+            }
+            syn::ReturnType::Default => false,
+        };
+        // Calls are run in a block which may time out, whose error type is given here
+        // Named `Server::method`, so services sharing a client are limited
+        // and have their circuits broken apart
+        let name = self.0.limit_name();
+        // Routes overriding the client's retry policy send with their own copy
+        let policy = match &self.0.retry {
+            Some(retry) => {
+                let policy = retry.policy();
+                quote! { &#policy }
+            }
+            None => quote! { __client.retry_policy() },
+        };
+        let send = if is_event_stream {
+            quote! {
+                let __source = phalanx::web::EventSource::connect(__client, #name, __req, #policy);
+                Ok::<_, Box<dyn std::error::Error>>(__source.await?)
             }
         } else {
-            (quote! {}, quote! {})
+            quote! {
+                let __res = phalanx::client::PhalanxResponse::from(__client.send(#name, __req, #policy).await?);
+                Ok::<_, Box<dyn std::error::Error>>(#decode)
+            }
         };
+        let timeout = self.0.client_timeout();
 
         let stream = quote! {
             #(#attrs)*
             pub async fn #fn_name ( &self, #(#args),* ) -> Result< #ret_type , Box<dyn std::error::Error> > {
                 let __client  = phalanx::client::PhalanxClient::client(self);
-                #content_type
-                let __req = __client.client. #method (&__client.format_url( #format_url )) #payload;
-                let __res = phalanx::client::PhalanxResponse::from(__req.send().await?);
-                Ok(<#ret_type as phalanx::util::AsyncTryFrom<phalanx::client::PhalanxResponse>>::try_from(__res).await?)
+                phalanx::client::timeout(#timeout, async move {
+                    let __req = __client.client. #method (&__client.format_url( #format_url ));
+                    #accept
+                    #payload
+                    #send
+                }).await
             }
         };
 
         tokens.extend(stream);
     }
 }
+
+impl ClientRoute {
+    /// WebSocket clients return a sink of outgoing messages and a stream of incoming ones
+    fn ws_to_tokens(&self, tokens: &mut TokenStream2) {
+        let fn_name = &self.0.ident;
+        let attrs = &self.0.attrs;
+        let route = &self.0.route_attr.route;
+        let args = &self.0.path_args;
+        let (incoming, outgoing) = self.0.ws_types();
+
+        let path_args = super::split_args(&self.0.path_args);
+        let format_args = path_args
+            .iter()
+            .map(|(ident, _)| quote! { #ident = #ident });
+        let format_url = if !path_args.is_empty() {
+            quote! { &format!( #route , #(#format_args),* ) }
+        } else {
+            quote! { #route }
+        };
+
+        let stream = quote! {
+            #(#attrs)*
+            pub async fn #fn_name ( &self, #(#args),* ) -> Result<
+                (
+                    phalanx::web::WsSink<#incoming>,
+                    phalanx::web::WsStream<Result<#outgoing, phalanx::client::PhalanxClientError>>,
+                ),
+                Box<dyn std::error::Error>,
+            > {
+                let __client = phalanx::client::PhalanxClient::client(self);
+                Ok(phalanx::web::websocket::connect(__client, #format_url ).await?)
+            }
+        };
+
+        tokens.extend(stream);
+    }
+
+    /// Routes with a `#[batch_of]` list route load their values through the client's loader
+    fn batched_to_tokens(&self, list_route: &Route, tokens: &mut TokenStream2) {
+        let fn_name = &self.0.ident;
+        let name = fn_name.to_string();
+        let attrs = &self.0.attrs;
+        let args = &self.0.args;
+        let list_fn_name = &list_route.ident;
+
+        let (key, key_type) = super::split_args(&self.0.args)[0];
+        let key_value = super::json_value(key_type).expect("batched routes are validated");
+        let unwrap_key = key_value.unwrap(quote! { #key });
+        let key_type = key_value.ty();
+
+        let keys = list_route
+            .payload_arg
+            .as_ref()
+            .and_then(|arg| super::json_value(&arg.ty))
+            .expect("list routes are validated");
+        let wrap_keys = keys.wrap(quote! { __keys });
+
+        let values = match &list_route.ret_type {
+            syn::ReturnType::Type(_, ty) => {
+                super::json_value(super::result_ok_type(ty).unwrap_or(ty))
+            }
+            syn::ReturnType::Default => None,
+        }
+        .expect("list routes are validated");
+        let unwrap_values = values.unwrap(quote! { __res });
+
+        // Values the list route didn't find are reported as missing
+        let missing = match list_route.list_item_type() {
+            Some(item) if super::generic_type(item, "Option").is_some() => quote! {
+                let __res = __res.ok_or_else(|| phalanx::client::PhalanxClientError::BatchCallError {
+                    status: 404,
+                    message: format!("no value for `{}`", #name),
+                })?;
+            },
+            _ => quote! {},
+        };
+
+        let (ret_type, wrap) = match &self.0.ret_type {
+            syn::ReturnType::Type(_, ty) => {
+                let ty = super::result_ok_type(ty).unwrap_or(ty);
+                let value = super::json_value(ty).expect("batched routes are validated");
+                (quote! { #ty }, value.wrap(quote! { __res }))
+            }
+            syn::ReturnType::Default => unreachable!("batched routes are validated"),
+        };
+
+        let timeout = self.0.client_timeout();
+        let loader_name = self.0.limit_name();
+
+        tokens.extend(quote! {
+            #(#attrs)*
+            pub async fn #fn_name ( &self, #(#args),* ) -> Result< #ret_type , Box<dyn std::error::Error> > {
+                let __client = phalanx::client::PhalanxClient::client(self);
+                phalanx::client::timeout(#timeout, async move {
+                    let __loader = __client.loader::<#key_type, _>(#loader_name);
+                    let __res = __loader.load(#unwrap_key, |__keys| async move {
+                        let __res = self. #list_fn_name ( #wrap_keys ).await?;
+                        Ok(#unwrap_values)
+                    }).await?;
+                    #missing
+                    Ok::<_, Box<dyn std::error::Error>>(#wrap)
+                }).await
+            }
+        });
+    }
+}
@@ -0,0 +1,73 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    Ident, LitInt, Token,
+};
+
+/// The arguments to `#[concurrency(max = 4, queue = 16, wait_ms = 500, retry_after = 1)]`,
+/// as calls building a `phalanx::server::concurrency::Concurrency`
+#[derive(Clone)]
+pub struct ConcurrencyAttr {
+    max: LitInt,
+    setters: Vec<TokenStream2>,
+}
+
+impl ConcurrencyAttr {
+    /// The route's limit
+    pub fn limit(&self) -> TokenStream2 {
+        let max = &self.max;
+        let setters = &self.setters;
+        quote! {
+            phalanx::server::concurrency::Concurrency::new(#max) #(#setters)*
+        }
+    }
+}
+
+impl Parse for ConcurrencyAttr {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let span = input.span();
+        let mut max = None;
+        let mut setters = Vec::new();
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value: LitInt = input.parse()?;
+            value.base10_parse::<u64>()?;
+            match key.to_string().as_str() {
+                "max" => {
+                    if value.base10_parse::<u64>()? == 0 {
+                        return Err(syn::Error::new_spanned(
+                            value,
+                            "At least one request must be handled at once",
+                        ));
+                    }
+                    max = Some(value);
+                }
+                "queue" => setters.push(quote! { .queue(#value) }),
+                "wait_ms" => {
+                    setters.push(quote! { .wait(std::time::Duration::from_millis(#value)) })
+                }
+                "retry_after" => {
+                    setters.push(quote! { .retry_after(std::time::Duration::from_secs(#value)) })
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        key,
+                        "Expected one of `max`, `queue`, `wait_ms` or `retry_after`",
+                    ))
+                }
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        let max = max.ok_or_else(|| {
+            syn::Error::new(span, "Expected a `max`, i.e. `concurrency(max = 4)`")
+        })?;
+        Ok(ConcurrencyAttr { max, setters })
+    }
+}
@@ -0,0 +1,188 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ReturnType;
+
+use super::Route;
+
+/// Calls a route over JSON-RPC, for `phalanx::jsonrpc::JsonRpcService`
+/// and the service's `rpc` client
+pub struct JsonRpcRoute(Route);
+
+impl From<Route> for JsonRpcRoute {
+    fn from(route: Route) -> Self {
+        JsonRpcRoute(route)
+    }
+}
+
+impl JsonRpcRoute {
+    /// The match arm calling the route with the parameters in `__params`
+    pub fn server_arm(&self) -> TokenStream2 {
+        let fn_name = &self.0.ident;
+        let method = fn_name.to_string();
+
+        let args = super::split_args(&self.0.args);
+        let arg_names = args.iter().map(|(ident, _)| ident);
+        let take_args = args.iter().enumerate().map(|(position, (ident, ty))| {
+            let name = ident.to_string();
+            let value = json_value(ty);
+            let inner = value.ty();
+            let take = value.wrap(quote! {
+                __params.take::<#inner>(#name, #position)?
+            });
+            quote! { let #ident: #ty = #take; }
+        });
+
+        // Routes without arguments leave the parameters alone
+        let params = if args.is_empty() {
+            quote! {}
+        } else {
+            quote! { let mut __params = __params; }
+        };
+
+        let ret = match &self.0.ret_type {
+            ReturnType::Default => quote! { phalanx::jsonrpc::to_result(()) },
+            ReturnType::Type(_, ty) => match super::result_ok_type(ty) {
+                Some(ok_type) => {
+                    let unwrap = json_value(ok_type).unwrap(quote! { res });
+                    quote! {
+                        match res {
+                            Ok(res) => phalanx::jsonrpc::to_result(#unwrap),
+                            Err(err) => Err(phalanx::jsonrpc::RpcError::from_error(err)),
+                        }
+                    }
+                }
+                None => {
+                    let unwrap = json_value(ty).unwrap(quote! { res });
+                    quote! { phalanx::jsonrpc::to_result(#unwrap) }
+                }
+            },
+        };
+
+        // Calls which time out fail as the route's handler would, with `504 Gateway Timeout`
+        let deadline = match &self.0.timeout {
+            Some(timeout) => {
+                let duration = timeout.duration();
+                quote! { phalanx::deadline::Deadline::current().limit(#duration) }
+            }
+            None => quote! { phalanx::deadline::Deadline::current() },
+        };
+        let rate_limit = self.0.check_rate_limit(None).map(|check| {
+            quote! {
+                if let Err(limited) = #check.await {
+                    return Err(limited.to_rpc_error());
+                }
+            }
+        });
+        let permit = self.0.enter_limit(None).map(|enter| {
+            quote! {
+                let __permit = match #enter.await {
+                    Ok(permit) => permit,
+                    Err(err) => return Err(phalanx::jsonrpc::RpcError::from_error(err)),
+                };
+            }
+        });
+        let call = quote! {
+            match phalanx::server::within(#deadline, __server. #fn_name ( #(#arg_names),* )).await {
+                Ok(res) => res,
+                Err(err) => return Err(phalanx::jsonrpc::RpcError::from_error(err)),
+            }
+        };
+
+        quote! {
+            #method => {
+                #params
+                #(#take_args)*
+                #rate_limit
+                #permit
+                let res = #call;
+                #ret
+            }
+        }
+    }
+
+    /// The `rpc` client's method, sending its arguments as named parameters
+    pub fn client_method(&self) -> TokenStream2 {
+        let fn_name = &self.0.ident;
+        let method = fn_name.to_string();
+        let args = &self.0.args;
+        let attrs = &self.0.attrs;
+
+        let params = self.params().map(|(name, value)| {
+            quote! { .with(#name, #value)? }
+        });
+        let (ret_type, inner, wrap) = self.ret_type();
+        let timeout = self.0.client_timeout();
+
+        quote! {
+            #(#attrs)*
+            pub async fn #fn_name ( &self, #(#args),* ) -> Result< #ret_type , Box<dyn std::error::Error> > {
+                let __client = phalanx::client::PhalanxClient::client(self);
+                phalanx::client::timeout(#timeout, async move {
+                    let __params = phalanx::jsonrpc::Params::default() #(#params)*;
+                    let __res = phalanx::jsonrpc::call::<#inner>(__client, #method, __params).await?;
+                    Ok::<_, Box<dyn std::error::Error>>(#wrap)
+                }).await
+            }
+        }
+    }
+
+    /// The batch builder's method, adding a call to the route
+    pub fn batch_method(&self, batch: &syn::Ident) -> TokenStream2 {
+        let fn_name = &self.0.ident;
+        let method = fn_name.to_string();
+        let args = &self.0.args;
+        let attrs = &self.0.attrs;
+
+        let params = self.params().map(|(name, value)| {
+            quote! { .and_then(|__params| __params.with(#name, #value)) }
+        });
+        let (ret_type, inner, wrap) = self.ret_type();
+        let pending = quote! { phalanx::batch::Pending<#ret_type> };
+
+        quote! {
+            #(#attrs)*
+            pub fn #fn_name ( self, #(#args),* ) -> #batch<'a, <P as phalanx::batch::Append<#pending>>::Output>
+            where
+                P: phalanx::batch::Append<#pending>,
+            {
+                let __params = Ok(phalanx::jsonrpc::Params::default()) #(#params)*;
+                #batch(self.0.call::<#inner, #ret_type>(#method, __params, |__res| #wrap))
+            }
+        }
+    }
+
+    /// The name of each argument, and a reference to the value sent for it
+    fn params(&self) -> impl Iterator<Item = (String, TokenStream2)> + '_ {
+        super::split_args(&self.0.args)
+            .into_iter()
+            .map(|(ident, ty)| {
+                let value = match json_value(ty) {
+                    super::JsonValue::Plain(_) => quote! { &#ident },
+                    super::JsonValue::Wrapped(..) => quote! { &*#ident },
+                };
+                (ident.to_string(), value)
+            })
+    }
+
+    /// The type clients return, the type its JSON is decoded as, and the
+    /// expression converting `__res` from one to the other
+    fn ret_type(&self) -> (TokenStream2, TokenStream2, TokenStream2) {
+        match &self.0.ret_type {
+            ReturnType::Default => (quote! { () }, quote! { () }, quote! { __res }),
+            ReturnType::Type(_, ty) => {
+                let ty = super::result_ok_type(ty).unwrap_or(ty);
+                let value = json_value(ty);
+                let inner = value.ty();
+                (
+                    quote! { #ty },
+                    quote! { #inner },
+                    value.wrap(quote! { __res }),
+                )
+            }
+        }
+    }
+}
+
+fn json_value(ty: &syn::Type) -> super::JsonValue<'_> {
+    super::json_value(ty).expect("JSON-RPC routes are filtered with `Route::is_json_rpc`")
+}
@@ -1,16 +1,27 @@
 use std::convert::TryFrom;
 
-use proc_macro2::{Ident, Span};
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use quote::quote;
 
 use syn::{parse::Parse, Attribute, FnArg, ImplItemMethod, LitStr, Pat, PatType, ReturnType, Type};
 
 use regex::Regex;
 
 pub mod client;
+pub mod jsonrpc;
+pub mod proto;
 pub mod server;
 
+mod concurrency_attr;
+mod rate_limit_attr;
+mod retry_attr;
 mod route_attr;
+mod timeout_attr;
+pub use concurrency_attr::ConcurrencyAttr;
+use rate_limit_attr::RateLimitAttr;
+use retry_attr::RetryAttr;
 use route_attr::RouteAttr;
+use timeout_attr::TimeoutAttr;
 
 #[derive(Clone)]
 pub struct Route {
@@ -20,25 +31,79 @@ pub struct Route {
     args: Vec<PatType>,
     path_args: Vec<PatType>,
     payload_arg: Option<PatType>,
+    connection_arg: Option<PatType>,
+
+    /// Wrapper type structured payloads and returns are sent in, if not as written
+    format: Option<syn::Path>,
 
     ret_type: ReturnType,
     attrs: Vec<Attribute>,
     route_attr: RouteAttr,
+
+    /// The route this route takes a list of calls to, from `#[batch_of(route)]`
+    batch_of: Option<Ident>,
+    /// The list route calls to this route are coalesced into, if any
+    batched_by: Option<Box<Route>>,
+    /// Overrides of the client's retry policy, from `#[retry(...)]`
+    retry: Option<RetryAttr>,
+    /// How long calls and handlers may take, from `#[timeout(ms = ...)]`
+    timeout: Option<TimeoutAttr>,
+    /// How many requests the handler handles at once, from `#[concurrency(...)]`
+    /// or the service's default
+    concurrency: Option<ConcurrencyAttr>,
+    /// How often each caller may request the route, from `#[rate_limit(...)]`
+    rate_limit: Option<RateLimitAttr>,
 }
 
 impl Route {
-    pub fn new(method: &ImplItemMethod, server_type: &Type) -> syn::Result<Self> {
+    pub fn new(
+        method: &ImplItemMethod,
+        server_type: &Type,
+        format: Option<&syn::Path>,
+        concurrency: Option<&ConcurrencyAttr>,
+    ) -> syn::Result<Self> {
         validate_method(method)?;
 
         // Get the method arguments, but  the self parameter
-        let args: Vec<_> = method.sig.inputs.iter().skip(1).map(|f| match f {
+        let mut args: Vec<_> = method.sig.inputs.iter().skip(1).map(|f| match f {
             FnArg::Typed(typed) => typed.clone(),
             FnArg::Receiver(_) => panic!("Receiver type found when it should have been automatically removed from arg list already.")
         }).collect();
 
         let mut attrs = Vec::with_capacity(method.attrs.len() - 1);
         let mut route_attr = None;
+        let mut transactional = false;
+        let mut batch_of = None;
+        let mut retry = None;
+        let mut timeout = None;
+        let mut route_concurrency = None;
+        let mut rate_limit = None;
         for attr in &method.attrs {
+            if attr.path.is_ident("transactional") {
+                transactional = true;
+                continue;
+            }
+            if attr.path.is_ident("batch_of") {
+                batch_of = Some(attr.parse_args::<Ident>()?);
+                continue;
+            }
+            if attr.path.is_ident("retry") {
+                retry = Some(attr.parse_args::<RetryAttr>()?);
+                continue;
+            }
+            if attr.path.is_ident("concurrency") {
+                route_concurrency = Some((attr, attr.parse_args::<ConcurrencyAttr>()?));
+                continue;
+            }
+            if attr.path.is_ident("rate_limit") {
+                rate_limit = Some((attr, attr.parse_args::<RateLimitAttr>()?));
+                continue;
+            }
+            if attr.path.is_ident("timeout") {
+                timeout = Some((attr, attr.parse_args::<TimeoutAttr>()?));
+                continue;
+            }
+
             match RouteAttr::try_from(attr) {
                 Ok(parsed_attr) => {
                     if route_attr.is_some() {
@@ -56,6 +121,19 @@ impl Route {
         let route_attr = route_attr
             .ok_or_else(move || syn::Error::new_spanned(&method.sig, "Missing route attribute"))?;
 
+        // Transactional routes receive a database connection as their first argument
+        let connection_arg = if transactional {
+            if args.is_empty() {
+                return Err(syn::Error::new_spanned(
+                    &method.sig,
+                    "Transactional routes require a database connection as their first argument",
+                ));
+            }
+            Some(args.remove(0))
+        } else {
+            None
+        };
+
         // Find which arguments are path arguments and determine if there is an extra payload argument
         lazy_static::lazy_static! {
             static ref RE: Regex = Regex::new(&r"\{([[:alpha:]_]+)\}").unwrap();
@@ -101,17 +179,377 @@ impl Route {
             }
         }
 
+        if route_attr.is_ws() {
+            validate_ws(method, payload_arg.as_ref(), connection_arg.is_some())?;
+        }
+        // Sockets and subscriptions stay open, so only their connection could time out
+        let timeout = match timeout {
+            Some((attr, _)) if route_attr.is_ws() || route_attr.is_subscribe() => {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "Timeouts are not supported on WebSocket or subscription routes",
+                ));
+            }
+            timeout => timeout.map(|(_, timeout)| timeout),
+        };
+        let concurrency = match route_concurrency {
+            Some((attr, _)) if route_attr.is_ws() || route_attr.is_subscribe() => {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "Concurrency limits are not supported on WebSocket or subscription routes",
+                ));
+            }
+            Some((_, concurrency)) => Some(concurrency),
+            None if route_attr.is_ws() || route_attr.is_subscribe() => None,
+            None => concurrency.cloned(),
+        };
+        let rate_limit = match rate_limit {
+            Some((attr, _)) if route_attr.is_ws() || route_attr.is_subscribe() => {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "Rate limits are not supported on WebSocket or subscription routes",
+                ));
+            }
+            rate_limit => rate_limit.map(|(_, rate_limit)| rate_limit),
+        };
+        if route_attr.is_subscribe() && subscription_type(&method.sig.output).is_none() {
+            return Err(syn::Error::new_spanned(
+                &method.sig.output,
+                "Subscription routes must return a `Subscription<T>`",
+            ));
+        }
+
         Ok(Self {
             server_type: server_type.clone(),
             ident: method.sig.ident.clone(),
             args,
             path_args,
             payload_arg,
+            connection_arg,
+            format: format.cloned(),
             ret_type: method.sig.output.clone(),
             attrs,
             route_attr,
+            batch_of,
+            batched_by: None,
+            retry,
+            timeout,
+            concurrency,
+            rate_limit,
+        })
+    }
+
+    /// Whether the route is a single request and response, rather than a socket or subscription
+    pub fn is_rpc(&self) -> bool {
+        !self.route_attr.is_ws() && !self.route_attr.is_subscribe()
+    }
+
+    /// How long client calls may take: the route's timeout if it has one,
+    /// or else the timeout of the client in `__client`
+    fn client_timeout(&self) -> TokenStream2 {
+        match &self.timeout {
+            Some(timeout) => {
+                let duration = timeout.duration();
+                quote! { Some(#duration) }
+            }
+            None => quote! { __client.default_timeout() },
+        }
+    }
+
+    /// The route's server type and method, naming it in limits, loaders,
+    /// circuits and metrics
+    fn limit_name(&self) -> String {
+        let server_type = &self.server_type;
+        // Named as written, without the spaces tokens are printed with
+        format!(
+            "{}::{}",
+            quote!(#server_type).to_string().replace(' ', ""),
+            self.ident
+        )
+    }
+
+    /// Waiting for a turn under the route's concurrency limit, if it has one,
+    /// as a future of a `Result<_, phalanx::server::concurrency::Overloaded>`
+    ///
+    /// `req` is the request being handled, or `None` for calls made within one.
+    fn enter_limit(&self, req: Option<TokenStream2>) -> Option<TokenStream2> {
+        let concurrency = self.concurrency.as_ref()?;
+        let name = self.limit_name();
+        let limit = concurrency.limit();
+        Some(match req {
+            Some(req) => quote! { phalanx::server::concurrency::enter(#name, #limit, #req) },
+            None => quote! { phalanx::server::concurrency::enter_current(#name, #limit) },
         })
     }
+
+    /// Taking a token from the caller's bucket, if the route is rate limited,
+    /// as a future of a `Result<(), phalanx::server::rate_limit::Limited>`
+    ///
+    /// `req` is the request being handled, or `None` for calls made within one.
+    fn check_rate_limit(&self, req: Option<TokenStream2>) -> Option<TokenStream2> {
+        let rate_limit = self.rate_limit.as_ref()?;
+        let name = self.limit_name();
+        let limit = rate_limit.limit();
+        let key = rate_limit.key();
+        Some(match req {
+            Some(req) => {
+                quote! { phalanx::server::rate_limit::check(#name, #limit, #key, #req) }
+            }
+            None => quote! { phalanx::server::rate_limit::check_current(#name, #limit, #key) },
+        })
+    }
+
+    /// Whether the route's arguments and return value can all be sent as JSON
+    pub fn is_json_rpc(&self) -> bool {
+        let ret_type = match &self.ret_type {
+            ReturnType::Type(_, ty) => json_value(result_ok_type(ty).unwrap_or(ty)).is_some(),
+            ReturnType::Default => true,
+        };
+
+        self.is_rpc()
+            && self.connection_arg.is_none()
+            && ret_type
+            && self.args.iter().all(|arg| json_value(&arg.ty).is_some())
+    }
+
+    /// The item type of a list route's `Vec` of values
+    fn list_item_type(&self) -> Option<&Type> {
+        let values = match &self.ret_type {
+            ReturnType::Type(_, ty) => json_value(result_ok_type(ty).unwrap_or(ty))?,
+            ReturnType::Default => return None,
+        };
+        generic_type(values.ty(), "Vec")
+    }
+
+    /// The message type of a subscription route
+    fn subscription_type(&self) -> Option<&Type> {
+        if self.route_attr.is_subscribe() {
+            subscription_type(&self.ret_type)
+        } else {
+            None
+        }
+    }
+
+    /// The types of the incoming and outgoing messages of a WebSocket route
+    fn ws_types(&self) -> (&Type, &Type) {
+        let incoming = self
+            .payload_arg
+            .as_ref()
+            .and_then(|arg| generic_type(&arg.ty, "WsStream"));
+        let outgoing = match &self.ret_type {
+            ReturnType::Type(_, ty) => stream_item_type(ty),
+            ReturnType::Default => None,
+        };
+        match (incoming, outgoing) {
+            (Some(incoming), Some(outgoing)) => (incoming, outgoing),
+            _ => unreachable!("WebSocket routes are validated when parsed"),
+        }
+    }
+
+    /// How a payload or return value of type `ty` is sent, if not as written
+    fn wrapper(&self, ty: &Type) -> Option<Wrapper> {
+        if is_byte_vec(ty) {
+            Some(Wrapper::Binary)
+        } else if is_scalar(ty) {
+            Some(Wrapper::Text)
+        } else if is_sent_as_written(ty) {
+            None
+        } else {
+            self.format.clone().map(Wrapper::Format)
+        }
+    }
+
+    /// Whether a value of type `ty` is sent as [phalanx::web::Negotiated]
+    fn is_negotiated(&self, ty: &Type) -> bool {
+        let path = match (self.wrapper(ty), ty) {
+            (Some(Wrapper::Format(format)), _) => format,
+            (None, Type::Path(path)) => path.path.clone(),
+            _ => return false,
+        };
+
+        match path.segments.last() {
+            Some(segment) => segment.ident == "Negotiated",
+            None => false,
+        }
+    }
+}
+
+/// A phalanx web type carrying a value which can't be sent directly
+enum Wrapper {
+    /// `Vec<u8>`, sent as [phalanx::web::Binary]
+    Binary,
+    /// Primitive scalars, sent as [phalanx::web::Text]
+    Text,
+    /// Structured values, sent in the service's format
+    Format(syn::Path),
+}
+
+impl Wrapper {
+    /// The wrapped type of a value of type `ty`
+    fn ty(&self, ty: &Type) -> TokenStream2 {
+        match self {
+            Wrapper::Binary => quote! { phalanx::web::Binary },
+            Wrapper::Text => quote! { phalanx::web::Text<#ty> },
+            Wrapper::Format(format) => quote! { #format<#ty> },
+        }
+    }
+
+    /// A function wrapping a value
+    fn wrap(&self) -> TokenStream2 {
+        match self {
+            Wrapper::Binary => quote! { phalanx::web::Binary::from },
+            Wrapper::Text => quote! { phalanx::web::Text },
+            Wrapper::Format(format) => quote! { #format },
+        }
+    }
+
+    /// Get the value back out of the wrapper `expr`
+    fn unwrap(&self, expr: TokenStream2) -> TokenStream2 {
+        match self {
+            Wrapper::Binary => quote! { #expr.to_vec() },
+            Wrapper::Text | Wrapper::Format(_) => quote! { #expr.into_inner() },
+        }
+    }
+}
+
+/// Link each `#[batch_of(route)]` list route to the route it batches
+pub fn link_batches(routes: &mut [Route]) -> syn::Result<()> {
+    for i in 0..routes.len() {
+        let target = match &routes[i].batch_of {
+            Some(target) => target.clone(),
+            None => continue,
+        };
+        validate_list_route(&routes[i], &target)?;
+
+        let j = match routes.iter().position(|route| route.ident == target) {
+            Some(j) => j,
+            None => {
+                return Err(syn::Error::new_spanned(
+                    &target,
+                    format!("No route named `{}` to batch", target),
+                ))
+            }
+        };
+        let batched = &routes[j];
+        let ret_type = match &batched.ret_type {
+            ReturnType::Type(_, ty) => json_value(result_ok_type(ty).unwrap_or(ty)),
+            ReturnType::Default => None,
+        };
+        if batched.args.len() != 1 || json_value(&batched.args[0].ty).is_none() {
+            return Err(syn::Error::new_spanned(
+                &target,
+                "Batched routes must take a single argument which can be sent as JSON",
+            ));
+        }
+        if ret_type.is_none() || !batched.is_rpc() {
+            return Err(syn::Error::new_spanned(
+                &target,
+                "Batched routes must return a value which can be sent as JSON",
+            ));
+        }
+        if batched.batched_by.is_some() {
+            return Err(syn::Error::new_spanned(
+                &target,
+                format!("`{}` is already batched by another route", target),
+            ));
+        }
+
+        let list_route = Box::new(routes[i].clone());
+        routes[j].batched_by = Some(list_route);
+    }
+
+    Ok(())
+}
+
+/// List routes take a list of arguments as their payload and return a list of values
+fn validate_list_route(route: &Route, target: &Ident) -> syn::Result<()> {
+    let keys = route
+        .payload_arg
+        .as_ref()
+        .and_then(|arg| json_value(&arg.ty))
+        .and_then(|keys| generic_type(keys.ty(), "Vec"));
+    if keys.is_none() || !route.path_args.is_empty() || route.connection_arg.is_some() {
+        return Err(syn::Error::new_spanned(
+            target,
+            "`#[batch_of]` routes must take only a `Vec` of arguments, such as `Json<Vec<u32>>`",
+        ));
+    }
+
+    if route.list_item_type().is_none() {
+        return Err(syn::Error::new_spanned(
+            target,
+            "`#[batch_of]` routes must return a `Vec` of values, such as `Json<Vec<Post>>`",
+        ));
+    }
+
+    Ok(())
+}
+
+/// How a value of some type is sent over JSON-RPC
+enum JsonValue<'a> {
+    /// Sent as written
+    Plain(&'a Type),
+    /// A wrapper from `phalanx::web` or actix, such as `Json<T>`, sent as its `T`
+    Wrapped(syn::Path, &'a Type),
+}
+
+impl<'a> JsonValue<'a> {
+    /// The type sent as JSON
+    fn ty(&self) -> &'a Type {
+        match self {
+            JsonValue::Plain(ty) | JsonValue::Wrapped(_, ty) => ty,
+        }
+    }
+
+    /// Wrap the inner value `expr`
+    fn wrap(&self, expr: TokenStream2) -> TokenStream2 {
+        match self {
+            JsonValue::Plain(_) => expr,
+            JsonValue::Wrapped(wrapper, _) => quote! { #wrapper(#expr) },
+        }
+    }
+
+    /// Get the inner value out of the wrapper `expr`
+    fn unwrap(&self, expr: TokenStream2) -> TokenStream2 {
+        match self {
+            JsonValue::Plain(_) => expr,
+            JsonValue::Wrapped(..) => quote! { #expr.into_inner() },
+        }
+    }
+}
+
+/// How a value of type `ty` is sent over JSON-RPC, if it can be represented as JSON
+fn json_value(ty: &Type) -> Option<JsonValue<'_>> {
+    const WRAPPERS: &[&str] = &[
+        "Json",
+        "Form",
+        "Cbor",
+        "MsgPack",
+        "Bincode",
+        "Negotiated",
+        "Text",
+    ];
+
+    if let Some(wrapper) = WRAPPERS
+        .iter()
+        .find(|wrapper| generic_type(ty, wrapper).is_some())
+    {
+        let mut path = match ty {
+            Type::Path(path) => path.path.clone(),
+            _ => unreachable!("generic types are paths"),
+        };
+        if let Some(segment) = path.segments.last_mut() {
+            segment.arguments = syn::PathArguments::None;
+        }
+        return Some(JsonValue::Wrapped(path, generic_type(ty, wrapper)?));
+    }
+
+    let is_string = matches!(ty, Type::Path(path) if path.path.is_ident("String"));
+    if is_string || !is_sent_as_written(ty) || is_unit(ty) {
+        Some(JsonValue::Plain(ty))
+    } else {
+        None
+    }
 }
 
 fn validate_method(method: &ImplItemMethod) -> syn::Result<()> {
@@ -178,6 +616,42 @@ fn validate_method(method: &ImplItemMethod) -> syn::Result<()> {
     Ok(())
 }
 
+/// WebSocket routes take a `WsStream` of incoming messages and return a stream of outgoing ones
+fn validate_ws(
+    method: &ImplItemMethod,
+    payload_arg: Option<&PatType>,
+    transactional: bool,
+) -> syn::Result<()> {
+    if transactional {
+        return Err(syn::Error::new_spanned(
+            &method.sig,
+            "WebSocket routes can't be transactional",
+        ));
+    }
+
+    if payload_arg
+        .and_then(|arg| generic_type(&arg.ty, "WsStream"))
+        .is_none()
+    {
+        return Err(syn::Error::new_spanned(
+            &method.sig,
+            "WebSocket routes must take a `WsStream<T>` of incoming messages",
+        ));
+    }
+
+    match &method.sig.output {
+        ReturnType::Type(_, ty)
+            if matches!(ty.as_ref(), Type::ImplTrait(_)) && stream_item_type(ty).is_some() =>
+        {
+            Ok(())
+        }
+        output => Err(syn::Error::new_spanned(
+            output,
+            "WebSocket routes must return an `impl Stream<Item = T>` of outgoing messages",
+        )),
+    }
+}
+
 fn split_args<'a>(args: &'a [PatType]) -> Vec<(&'a Ident, &'a Type)> {
     args.iter()
         .map(|typed| match typed.pat.as_ref() {
@@ -186,3 +660,144 @@ fn split_args<'a>(args: &'a [PatType]) -> Vec<(&'a Ident, &'a Type)> {
         })
         .collect()
 }
+
+/// Get the `T` out of a `Result<T, E>` type, if the type is a result
+fn result_ok_type(ty: &Type) -> Option<&Type> {
+    generic_type(ty, "Result")
+}
+
+/// Get the `T` out of a `Subscription<T>` return type
+fn subscription_type(ret_type: &ReturnType) -> Option<&Type> {
+    match ret_type {
+        ReturnType::Type(_, ty) => generic_type(ty, "Subscription"),
+        ReturnType::Default => None,
+    }
+}
+
+/// Get the `T` out of an `EventStream<T>` type
+fn event_type(ty: &Type) -> Option<&Type> {
+    generic_type(ty, "EventStream")
+}
+
+/// Get the first type argument of a type named `name`, such as the `T` of `Result<T, E>`
+fn generic_type<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+
+    if segment.ident != name {
+        return None;
+    }
+
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first()? {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Get the `T` out of an `impl Stream<Item = T>` or `JsonStream<T>` type
+fn stream_item_type(ty: &Type) -> Option<&Type> {
+    match ty {
+        Type::ImplTrait(impl_trait) => impl_trait.bounds.iter().find_map(|bound| {
+            let segment = match bound {
+                syn::TypeParamBound::Trait(bound) => bound.path.segments.last()?,
+                _ => return None,
+            };
+            if segment.ident != "Stream" {
+                return None;
+            }
+            match &segment.arguments {
+                syn::PathArguments::AngleBracketed(args) => {
+                    args.args.iter().find_map(|arg| match arg {
+                        syn::GenericArgument::Binding(binding) if binding.ident == "Item" => {
+                            Some(&binding.ty)
+                        }
+                        _ => None,
+                    })
+                }
+                _ => None,
+            }
+        }),
+        Type::Path(_) => generic_type(ty, "JsonStream"),
+        _ => None,
+    }
+}
+
+/// Whether the type is spelled `Vec<u8>`, which is sent as [phalanx::web::Binary] on the server
+fn is_byte_vec(ty: &Type) -> bool {
+    let segment = match ty {
+        Type::Path(path) => match path.path.segments.last() {
+            Some(segment) => segment,
+            None => return false,
+        },
+        _ => return false,
+    };
+
+    if segment.ident != "Vec" {
+        return false;
+    }
+
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(syn::GenericArgument::Type(Type::Path(path))) => path.path.is_ident("u8"),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether the type is a primitive scalar, which is sent as [phalanx::web::Text]
+fn is_scalar(ty: &Type) -> bool {
+    const SCALARS: &[&str] = &[
+        "bool", "char", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64",
+        "i128", "isize", "f32", "f64",
+    ];
+
+    match ty {
+        Type::Path(path) => SCALARS.iter().any(|scalar| path.path.is_ident(scalar)),
+        _ => false,
+    }
+}
+
+fn is_unit(ty: &Type) -> bool {
+    matches!(ty, Type::Tuple(tuple) if tuple.elems.is_empty())
+}
+
+/// Whether the type is sent as written, even when the service has a format
+///
+/// This covers the unit type, strings, `impl Trait` types and the wrapper
+/// types from `phalanx::web`. Payloads are extracted through
+/// `phalanx::server::Extract`, so only `PhalanxPayload`s are listed.
+fn is_sent_as_written(ty: &Type) -> bool {
+    const WRAPPERS: &[&str] = &[
+        "String",
+        "Json",
+        "JsonStream",
+        "EventStream",
+        "Subscription",
+        "Cbor",
+        "MsgPack",
+        "Bincode",
+        "Negotiated",
+        "Form",
+        "Multipart",
+        "Protobuf",
+        "Text",
+        "Binary",
+        "BodyStream",
+        "Bytes",
+    ];
+
+    match ty {
+        Type::Path(path) => match path.path.segments.last() {
+            Some(segment) => WRAPPERS.iter().any(|wrapper| segment.ident == wrapper),
+            None => false,
+        },
+        Type::ImplTrait(_) => true,
+        _ => is_unit(ty),
+    }
+}
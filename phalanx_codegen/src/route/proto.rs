@@ -0,0 +1,187 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, ToTokens};
+use syn::Type;
+
+use super::Route;
+
+/// Describes a route as an RPC for `phalanx::proto::ProtoService`
+///
+/// Only routes whose bodies are protobuf messages on the wire are described:
+/// the payload, if any, must be a `Protobuf<T>` and the response either a
+/// `Protobuf<T>` or `()`. Routes with path arguments get a request message
+/// of their own, with a field for each path argument and one for the payload.
+pub struct ProtoRoute(Route);
+
+impl ProtoRoute {
+    /// The route as an RPC, if its payload and response are protobuf messages
+    pub fn new(route: Route) -> Option<Self> {
+        if !route.is_rpc() {
+            return None;
+        }
+        request(&route)?;
+        response(&route)?;
+        Some(ProtoRoute(route))
+    }
+}
+
+impl ToTokens for ProtoRoute {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        let name = rpc_name(&self.0.ident.to_string());
+        let method = method(&self.0);
+        let path = &self.0.route_attr.route;
+        let request = request(&self.0);
+        let response = response(&self.0);
+
+        tokens.extend(quote! {
+            phalanx::proto::Rpc {
+                name: #name,
+                method: #method,
+                path: #path,
+                request: #request,
+                response: #response,
+            }
+        });
+    }
+}
+
+/// Describes a route left out of `phalanx::proto::ProtoService::rpcs`, as it
+/// doesn't send protobuf messages
+pub struct SkippedProtoRoute(Route);
+
+impl From<Route> for SkippedProtoRoute {
+    fn from(route: Route) -> Self {
+        SkippedProtoRoute(route)
+    }
+}
+
+impl ToTokens for SkippedProtoRoute {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        let name = self.0.ident.to_string();
+        let method = method(&self.0);
+        let path = &self.0.route_attr.route;
+
+        tokens.extend(quote! {
+            phalanx::proto::SkippedRoute {
+                name: #name,
+                method: #method,
+                path: #path,
+            }
+        });
+    }
+}
+
+/// The route's HTTP method, as written in comments
+fn method(route: &Route) -> String {
+    route
+        .route_attr
+        .method_ident_lower()
+        .to_string()
+        .to_uppercase()
+}
+
+/// The request message: the `Protobuf<T>` payload, or `Empty` without one,
+/// or a message of the path arguments and payload if there are path arguments
+fn request(route: &Route) -> Option<TokenStream2> {
+    let payload = match &route.payload_arg {
+        Some(arg) => {
+            let (ident, ty) = super::split_args(std::slice::from_ref(arg))[0];
+            Some((ident.to_string(), message_type(ty)?))
+        }
+        None => None,
+    };
+
+    if route.path_args.is_empty() {
+        return Some(match payload {
+            Some((_, message)) => quote! { phalanx::proto::ProtoMessage::Named(#message) },
+            None => quote! { phalanx::proto::ProtoMessage::Empty },
+        });
+    }
+
+    let fields = super::split_args(&route.path_args)
+        .into_iter()
+        .map(|(ident, ty)| (ident.to_string(), scalar_type(ty).to_string()))
+        .chain(payload)
+        .map(|(name, ty)| quote! { phalanx::proto::ProtoField { name: #name, ty: #ty } });
+    Some(quote! { phalanx::proto::ProtoMessage::Request(vec![#(#fields),*]) })
+}
+
+/// The response message: the `Protobuf<T>` returned, or `Empty` for `()`
+fn response(route: &Route) -> Option<TokenStream2> {
+    let ty = match &route.ret_type {
+        syn::ReturnType::Default => return Some(quote! { phalanx::proto::ProtoMessage::Empty }),
+        syn::ReturnType::Type(_, ty) => super::result_ok_type(ty).unwrap_or(ty),
+    };
+
+    if super::is_unit(ty) {
+        return Some(quote! { phalanx::proto::ProtoMessage::Empty });
+    }
+    let message = message_type(ty)?;
+    Some(quote! { phalanx::proto::ProtoMessage::Named(#message) })
+}
+
+/// `read_post` becomes `ReadPost`
+fn rpc_name(ident: &str) -> String {
+    ident
+        .split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// The protobuf scalar type of a path argument. Types without one are
+/// described as `string`, as that's how they're sent in the URL.
+fn scalar_type(ty: &Type) -> &'static str {
+    let ident = match ty {
+        Type::Path(path) => match path.path.segments.last() {
+            Some(segment) => segment.ident.to_string(),
+            None => return "string",
+        },
+        _ => return "string",
+    };
+
+    match ident.as_str() {
+        "i8" | "i16" | "i32" => "int32",
+        "i64" | "isize" => "int64",
+        "u8" | "u16" | "u32" => "uint32",
+        "u64" | "usize" => "uint64",
+        "f32" => "float",
+        "f64" => "double",
+        "bool" => "bool",
+        _ => "string",
+    }
+}
+
+/// The message name of a `Protobuf<T>` type, qualified by the path `T` is
+/// written with, so `Protobuf<blog::Post>` is a `blog.Post`
+fn message_type(ty: &Type) -> Option<String> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+
+    if segment.ident != "Protobuf" {
+        return None;
+    }
+
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first()? {
+            syn::GenericArgument::Type(Type::Path(path)) => Some(
+                path.path
+                    .segments
+                    .iter()
+                    .map(|segment| segment.ident.to_string())
+                    .filter(|ident| !matches!(ident.as_str(), "crate" | "self" | "super"))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            ),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,97 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    Ident, LitInt, LitStr, Token,
+};
+
+/// The arguments to `#[rate_limit(per_minute = 60, key = "header:X-Api-Key")]`
+#[derive(Clone)]
+pub struct RateLimitAttr {
+    /// A call to the `phalanx::server::rate_limit::RateLimit` constructor for the period
+    limit: TokenStream2,
+    key: TokenStream2,
+}
+
+impl RateLimitAttr {
+    /// The route's limit, as a `phalanx::server::rate_limit::RateLimit`
+    pub fn limit(&self) -> &TokenStream2 {
+        &self.limit
+    }
+
+    /// What tells callers apart, as a `phalanx::server::rate_limit::Key`
+    pub fn key(&self) -> &TokenStream2 {
+        &self.key
+    }
+}
+
+impl Parse for RateLimitAttr {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let span = input.span();
+        let mut limit = None;
+        let mut key = None;
+        while !input.is_empty() {
+            let name: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match name.to_string().as_str() {
+                "per_second" | "per_minute" | "per_hour" => {
+                    let value: LitInt = input.parse()?;
+                    if value.base10_parse::<u32>()? == 0 {
+                        return Err(syn::Error::new_spanned(
+                            value,
+                            "At least one request must be allowed",
+                        ));
+                    }
+                    if limit.is_some() {
+                        return Err(syn::Error::new_spanned(
+                            name,
+                            "Only one of `per_second`, `per_minute` or `per_hour` may be set",
+                        ));
+                    }
+                    limit = Some(quote! { phalanx::server::rate_limit::RateLimit::#name(#value) });
+                }
+                "key" => {
+                    let value: LitStr = input.parse()?;
+                    let string = value.value();
+                    key = Some(match string.as_str() {
+                        "ip" => quote! { phalanx::server::rate_limit::Key::Ip },
+                        "user" => quote! { phalanx::server::rate_limit::Key::User },
+                        _ => match string.strip_prefix("header:") {
+                            Some(header) if !header.is_empty() => {
+                                quote! { phalanx::server::rate_limit::Key::Header(#header) }
+                            }
+                            _ => {
+                                return Err(syn::Error::new_spanned(
+                                    value,
+                                    "Expected a key of \"ip\", \"user\" or \"header:<name>\"",
+                                ))
+                            }
+                        },
+                    });
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        name,
+                        "Expected one of `per_second`, `per_minute`, `per_hour` or `key`",
+                    ))
+                }
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        let limit = limit.ok_or_else(|| {
+            syn::Error::new(
+                span,
+                "Expected a limit, i.e. `rate_limit(per_minute = 60, key = \"ip\")`",
+            )
+        })?;
+        Ok(RateLimitAttr {
+            limit,
+            key: key.unwrap_or_else(|| quote! { phalanx::server::rate_limit::Key::Ip }),
+        })
+    }
+}
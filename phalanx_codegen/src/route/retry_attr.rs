@@ -0,0 +1,92 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    bracketed,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Ident, LitBool, LitInt, Token,
+};
+
+/// The arguments to `#[retry(attempts = 5, backoff_ms = 50, statuses = [503])]`,
+/// as calls overriding the client's `phalanx::retry::RetryPolicy`
+#[derive(Clone)]
+pub struct RetryAttr {
+    setters: Vec<TokenStream2>,
+}
+
+impl RetryAttr {
+    /// The policy requests to the route are sent with
+    pub fn policy(&self) -> TokenStream2 {
+        let setters = &self.setters;
+        quote! {
+            phalanx::retry::RetryPolicy::clone(__client.retry_policy()) #(#setters)*
+        }
+    }
+}
+
+impl Parse for RetryAttr {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut setters = Vec::new();
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            let setter = match key.to_string().as_str() {
+                "never" => quote! { .attempts(1) },
+                "non_idempotent" => quote! { .non_idempotent(true) },
+                "attempts" => {
+                    input.parse::<Token![=]>()?;
+                    let attempts: LitInt = input.parse()?;
+                    if attempts.base10_parse::<u32>()? == 0 {
+                        return Err(syn::Error::new_spanned(
+                            attempts,
+                            "Requests must be attempted at least once",
+                        ));
+                    }
+                    quote! { .attempts(#attempts) }
+                }
+                "backoff_ms" => {
+                    input.parse::<Token![=]>()?;
+                    let millis: LitInt = input.parse()?;
+                    quote! { .backoff(std::time::Duration::from_millis(#millis)) }
+                }
+                "max_backoff_ms" => {
+                    input.parse::<Token![=]>()?;
+                    let millis: LitInt = input.parse()?;
+                    quote! { .max_backoff(std::time::Duration::from_millis(#millis)) }
+                }
+                "jitter" => {
+                    input.parse::<Token![=]>()?;
+                    let jitter: LitBool = input.parse()?;
+                    quote! { .jitter(#jitter) }
+                }
+                "statuses" => {
+                    input.parse::<Token![=]>()?;
+                    let content;
+                    bracketed!(content in input);
+                    let statuses = Punctuated::<LitInt, Token![,]>::parse_terminated(&content)?;
+                    for status in statuses.iter() {
+                        let code = status.base10_parse::<u16>()?;
+                        if !(100..600).contains(&code) {
+                            return Err(syn::Error::new_spanned(status, "Not an HTTP status code"));
+                        }
+                    }
+                    let statuses = statuses.iter();
+                    quote! { .statuses(&[#(#statuses),*]) }
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        key,
+                        "Expected one of `attempts`, `backoff_ms`, `max_backoff_ms`, `jitter`, `statuses`, `non_idempotent` or `never`",
+                    ))
+                }
+            };
+            setters.push(setter);
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        Ok(RetryAttr { setters })
+    }
+}
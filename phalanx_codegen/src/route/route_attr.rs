@@ -49,6 +49,8 @@ method_type! {
     Options,   options,
     Trace,     trace,
     Patch,     patch,
+    Ws,        ws,
+    Subscribe, subscribe,
 }
 
 #[derive(Clone)]
@@ -60,11 +62,29 @@ pub(super) struct RouteAttr {
 
 impl RouteAttr {
     pub fn method_ident(&self) -> Ident {
-        Ident::new(self.method.as_str(), self.span)
+        Ident::new(self.http_method().as_str(), self.span)
     }
 
     pub fn method_ident_lower(&self) -> Ident {
-        Ident::new(self.method.as_lower_str(), self.span)
+        Ident::new(self.http_method().as_lower_str(), self.span)
+    }
+
+    /// WebSockets and subscriptions are opened with a `GET` request
+    fn http_method(&self) -> &MethodType {
+        match self.method {
+            MethodType::Ws | MethodType::Subscribe => &MethodType::Get,
+            ref method => method,
+        }
+    }
+
+    /// WebSocket routes are generated separately
+    pub fn is_ws(&self) -> bool {
+        self.method == MethodType::Ws
+    }
+
+    /// Subscriptions are sent as server-sent events
+    pub fn is_subscribe(&self) -> bool {
+        self.method == MethodType::Subscribe
     }
 }
 
@@ -13,6 +13,10 @@ impl From<Route> for ServerRoute {
 
 impl ToTokens for ServerRoute {
     fn to_tokens(&self, tokens: &mut TokenStream2) {
+        if self.0.route_attr.is_ws() {
+            return self.ws_to_tokens(tokens);
+        }
+
         // Extract the identifier and type from each argument
         let args = super::split_args(&self.0.args);
 
@@ -22,11 +26,52 @@ impl ToTokens for ServerRoute {
         let path_args: Vec<_> = super::split_args(&self.0.path_args);
 
         let (ret_type, ret_trailer) = match &self.0.ret_type {
+            // Subscriptions are sent as server-sent events
+            _ if self.0.subscription_type().is_some() => {
+                let message = self.0.subscription_type();
+                (
+                    quote! { phalanx::web::EventStream<#message> },
+                    quote! { phalanx::web::EventStream::from(res) },
+                )
+            }
             syn::ReturnType::Default => (
-                quote! { -> phalanx::server::UnitResponder },
+                quote! { phalanx::server::UnitResponder },
                 quote! { phalanx::server::UnitResponder },
             ),
-            ty => (quote! { #ty }, quote! { res }),
+            syn::ReturnType::Type(_, ty) => match super::result_ok_type(ty) {
+                Some(ok_type) if super::is_unit(ok_type) => (
+                    quote! { impl phalanx::reexports::Responder },
+                    quote! { res.map(|_| phalanx::server::UnitResponder) },
+                ),
+                Some(ok_type) => match self.0.wrapper(ok_type) {
+                    Some(wrapper) => {
+                        let wrap = wrapper.wrap();
+                        (
+                            quote! { impl phalanx::reexports::Responder },
+                            quote! { res.map(#wrap) },
+                        )
+                    }
+                    None => (quote! { #ty }, quote! { res }),
+                },
+                // Streams returned as `impl Stream` are sent as newline delimited JSON
+                None if matches!(ty.as_ref(), syn::Type::ImplTrait(_)) => {
+                    match super::stream_item_type(ty) {
+                        Some(item) => (
+                            quote! { phalanx::web::JsonStream<#item> },
+                            quote! { phalanx::web::JsonStream::new(res) },
+                        ),
+                        None => (quote! { #ty }, quote! { res }),
+                    }
+                }
+                None => match self.0.wrapper(ty) {
+                    Some(wrapper) => {
+                        let wrapped = wrapper.ty(ty);
+                        let wrap = wrapper.wrap();
+                        (quote! { #wrapped }, quote! { #wrap(res) })
+                    }
+                    None => (quote! { #ty }, quote! { res }),
+                },
+            },
         };
 
         // Output the new method
@@ -45,13 +90,163 @@ impl ToTokens for ServerRoute {
             quote! {}
         };
 
-        let payload_arg = &self.0.payload_arg;
+        // Payloads are extracted as their PhalanxPayload implementation says, once
+        // the request has been let through, so requests turned away aren't read.
+        // Payloads which aren't sent as written are extracted as their wrapper,
+        // and unwrapped before the call
+        let (payload_arg, payload, payload_conversion) = match &self.0.payload_arg {
+            Some(arg) => {
+                let (ident, ty) = super::split_args(std::slice::from_ref(arg))[0];
+                let (extract, unwrap) = match self.0.wrapper(&arg.ty) {
+                    Some(wrapper) => {
+                        let wrapped = wrapper.ty(ty);
+                        let unwrap = wrapper.unwrap(quote! { #ident });
+                        (wrapped, quote! { let #ident = #unwrap; })
+                    }
+                    None => (quote! { #ty }, quote! {}),
+                };
+                (
+                    quote! { __payload: phalanx::reexports::web::Payload, },
+                    quote! {
+                        let #ident: phalanx::server::Extract<#extract> =
+                            phalanx::server::extract(&__req, &mut __payload.into_inner()).await?;
+                    },
+                    quote! {
+                        let #ident = #ident.into_inner();
+                        #unwrap
+                    },
+                )
+            }
+            None => (quote! {}, quote! {}, quote! {}),
+        };
+
+        let call = if let Some(connection_arg) = &self.0.connection_arg {
+            let (conn, _) = super::split_args(std::slice::from_ref(connection_arg))[0];
+            quote! {
+                let server = server.into_inner();
+                let res = phalanx::diesel::transaction(#conn, |#conn| async move {
+                    server. #fn_name ( #conn, #(#arg_names),* ).await
+                }).await;
+            }
+        } else {
+            quote! {
+                let res = server.into_inner(). #fn_name ( #(#arg_names),* ).await;
+            }
+        };
+
+        // Connections are checked out of the pool once the request has been let
+        // through, so requests turned away or waiting don't hold on to one
+        let connection = self.0.connection_arg.as_ref().map(|arg| {
+            let (conn, ty) = super::split_args(std::slice::from_ref(arg))[0];
+            quote! {
+                let #conn: #ty = phalanx::server::extract(&__req, &mut phalanx::reexports::dev::Payload::None).await?;
+            }
+        });
+
+        // Handlers still running at the request's deadline, or after the route's
+        // timeout, are dropped and responded to with `504 Gateway Timeout`. The
+        // deadline is extracted first, so it covers reading the payload, while the
+        // route's timeout only starts once the handler runs
+        let deadline = match &self.0.timeout {
+            Some(timeout) => {
+                let duration = timeout.duration();
+                quote! { __deadline.limit(#duration) }
+            }
+            None => quote! { __deadline },
+        };
+        // Requests over the route's concurrency limit wait for a turn, and are
+        // responded to with `503 Service Unavailable` if they can't get one
+        let permit = self
+            .0
+            .enter_limit(Some(quote! { &__req }))
+            .map(|enter| quote! { let __permit = #enter.await?; });
+        // Requests accepting none of the server's formats for a negotiated response
+        // are refused with `406 Not Acceptable` before the route is run
+        let accepted = match &self.0.ret_type {
+            syn::ReturnType::Type(_, ty) if self.0.subscription_type().is_none() => {
+                let ty = super::result_ok_type(ty).unwrap_or(ty);
+                if self.0.is_negotiated(ty) {
+                    Some(quote! { phalanx::web::NegotiatedFormat::accepted(&__req)?; })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        // Callers over the route's rate limit are responded to with `429 Too Many Requests`
+        let rate_limit = self
+            .0
+            .check_rate_limit(Some(quote! { &__req }))
+            .map(|check| quote! { #check.await?; });
+        let handler = quote! {
+            async fn #fn_name ( __deadline: phalanx::deadline::Deadline, __req: phalanx::reexports::HttpRequest, server: phalanx::reexports::web::Data<#server_type>, #path_args #payload_arg ) -> Result<#ret_type, phalanx::reexports::Error> {
+                #accepted
+                #rate_limit
+                #permit
+                #payload
+                #connection
+                phalanx::server::within(#deadline, async move {
+                    #payload_conversion
+                    #call
+                    #ret_trailer
+                }).await
+            }
+        };
 
         let stream = quote! {
             #(#attrs)*
-            async fn #fn_name ( server: phalanx::reexports::web::Data<#server_type>, #path_args #payload_arg ) #ret_type {
-                let res = server.into_inner(). #fn_name ( #(#arg_names),* ).await;
-                #ret_trailer
+            #handler
+
+            let __resource = phalanx::reexports::Resource::new(#route)
+                .name(#fn_name_str)
+                .guard(phalanx::reexports::guard:: #method ())
+                .to(#fn_name);
+            __config.service(__resource);
+        };
+
+        tokens.extend(stream);
+    }
+}
+
+impl ServerRoute {
+    /// WebSocket routes are passed the incoming messages once the connection is accepted
+    fn ws_to_tokens(&self, tokens: &mut TokenStream2) {
+        let args = super::split_args(&self.0.args);
+        let arg_names = args.iter().map(|(ident, _)| ident);
+
+        let fn_name = &self.0.ident;
+        let fn_name_str = &self.0.ident.to_string();
+        let server_type = &self.0.server_type;
+        let route = &self.0.route_attr.route;
+        let method = self.0.route_attr.method_ident();
+        let attrs = &self.0.attrs;
+
+        let path_args = super::split_args(&self.0.path_args);
+        let path_args = if !path_args.is_empty() {
+            let arg_names = path_args.iter().map(|(ident, _)| ident);
+            let arg_types = path_args.iter().map(|(_, ty)| ty);
+            quote! { phalanx::reexports::web::Path(( #(#arg_names),* )): phalanx::reexports::web::Path<( #(#arg_types),* )>, }
+        } else {
+            quote! {}
+        };
+
+        let incoming = self
+            .0
+            .payload_arg
+            .as_ref()
+            .map(|arg| super::split_args(std::slice::from_ref(arg))[0].0);
+
+        let stream = quote! {
+            #(#attrs)*
+            async fn #fn_name (
+                server: phalanx::reexports::web::Data<#server_type>,
+                #path_args
+                __req: phalanx::reexports::HttpRequest,
+                __payload: phalanx::reexports::web::Payload,
+            ) -> Result<phalanx::reexports::HttpResponse, phalanx::reexports::Error> {
+                phalanx::web::websocket::serve(&__req, __payload, move |#incoming| async move {
+                    server.into_inner(). #fn_name ( #(#arg_names),* ).await
+                })
             }
 
             let __resource = phalanx::reexports::Resource::new(#route)
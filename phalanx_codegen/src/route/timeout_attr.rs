@@ -0,0 +1,42 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    Ident, LitInt, Token,
+};
+
+/// The arguments to `#[timeout(ms = 500)]`
+#[derive(Clone)]
+pub struct TimeoutAttr {
+    millis: LitInt,
+}
+
+impl TimeoutAttr {
+    /// The route's timeout, as a `std::time::Duration`
+    pub fn duration(&self) -> TokenStream2 {
+        let millis = &self.millis;
+        quote! { std::time::Duration::from_millis(#millis) }
+    }
+}
+
+impl Parse for TimeoutAttr {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key: Ident = input.parse()?;
+        if key != "ms" {
+            return Err(syn::Error::new_spanned(key, "Expected `ms`"));
+        }
+        input.parse::<Token![=]>()?;
+        let millis: LitInt = input.parse()?;
+        if millis.base10_parse::<u64>()? == 0 {
+            return Err(syn::Error::new_spanned(
+                millis,
+                "Timeouts must be at least one millisecond",
+            ));
+        }
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+        }
+
+        Ok(TimeoutAttr { millis })
+    }
+}
@@ -1,14 +1,24 @@
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
 
-use syn::{parse_macro_input, Error, ImplItem, ItemImpl, Type};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Error, ImplItem, ItemImpl, LitStr, Token, Type,
+};
 
-use crate::route::{client::ClientRoute, server::ServerRoute, Route};
+use crate::route::{
+    client::ClientRoute,
+    jsonrpc::JsonRpcRoute,
+    proto::{ProtoRoute, SkippedProtoRoute},
+    server::ServerRoute,
+    ConcurrencyAttr, Route,
+};
 
 /// Wrapper for a single service
 pub struct Service {
     server: ServerService,
     client: ClientService,
+    json_rpc: Option<JsonRpcService>,
     parsed_impl: ItemImpl,
 }
 
@@ -28,56 +38,187 @@ impl Service {
     }
 
     fn new(attr: TokenStream, parsed_impl: ItemImpl) -> Result<Self, Error> {
+        let attr: ServiceAttr = syn::parse(attr)?;
         validate_impl(&parsed_impl)?;
-        let routes = parse_routes(&parsed_impl)?;
+        let mut routes = parse_routes(
+            &parsed_impl,
+            attr.format.as_ref(),
+            attr.concurrency.as_ref(),
+        )?;
+        crate::route::link_batches(&mut routes)?;
 
         let client_routes: Vec<ClientRoute> = routes
             .iter()
             .map(|route| ClientRoute::from(route.clone()))
             .collect();
 
+        // Only routes sending protobuf messages can be described as RPCs, and
+        // the rest are listed as left out
+        let mut proto_routes = Vec::new();
+        let mut skipped_proto_routes = Vec::new();
+        for route in routes.iter() {
+            match ProtoRoute::new(route.clone()) {
+                Some(proto_route) => proto_routes.push(proto_route),
+                None => skipped_proto_routes.push(SkippedProtoRoute::from(route.clone())),
+            }
+        }
+
+        // Routes which can't be sent as JSON are left out of JSON-RPC and batches
+        let json_rpc_routes: Vec<JsonRpcRoute> = routes
+            .iter()
+            .filter(|route| route.is_json_rpc())
+            .map(|route| JsonRpcRoute::from(route.clone()))
+            .collect();
+
         let server_routes: Vec<ServerRoute> = routes.into_iter().map(ServerRoute::from).collect();
 
         let server_type = parsed_impl.self_ty.as_ref();
 
         Ok(Service {
-            server: ServerService::new(server_routes, server_type.clone()),
-            client: ClientService::from_attr(attr, client_routes)?,
+            server: ServerService::new(
+                server_routes,
+                proto_routes,
+                skipped_proto_routes,
+                server_type.clone(),
+                attr.batch,
+            ),
+            json_rpc: if attr.rpc.is_some() || attr.batch {
+                Some(JsonRpcService {
+                    rpc_client: attr.rpc,
+                    batch_client: if attr.batch {
+                        Some(attr.client.clone())
+                    } else {
+                        None
+                    },
+                    routes: json_rpc_routes,
+                    server_type: server_type.clone(),
+                })
+            } else {
+                None
+            },
+            client: ClientService::new(attr.client, client_routes),
             parsed_impl,
         })
     }
 }
 
-struct ClientService {
-    ty: Type,
-    routes: Vec<ClientRoute>,
+/// The arguments to `#[phalanx(MyClient, format = "msgpack", rpc = MyRpcClient, batch, concurrency(max = 16))]`
+struct ServiceAttr {
+    client: Type,
+    /// Path to the wrapper type structured payloads and returns are sent in
+    format: Option<syn::Path>,
+    /// Client calling the service over JSON-RPC, if it's served over JSON-RPC
+    rpc: Option<Type>,
+    /// Whether the service mounts a `/_batch` endpoint, and its client a batch builder
+    batch: bool,
+    /// The concurrency limit of routes without their own
+    concurrency: Option<ConcurrencyAttr>,
 }
 
-impl ClientService {
-    fn from_attr(attr: TokenStream, client_routes: Vec<ClientRoute>) -> Result<Self, Error> {
-        let client_type: Type = syn::parse(attr).map_err(|err| {
+impl Parse for ServiceAttr {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let client: Type = input.parse().map_err(|err| {
             syn::Error::new(
                 err.span(),
                 "phalanx requires a client type, i.e. `#[phalanx(MyClient)]`",
             )
         })?;
+
+        let mut format = None;
+        let mut rpc = None;
+        let mut batch = false;
+        let mut concurrency = None;
+        while input.parse::<Option<Token![,]>>()?.is_some() {
+            if input.is_empty() {
+                break;
+            }
+            let key: syn::Ident = input.parse()?;
+            if key == "batch" {
+                batch = true;
+                continue;
+            }
+            if key == "concurrency" {
+                let content;
+                syn::parenthesized!(content in input);
+                concurrency = Some(content.parse()?);
+                continue;
+            }
+            if key == "rpc" {
+                input.parse::<Token![=]>()?;
+                rpc = Some(input.parse()?);
+                continue;
+            }
+            if key != "format" {
+                return Err(syn::Error::new_spanned(
+                    key,
+                    "Expected a format, JSON-RPC client, `batch` or concurrency limit, i.e. `#[phalanx(MyClient, format = \"msgpack\", rpc = MyRpcClient, batch, concurrency(max = 16))]`",
+                ));
+            }
+            input.parse::<Token![=]>()?;
+            let name: LitStr = input.parse()?;
+            let wrapper = match name.value().as_str() {
+                "json" => "Json",
+                "cbor" => "Cbor",
+                "msgpack" => "MsgPack",
+                "bincode" => "Bincode",
+                "negotiated" => "Negotiated",
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        name,
+                        "Unknown format, expected one of \"json\", \"cbor\", \"msgpack\", \"bincode\" or \"negotiated\"",
+                    ))
+                }
+            };
+            let wrapper = syn::Ident::new(wrapper, name.span());
+            format = Some(syn::parse_quote! { phalanx::web::#wrapper });
+        }
+
         Ok(Self {
+            client,
+            format,
+            rpc,
+            batch,
+            concurrency,
+        })
+    }
+}
+
+struct ClientService {
+    ty: Type,
+    routes: Vec<ClientRoute>,
+}
+
+impl ClientService {
+    fn new(client_type: Type, client_routes: Vec<ClientRoute>) -> Self {
+        Self {
             ty: client_type,
             routes: client_routes,
-        })
+        }
     }
 }
 
 struct ServerService {
     routes: Vec<ServerRoute>,
+    proto_routes: Vec<ProtoRoute>,
+    skipped_proto_routes: Vec<SkippedProtoRoute>,
     server_type: Type,
+    batch: bool,
 }
 
 impl ServerService {
-    fn new(server_routes: Vec<ServerRoute>, server_type: Type) -> Self {
+    fn new(
+        server_routes: Vec<ServerRoute>,
+        proto_routes: Vec<ProtoRoute>,
+        skipped_proto_routes: Vec<SkippedProtoRoute>,
+        server_type: Type,
+        batch: bool,
+    ) -> Self {
         Self {
             routes: server_routes,
+            proto_routes,
+            skipped_proto_routes,
             server_type,
+            batch,
         }
     }
 }
@@ -87,6 +228,7 @@ impl ToTokens for Service {
         let parsed_impl = &self.parsed_impl;
         let server = &self.server;
         let client = &self.client;
+        let json_rpc = &self.json_rpc;
 
         tokens.extend(quote! {
             #parsed_impl
@@ -94,6 +236,8 @@ impl ToTokens for Service {
             #server
 
             #client
+
+            #json_rpc
         });
     }
 }
@@ -114,15 +258,136 @@ impl ToTokens for ClientService {
 impl ToTokens for ServerService {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         let routes = &self.routes;
+        let proto_routes = &self.proto_routes;
+        let skipped_proto_routes = &self.skipped_proto_routes;
         let server_type = &self.server_type;
+        let server_name = type_name(server_type);
+
+        let batch = if self.batch {
+            quote! { phalanx::batch::mount::<Self>(__config); }
+        } else {
+            quote! {}
+        };
+
+        // phalanx enables this crate's `protobuf` feature along with its own
+        let proto_service = if cfg!(feature = "protobuf") {
+            quote! {
+                impl phalanx::proto::ProtoService for #server_type {
+                    fn name() -> &'static str {
+                        #server_name
+                    }
+
+                    fn rpcs() -> Vec<phalanx::proto::Rpc> {
+                        vec![#(#proto_routes),*]
+                    }
+
+                    fn skipped() -> Vec<phalanx::proto::SkippedRoute> {
+                        vec![#(#skipped_proto_routes),*]
+                    }
+                }
+            }
+        } else {
+            quote! {}
+        };
 
         tokens.extend(quote! {
             impl phalanx::server::PhalanxServer for #server_type {
                 fn mount(__config: &mut phalanx::reexports::web::ServiceConfig) {
                     #(#routes)*
+                    #batch
+                }
+            }
+
+            #proto_service
+        });
+    }
+}
+
+/// A service whose routes can be called by name, over JSON-RPC or in batches
+struct JsonRpcService {
+    rpc_client: Option<Type>,
+    /// The service's client, if it gets a batch builder
+    batch_client: Option<Type>,
+    routes: Vec<JsonRpcRoute>,
+    server_type: Type,
+}
+
+impl JsonRpcService {
+    /// The `MyClientBatch` builder, and the `batch` methods of `MyClient` starting one
+    fn batch_builder(&self, client_type: &Type) -> proc_macro2::TokenStream {
+        let batch = quote::format_ident!("{}Batch", type_name(client_type));
+        let methods = self.routes.iter().map(|route| route.batch_method(&batch));
+
+        quote! {
+            pub struct #batch<'a, P>(phalanx::batch::Batch<'a, P>);
+
+            impl #client_type {
+                /// Start a batch of calls, whose results are returned as a tuple
+                pub fn batch(&self) -> #batch<'_, ()> {
+                    let __client = phalanx::client::PhalanxClient::client(self);
+                    #batch(phalanx::batch::Batch::new(__client, ()))
+                }
+
+                /// Start a batch of calls returning the same type, whose results are returned as a `Vec`
+                pub fn batch_all<R>(&self) -> #batch<'_, Vec<phalanx::batch::Pending<R>>> {
+                    let __client = phalanx::client::PhalanxClient::client(self);
+                    #batch(phalanx::batch::Batch::new(__client, Vec::new()))
+                }
+            }
+
+            impl<'a, P> #batch<'a, P> {
+                #(#methods)*
+
+                pub async fn send(self) -> Result<P::Results, Box<dyn std::error::Error>>
+                where
+                    P: phalanx::batch::Resolve,
+                {
+                    Ok(self.0.send().await?)
+                }
+            }
+        }
+    }
+}
+
+impl ToTokens for JsonRpcService {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let server_type = &self.server_type;
+        let arms = self.routes.iter().map(JsonRpcRoute::server_arm);
+
+        let rpc_client = self.rpc_client.as_ref().map(|client_type| {
+            let methods = self.routes.iter().map(JsonRpcRoute::client_method);
+            quote! {
+                impl #client_type {
+                    #(#methods)*
                 }
             }
         });
+        let batch_builder = self
+            .batch_client
+            .as_ref()
+            .map(|client_type| self.batch_builder(client_type));
+
+        tokens.extend(quote! {
+            impl phalanx::jsonrpc::JsonRpcService for #server_type {
+                fn call(
+                    self: std::sync::Arc<Self>,
+                    __method: String,
+                    __params: phalanx::jsonrpc::Params,
+                ) -> phalanx::jsonrpc::CallFuture {
+                    let __server = self;
+                    Box::pin(async move {
+                        match __method.as_str() {
+                            #(#arms)*
+                            _ => Err(phalanx::jsonrpc::RpcError::method_not_found(&__method)),
+                        }
+                    })
+                }
+            }
+
+            #rpc_client
+
+            #batch_builder
+        });
     }
 }
 
@@ -144,16 +409,22 @@ fn validate_impl(parsed_impl: &ItemImpl) -> syn::Result<()> {
     Ok(())
 }
 
-fn parse_routes(parsed_impl: &ItemImpl) -> syn::Result<Vec<Route>> {
+fn parse_routes(
+    parsed_impl: &ItemImpl,
+    format: Option<&syn::Path>,
+    concurrency: Option<&ConcurrencyAttr>,
+) -> syn::Result<Vec<Route>> {
     let mut routes: Vec<Route> = Vec::new();
     let server_type = parsed_impl.self_ty.as_ref();
 
     for item in &parsed_impl.items {
         match item {
-            ImplItem::Method(method) => match Route::new(method, server_type) {
-                Ok(route) => routes.push(route),
-                Err(err) => return Err(err),
-            },
+            ImplItem::Method(method) => {
+                match Route::new(method, server_type, format, concurrency) {
+                    Ok(route) => routes.push(route),
+                    Err(err) => return Err(err),
+                }
+            }
             ImplItem::Type(assoc_type) => {
                 return Err(syn::Error::new_spanned(
                     &assoc_type,
@@ -166,3 +437,16 @@ fn parse_routes(parsed_impl: &ItemImpl) -> syn::Result<Vec<Route>> {
 
     Ok(routes)
 }
+
+/// The name of a type, ignoring its module path and generic arguments
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string())
+            .unwrap_or_default(),
+        ty => ty.to_token_stream().to_string(),
+    }
+}
@@ -71,6 +71,17 @@ mod json {
             println!("Path: {:?} Payload: {:?}", path, data);
             web::Json(data)
         }
+
+        #[post("/extract/{path}")]
+        async fn extract(
+            &self,
+            path: i32,
+            payload: web::Json<SimpleData>,
+        ) -> web::Json<SimpleData> {
+            web::Json(SimpleData {
+                data: payload.data + path,
+            })
+        }
     }
 
     // Verify the code compiles and the client methods are added
@@ -78,4 +89,2909 @@ mod json {
         let client = JsonClient(Client::url("http://localhost:8080"));
         let _future = client.index(0, web::Json(SimpleData { data: 0i32 }));
     }
+
+    #[test]
+    fn extraction() {
+        use actix_web::{test, App};
+        use phalanx::{prelude::PhalanxMount, reexports::http::StatusCode};
+
+        phalanx::reexports::rt::System::new("extraction").block_on(async {
+            let app = App::new()
+                .app_data(actix_web::web::JsonConfig::default().limit(16))
+                .phalanx_mount(JsonServer);
+            let mut app = test::init_service(app).await;
+
+            // Payloads are extracted by actix, accepting JSON subtypes
+            let req = test::TestRequest::post()
+                .uri("/extract/1")
+                .header("content-type", "application/merge-patch+json")
+                .set_payload(r#"{"data":1}"#)
+                .to_request();
+            assert_eq!(
+                test::call_service(&mut app, req).await.status(),
+                StatusCode::OK
+            );
+
+            // and limited by JsonConfig
+            let req = test::TestRequest::post()
+                .uri("/extract/1")
+                .header("content-type", "application/json")
+                .set_payload(r#"{"data":1234567890}"#)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        });
+    }
+}
+
+mod form {
+    use super::*;
+    use phalanx::web;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct SimpleData {
+        data: i32,
+    }
+
+    #[derive(Clone)]
+    struct FormServer;
+
+    #[derive(PhalanxClient)]
+    struct FormClient(#[client] Client);
+
+    #[phalanx(FormClient)]
+    impl FormServer {
+        #[post("/{path}")]
+        async fn index(&self, path: i32, payload: web::Form<SimpleData>) -> web::Form<SimpleData> {
+            let data = payload.into_inner();
+            println!("Path: {:?} Payload: {:?}", path, data);
+            web::Form(data)
+        }
+    }
+
+    // Verify the code compiles and the client methods are added
+    fn _test() {
+        let client = FormClient(Client::url("http://localhost:8080"));
+        let _future = client.index(0, web::Form(SimpleData { data: 0i32 }));
+    }
+}
+
+mod binary {
+    use super::*;
+    use phalanx::web::{Binary, Bytes};
+
+    #[derive(Clone)]
+    struct BinaryServer;
+
+    #[derive(PhalanxClient)]
+    struct BinaryClient(#[client] Client);
+
+    #[phalanx(BinaryClient)]
+    impl BinaryServer {
+        #[post("/vec")]
+        async fn vec(&self, payload: Vec<u8>) -> Vec<u8> {
+            payload
+        }
+
+        #[post("/bytes")]
+        async fn bytes(&self, payload: Bytes) -> Bytes {
+            payload
+        }
+
+        #[post("/binary")]
+        async fn binary(&self, payload: Binary) -> Binary {
+            Binary::new(payload.into_inner()).with_content_type("image/png")
+        }
+    }
+
+    // Verify the code compiles and the client methods are added
+    fn _test() {
+        let client = BinaryClient(Client::url("http://localhost:8080"));
+        let _future = client.vec(vec![0u8; 16]);
+        let _future = client.bytes(Bytes::from_static(b"bytes"));
+        let _future = client.binary(Binary::new(vec![0u8; 16]).with_content_type("image/png"));
+    }
+}
+
+mod text {
+    use super::*;
+    use phalanx::web::Text;
+
+    #[derive(Clone)]
+    struct TextServer;
+
+    #[derive(PhalanxClient)]
+    struct TextClient(#[client] Client);
+
+    #[phalanx(TextClient)]
+    impl TextServer {
+        #[get("/count")]
+        async fn count(&self) -> u64 {
+            0
+        }
+
+        #[post("/{path}")]
+        async fn scale(&self, path: i32, factor: f64) -> f64 {
+            path as f64 * factor
+        }
+
+        #[post("/text")]
+        async fn text(&self, payload: Text<std::net::Ipv4Addr>) -> Text<bool> {
+            Text(payload.is_loopback())
+        }
+    }
+
+    // Verify scalars are sent and received as plain values
+    async fn _test() {
+        let client = TextClient(Client::url("http://localhost:8080"));
+        let _count: Result<u64, _> = client.count().await;
+        let _scale: Result<f64, _> = client.scale(0, 0.5).await;
+        let _future = client.text(Text(std::net::Ipv4Addr::LOCALHOST));
+    }
+
+    // Verify parse errors can be sent between threads
+    fn _send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<phalanx::web::TextParseError>();
+    }
+
+    // Answers `count` with text which isn't a number
+    #[derive(Clone)]
+    struct WordServer;
+
+    #[derive(PhalanxClient)]
+    struct WordClient(#[client] Client);
+
+    #[phalanx(WordClient)]
+    impl WordServer {
+        #[get("/count")]
+        async fn count(&self) -> String {
+            String::from("many")
+        }
+    }
+
+    #[test]
+    fn parse_error() {
+        use actix_web::{test, App};
+        use phalanx::{client::PhalanxClientError, prelude::PhalanxMount};
+
+        phalanx::reexports::rt::System::new("parse_error").block_on(async {
+            let srv = test::start(|| App::new().phalanx_mount(WordServer));
+            let client = TextClient(Client::url(&format!("http://{}", srv.addr())));
+            let err = client.count().await.unwrap_err();
+            let err = match err.downcast_ref::<PhalanxClientError>() {
+                Some(PhalanxClientError::TextParseError(err)) => err,
+                _ => panic!("expected a parse error, got {}", err),
+            };
+            assert_eq!(err.to_string(), "could not parse \"many\" as u64");
+        });
+    }
+}
+
+mod multipart {
+    use super::*;
+    use phalanx::web::{
+        self,
+        multipart::{File, MultipartForm},
+        Bytes,
+    };
+
+    #[derive(Debug, MultipartForm)]
+    struct Attachment {
+        title: String,
+        caption: Option<String>,
+        #[multipart(max_count = 2)]
+        thumbnails: Vec<File>,
+        // Streamed, as the last part
+        #[multipart(limit = 1048576)]
+        image: File,
+    }
+
+    #[derive(Clone)]
+    struct MultipartServer;
+
+    #[derive(PhalanxClient)]
+    struct MultipartClient(#[client] Client);
+
+    #[phalanx(MultipartClient)]
+    impl MultipartServer {
+        #[post("/{path}")]
+        async fn index(&self, path: i32, payload: web::Multipart<Attachment>) -> String {
+            let attachment = payload.into_inner();
+            let size = attachment
+                .image
+                .bytes()
+                .await
+                .map_or(0, |image| image.len());
+            format!("{} {}: {} bytes", path, attachment.title, size)
+        }
+    }
+
+    // Verify the code compiles and the client methods are added
+    fn _test() {
+        let client = MultipartClient(Client::url("http://localhost:8080"));
+        let _future = client.index(
+            0,
+            web::Multipart(Attachment {
+                title: "title".into(),
+                caption: None,
+                thumbnails: vec![File::new(vec![0u8; 16])],
+                image: File::stream(futures::stream::iter(vec![Ok::<_, std::io::Error>(
+                    Bytes::from(vec![0u8; 16]),
+                )]))
+                .file_name("image.png")
+                .content_type("image/png"),
+            }),
+        );
+    }
+
+    fn part(name: &str, data: &str) -> String {
+        format!(
+            "--boundary\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n{}\r\n",
+            name, name, data
+        )
+    }
+
+    #[test]
+    fn file_before_text() {
+        use actix_web::{test, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("file_before_text").block_on(async {
+            let mut app = test::init_service(App::new().phalanx_mount(MultipartServer)).await;
+
+            // The image is read into memory, as the title and thumbnail follow it
+            let body = [
+                part("image", "image"),
+                part("title", "title"),
+                part("thumbnails", "thumbnail"),
+                String::from("--boundary--\r\n"),
+            ]
+            .concat();
+            let req = test::TestRequest::post()
+                .uri("/1")
+                .header("content-type", "multipart/form-data; boundary=boundary")
+                .set_payload(body)
+                .to_request();
+            let body = test::read_response(&mut app, req).await;
+            assert_eq!(body, "1 title: 5 bytes");
+        });
+    }
+
+    #[test]
+    fn optional_fields_left_out() {
+        use actix_web::test;
+        use phalanx::reexports::FromRequest;
+
+        phalanx::reexports::rt::System::new("optional_fields_left_out").block_on(async {
+            // The image is streamed without waiting for the caption or thumbnails
+            let body = [
+                part("title", "title"),
+                part("image", "image"),
+                String::from("--boundary--\r\n"),
+            ]
+            .concat();
+            let (req, mut payload) = test::TestRequest::post()
+                .header("content-type", "multipart/form-data; boundary=boundary")
+                .set_payload(body)
+                .to_http_parts();
+            let attachment = web::Multipart::<Attachment>::from_request(&req, &mut payload)
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(format!("{:?}", attachment.image).contains("stream"));
+            assert_eq!(attachment.image.bytes().await.unwrap(), "image");
+        });
+    }
+
+    #[test]
+    fn too_many_parts() {
+        use actix_web::{http::StatusCode, test, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("too_many_parts").block_on(async {
+            let mut app = test::init_service(App::new().phalanx_mount(MultipartServer)).await;
+
+            let body = [
+                part("title", "title"),
+                part("thumbnails", "thumbnail"),
+                part("thumbnails", "thumbnail"),
+                part("thumbnails", "thumbnail"),
+                part("image", "image"),
+                String::from("--boundary--\r\n"),
+            ]
+            .concat();
+            let req = test::TestRequest::post()
+                .uri("/1")
+                .header("content-type", "multipart/form-data; boundary=boundary")
+                .set_payload(body)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        });
+    }
+}
+
+mod result {
+    use super::*;
+    use phalanx::reexports::ResponseError;
+
+    #[derive(Debug)]
+    struct NotFound;
+
+    impl std::fmt::Display for NotFound {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "not found")
+        }
+    }
+
+    impl ResponseError for NotFound {}
+
+    #[derive(Clone)]
+    struct ResultServer;
+
+    #[derive(PhalanxClient)]
+    struct ResultClient(#[client] Client);
+
+    #[phalanx(ResultClient)]
+    impl ResultServer {
+        #[get("/{path}")]
+        async fn index(&self, path: i32) -> Result<String, NotFound> {
+            match path {
+                0 => Err(NotFound),
+                _ => Ok(format!("{}", path)),
+            }
+        }
+
+        #[post("/{path}")]
+        async fn unit(&self, path: i32) -> Result<(), NotFound> {
+            println!("Path: {:?}", path);
+            Ok(())
+        }
+    }
+
+    // Verify the client methods unwrap the result type
+    async fn _test() {
+        let client = ResultClient(Client::url("http://localhost:8080"));
+        let _index: Result<String, _> = client.index(0).await;
+        let _unit: Result<(), _> = client.unit(0).await;
+    }
+}
+
+mod transactional {
+    use super::*;
+    use actix_web::{test, App};
+    use diesel::{
+        dsl::sql, r2d2::ConnectionManager, result::Error as DieselError, select, sql_query,
+        sql_types::BigInt, RunQueryDsl, SqliteConnection,
+    };
+    use phalanx::{
+        diesel::{transaction, DbConnection, DbError, DbPool},
+        prelude::PhalanxMount,
+        reexports::{http::StatusCode, ResponseError},
+    };
+    use phalanx_codegen::transactional;
+
+    type Conn = DbConnection<SqliteConnection>;
+
+    #[derive(Clone)]
+    struct PostServer;
+
+    #[derive(PhalanxClient)]
+    struct PostClient(#[client] Client);
+
+    #[phalanx(PostClient)]
+    impl PostServer {
+        // Negative ids are saved, then rolled back with a `404 Not Found`
+        #[transactional]
+        #[post("/posts/{id}")]
+        async fn create(&self, conn: Conn, id: i32) -> Result<(), DbError> {
+            conn.run(move |conn| insert(conn, id)).await?;
+            if id < 0 {
+                return Err(DieselError::NotFound.into());
+            }
+            Ok(())
+        }
+    }
+
+    // Verify the client doesn't send the connection
+    async fn _test() {
+        let client = PostClient(Client::url("http://localhost:8080"));
+        let _: Result<(), _> = client.create(1).await;
+    }
+
+    /// A pool of one in-memory database, as each connection opens its own
+    fn pool() -> DbPool<SqliteConnection> {
+        let pool = DbPool::builder()
+            .max_size(1)
+            .build(ConnectionManager::new(":memory:"))
+            .unwrap();
+        sql_query("CREATE TABLE posts (id INTEGER PRIMARY KEY)")
+            .execute(&*pool.get().unwrap())
+            .unwrap();
+        pool
+    }
+
+    fn insert(conn: &SqliteConnection, id: i32) -> Result<usize, DieselError> {
+        sql_query(format!("INSERT INTO posts (id) VALUES ({})", id)).execute(conn)
+    }
+
+    fn count(pool: &DbPool<SqliteConnection>) -> i64 {
+        select(sql::<BigInt>("COUNT(*) FROM posts"))
+            .get_result(&*pool.get().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn statuses() {
+        let not_found = DbError::DieselError(DieselError::NotFound);
+        assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+
+        let conn = pool().get().unwrap();
+        insert(&conn, 1).unwrap();
+        let conflict = DbError::DieselError(insert(&conn, 1).unwrap_err());
+        assert_eq!(conflict.status_code(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn commit_and_rollback() {
+        phalanx::reexports::rt::System::new("commit_and_rollback").block_on(async {
+            let pool = pool();
+
+            let conn = Conn::get(&pool).await.unwrap();
+            let res: Result<_, DbError> = transaction(conn, |conn| async move {
+                conn.run(|conn| insert(conn, 1)).await
+            })
+            .await;
+            assert!(res.is_ok());
+            assert_eq!(count(&pool), 1);
+
+            let conn = Conn::get(&pool).await.unwrap();
+            let res: Result<(), DbError> = transaction(conn, |conn| async move {
+                conn.run(|conn| insert(conn, 2)).await?;
+                Err(DieselError::NotFound.into())
+            })
+            .await;
+            assert!(matches!(
+                res,
+                Err(DbError::DieselError(DieselError::NotFound))
+            ));
+            assert_eq!(count(&pool), 1);
+
+            // Transactions dropped before they finish are rolled back
+            let conn = Conn::get(&pool).await.unwrap();
+            let (inserted_tx, inserted) = futures::channel::oneshot::channel();
+            let mut cancelled = Box::pin(transaction(conn, |conn| async move {
+                conn.run(|conn| insert(conn, 3)).await?;
+                let _ = inserted_tx.send(());
+                futures::future::pending::<Result<(), DbError>>().await
+            }));
+            futures::future::select(cancelled.as_mut(), inserted).await;
+            drop(cancelled);
+            assert_eq!(count(&pool), 1);
+
+            // Failed rollbacks are reported in place of the route's error
+            let conn = Conn::get(&pool).await.unwrap();
+            let res: Result<(), DbError> = transaction(conn, |conn| async move {
+                conn.run(|conn| sql_query("ROLLBACK").execute(conn)).await?;
+                Err(DieselError::NotFound.into())
+            })
+            .await;
+            assert!(matches!(
+                res,
+                Err(DbError::DieselError(ref err)) if !matches!(err, DieselError::NotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn rollback_after_panic() {
+        phalanx::reexports::rt::System::new("rollback_after_panic").block_on(async {
+            let pool = pool();
+
+            // Queries which panic leave the connection usable for the rollback
+            let conn = Conn::get(&pool).await.unwrap();
+            let res: Result<(), DbError> = transaction(conn, |conn| async move {
+                conn.run(|conn| insert(conn, 1)).await?;
+                conn.run(|_| -> Result<(), DbError> { panic!("query panicked") })
+                    .await
+            })
+            .await;
+            assert!(matches!(res, Err(DbError::Canceled)));
+            assert_eq!(count(&pool), 0);
+        });
+    }
+
+    #[test]
+    fn route() {
+        phalanx::reexports::rt::System::new("route").block_on(async {
+            let pool = pool();
+            let app = App::new().data(pool.clone()).phalanx_mount(PostServer);
+            let mut app = test::init_service(app).await;
+            for (id, status) in &[
+                (1, StatusCode::OK),
+                (1, StatusCode::CONFLICT),
+                (-1, StatusCode::NOT_FOUND),
+            ] {
+                let req = test::TestRequest::post()
+                    .uri(&format!("/posts/{}", id))
+                    .to_request();
+                assert_eq!(test::call_service(&mut app, req).await.status(), *status);
+            }
+            assert_eq!(count(&pool), 1);
+        });
+    }
+}
+
+mod format {
+    use super::*;
+    use phalanx::web::{Bincode, Cbor};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Post {
+        title: String,
+        body: String,
+    }
+
+    #[derive(Clone)]
+    struct FormatServer;
+
+    #[derive(PhalanxClient)]
+    struct FormatClient(#[client] Client);
+
+    #[phalanx(FormatClient, format = "msgpack")]
+    impl FormatServer {
+        #[get("/{id}")]
+        async fn read(&self, id: u32) -> Post {
+            Post {
+                title: format!("{}", id),
+                body: String::new(),
+            }
+        }
+
+        #[post("/")]
+        async fn create(&self, post: Post) -> Result<u32, std::io::Error> {
+            println!("Post: {:?}", post);
+            Ok(0)
+        }
+
+        #[post("/{id}/edit")]
+        async fn update(&self, id: u32, post: Cbor<Post>) -> Bincode<Post> {
+            println!("Id: {:?}", id);
+            Bincode(post.into_inner())
+        }
+
+        #[get("/")]
+        async fn list(&self) -> Result<Vec<Post>, std::io::Error> {
+            Ok(Vec::new())
+        }
+    }
+
+    // Verify structured values are sent in the service's format, and explicit wrappers as written
+    async fn _test() {
+        let client = FormatClient(Client::url("http://localhost:8080"));
+        let post = || Post {
+            title: String::new(),
+            body: String::new(),
+        };
+        let _read: Result<Post, _> = client.read(0).await;
+        let _create: Result<u32, _> = client.create(post()).await;
+        let _update: Result<Bincode<Post>, _> = client.update(0, Cbor(post())).await;
+        let _list: Result<Vec<Post>, _> = client.list().await;
+    }
+
+    #[test]
+    fn bincode_limit() {
+        use phalanx::web::{BincodeFormat, Format};
+
+        let post = Post {
+            title: String::from("title"),
+            body: String::new(),
+        };
+        let encoded = BincodeFormat::encode(&post).unwrap();
+        let decoded: Post = BincodeFormat::decode(&encoded).unwrap();
+        assert_eq!(decoded.title, "title");
+
+        // Lengths longer than the payload are rejected before they're allocated
+        let huge = BincodeFormat::decode::<Vec<u8>>(&u64::MAX.to_le_bytes());
+        assert!(huge.is_err());
+    }
+}
+
+mod negotiated {
+    use super::*;
+    use phalanx::web::Negotiated;
+    use serde::{Deserialize, Serialize};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Post {
+        title: String,
+    }
+
+    /// How many posts have been created
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Clone)]
+    struct NegotiatedServer;
+
+    #[derive(PhalanxClient)]
+    struct NegotiatedClient(#[client] Client);
+
+    #[phalanx(NegotiatedClient, format = "negotiated")]
+    impl NegotiatedServer {
+        #[get("/{id}")]
+        async fn read(&self, id: u32) -> Post {
+            Post {
+                title: format!("{}", id),
+            }
+        }
+
+        #[post("/")]
+        async fn create(&self, post: Negotiated<Post>) -> Negotiated<Post> {
+            CREATED.fetch_add(1, Ordering::SeqCst);
+            post
+        }
+    }
+
+    // Verify negotiated values are sent and received as written
+    async fn _test() {
+        let client = NegotiatedClient(Client::url("http://localhost:8080"));
+        let _read: Result<Post, _> = client.read(0).await;
+        let _create: Result<Negotiated<Post>, _> = client
+            .create(Negotiated(Post {
+                title: String::new(),
+            }))
+            .await;
+    }
+
+    #[test]
+    fn negotiation() {
+        use actix_web::{test, App};
+        use phalanx::{
+            prelude::PhalanxMount,
+            reexports::http::StatusCode,
+            web::{CborFormat, Format, NegotiatedFormat, NegotiationConfig},
+        };
+
+        phalanx::reexports::rt::System::new("negotiation").block_on(async {
+            let config = NegotiationConfig::default()
+                .formats(&[NegotiatedFormat::Cbor, NegotiatedFormat::Json]);
+            let app = App::new().app_data(config).phalanx_mount(NegotiatedServer);
+            let mut app = test::init_service(app).await;
+            let post = CborFormat::encode(&Post {
+                title: String::from("cbor"),
+            })
+            .unwrap();
+
+            // Payloads without a content type are in the preferred format
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_payload(post.clone())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get("content-type").unwrap(),
+                "application/cbor"
+            );
+
+            let accept = |accept: &'static str| {
+                test::TestRequest::post()
+                    .uri("/")
+                    .header("accept", accept)
+                    .header("content-type", "application/cbor")
+                    .set_payload(post.clone())
+                    .to_request()
+            };
+
+            let res = test::call_service(&mut app, accept("application/json, */*;q=0")).await;
+            assert_eq!(
+                res.headers().get("content-type").unwrap(),
+                "application/json"
+            );
+
+            // Requests accepting no format are refused before the route runs
+            let created = CREATED.load(Ordering::SeqCst);
+            let res = test::call_service(&mut app, accept("text/html, */*;q=0")).await;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+            assert_eq!(CREATED.load(Ordering::SeqCst), created);
+
+            let res = test::call_service(
+                &mut app,
+                accept("application/cbor;q=0.2, application/json;q=0.5"),
+            )
+            .await;
+            assert_eq!(
+                res.headers().get("content-type").unwrap(),
+                "application/json"
+            );
+        });
+    }
+
+    #[test]
+    fn client() {
+        use actix_web::{test, App};
+        use phalanx::{
+            prelude::PhalanxMount,
+            web::{NegotiatedFormat, NegotiationConfig},
+        };
+
+        phalanx::reexports::rt::System::new("client").block_on(async {
+            // Payloads are sent as JSON, which servers accept without knowing the client
+            let srv = test::start(|| {
+                let config = NegotiationConfig::default().formats(&[NegotiatedFormat::Json]);
+                App::new().app_data(config).phalanx_mount(NegotiatedServer)
+            });
+            let client = NegotiatedClient(Client::url(&format!("http://{}", srv.addr())));
+            let post = client
+                .create(Negotiated(Post {
+                    title: String::from("json"),
+                }))
+                .await
+                .unwrap();
+            assert_eq!(post.title, "json");
+        });
+    }
+}
+
+mod custom_payload {
+    use super::*;
+    use phalanx::{
+        client::{PhalanxClientError, PhalanxPayload, PhalanxResponse},
+        reexports::{dev::Payload, HttpRequest, HttpResponse, RequestBuilder},
+    };
+    use std::{future::Future, pin::Pin};
+
+    /// A wire type defined outside of phalanx
+    struct Csv(Vec<String>);
+
+    impl PhalanxPayload for Csv {
+        type ExtractFuture = Pin<Box<dyn Future<Output = Result<Self, phalanx::reexports::Error>>>>;
+        type DecodeFuture = Pin<Box<dyn Future<Output = Result<Self, PhalanxClientError>>>>;
+
+        fn into_request(
+            self,
+            req: RequestBuilder,
+        ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+            Ok(req
+                .header("content-type", "text/csv")
+                .body(self.0.join("\n")))
+        }
+
+        fn extract(req: &HttpRequest, payload: &mut Payload) -> Self::ExtractFuture {
+            let bytes = phalanx::server::read_payload(req, payload, "text/csv");
+            Box::pin(async move {
+                let bytes = bytes.await?;
+                let text = String::from_utf8_lossy(&bytes);
+                Ok(Csv(text.lines().map(String::from).collect()))
+            })
+        }
+
+        fn decode(res: PhalanxResponse) -> Self::DecodeFuture {
+            Box::pin(async move {
+                let text = String::decode(res).await?;
+                Ok(Csv(text.lines().map(String::from).collect()))
+            })
+        }
+    }
+
+    impl phalanx::reexports::Responder for Csv {
+        type Error = phalanx::reexports::Error;
+        type Future = std::future::Ready<Result<HttpResponse, Self::Error>>;
+
+        fn respond_to(self, _: &HttpRequest) -> Self::Future {
+            std::future::ready(Ok(HttpResponse::Ok()
+                .content_type("text/csv")
+                .body(self.0.join("\n"))))
+        }
+    }
+
+    #[derive(Clone)]
+    struct CsvServer;
+
+    #[derive(PhalanxClient)]
+    struct CsvClient(#[client] Client);
+
+    #[phalanx(CsvClient)]
+    impl CsvServer {
+        #[post("/")]
+        async fn sort(&self, payload: Csv) -> Csv {
+            let mut rows = payload.0;
+            rows.sort();
+            Csv(rows)
+        }
+    }
+
+    // Verify payload types defined outside of phalanx work with generated clients
+    async fn _test() {
+        let client = CsvClient(Client::url("http://localhost:8080"));
+        let _sorted: Result<Csv, _> = client.sort(Csv(vec!["b".into(), "a".into()])).await;
+    }
+
+    #[test]
+    fn extraction() {
+        use actix_web::{test, App};
+        use phalanx::{prelude::PhalanxMount, reexports::http::StatusCode};
+
+        phalanx::reexports::rt::System::new("extraction").block_on(async {
+            let mut app = test::init_service(App::new().phalanx_mount(CsvServer)).await;
+
+            let req = test::TestRequest::post()
+                .uri("/")
+                .header("content-type", "text/csv")
+                .set_payload("b\na")
+                .to_request();
+            let body = test::read_response(&mut app, req).await;
+            assert_eq!(body, "a\nb");
+
+            let req = test::TestRequest::post()
+                .uri("/")
+                .header("content-type", "application/json")
+                .set_payload("[]")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        });
+    }
+}
+
+mod protobuf {
+    use super::*;
+    use phalanx::{proto::ProtoExporter, web::Protobuf};
+    use phalanx_codegen::put;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Post {
+        #[prost(string, tag = "1")]
+        title: String,
+    }
+
+    mod drafts {
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct Draft {
+            #[prost(string, tag = "1")]
+            pub body: String,
+        }
+    }
+
+    #[derive(Clone)]
+    struct ProtobufServer;
+
+    #[derive(PhalanxClient)]
+    struct ProtobufClient(#[client] Client);
+
+    #[phalanx(ProtobufClient)]
+    impl ProtobufServer {
+        #[get("/posts/{id}")]
+        async fn read_post(&self, id: u32) -> Protobuf<Post> {
+            Protobuf(Post {
+                title: format!("{}", id),
+            })
+        }
+
+        #[post("/posts")]
+        async fn create_post(&self, post: Protobuf<Post>) -> u64 {
+            post.title.len() as u64
+        }
+
+        #[post("/posts/clear")]
+        async fn clear(&self) {}
+
+        #[put("/posts/{id}/draft")]
+        async fn save_draft(
+            &self,
+            id: u32,
+            draft: Protobuf<drafts::Draft>,
+        ) -> Protobuf<drafts::Draft> {
+            Protobuf(drafts::Draft {
+                body: format!("{}: {}", id, draft.body),
+            })
+        }
+    }
+
+    // Verify protobuf messages are sent and received as written
+    async fn _test() {
+        let client = ProtobufClient(Client::url("http://localhost:8080"));
+        let _read: Result<Protobuf<Post>, _> = client.read_post(0).await;
+        let _create: Result<u64, _> = client
+            .create_post(Protobuf(Post {
+                title: String::new(),
+            }))
+            .await;
+    }
+
+    #[derive(Clone)]
+    struct GenericServer<T>(std::marker::PhantomData<T>);
+
+    #[derive(PhalanxClient)]
+    struct GenericClient(#[client] Client);
+
+    #[phalanx(GenericClient)]
+    impl GenericServer<u8> {
+        #[post("/clear")]
+        async fn clear(&self) {}
+    }
+
+    #[test]
+    fn export() {
+        let proto = ProtoExporter::new()
+            .package("blog")
+            .import("post.proto")
+            .service::<ProtobufServer>()
+            .service::<GenericServer<u8>>()
+            .export();
+
+        // `create_post` returns a bare integer rather than a message, so it's listed as left out
+        assert_eq!(
+            proto,
+            r#"syntax = "proto3";
+
+package blog;
+
+import "google/protobuf/empty.proto";
+import "post.proto";
+
+message ReadPostRequest {
+  uint32 id = 1;
+}
+
+message SaveDraftRequest {
+  uint32 id = 1;
+  drafts.Draft draft = 2;
+}
+
+service ProtobufServer {
+  // GET /posts/{id}
+  rpc ReadPost(ReadPostRequest) returns (Post);
+  // POST /posts/clear
+  rpc Clear(google.protobuf.Empty) returns (google.protobuf.Empty);
+  // PUT /posts/{id}/draft
+  rpc SaveDraft(SaveDraftRequest) returns (drafts.Draft);
+
+  // Left out, as they don't send protobuf messages:
+  // POST /posts (create_post)
+}
+
+service GenericServer {
+  // POST /clear
+  rpc Clear(google.protobuf.Empty) returns (google.protobuf.Empty);
+}
+"#
+        );
+    }
+}
+
+mod json_stream {
+    use super::*;
+    use futures::{stream, Stream, StreamExt};
+    use phalanx::{client::PhalanxClientError, web::JsonStream};
+
+    #[derive(Clone)]
+    struct StreamServer;
+
+    #[derive(PhalanxClient)]
+    struct StreamClient(#[client] Client);
+
+    #[phalanx(StreamClient)]
+    impl StreamServer {
+        #[get("/count/{to}")]
+        async fn count(&self, to: u32) -> impl Stream<Item = u32> {
+            stream::iter(0..to)
+        }
+
+        #[get("/words")]
+        async fn words(&self) -> JsonStream<String> {
+            JsonStream::new(stream::iter(vec![String::from("hello")]))
+        }
+    }
+
+    // Verify streams are received as a stream of results
+    async fn _test() {
+        let client = StreamClient(Client::url("http://localhost:8080"));
+        let count: JsonStream<Result<u32, PhalanxClientError>> = client.count(3).await.unwrap();
+        let _counts: Vec<Result<u32, _>> = count.collect().await;
+        let _words: Result<JsonStream<Result<String, _>>, _> = client.words().await;
+    }
+}
+
+mod body_stream {
+    use super::*;
+    use futures::{stream, TryStreamExt};
+    use phalanx::web::{BodyStream, Bytes};
+
+    #[derive(Clone)]
+    struct UploadServer;
+
+    #[derive(PhalanxClient)]
+    struct UploadClient(#[client] Client);
+
+    #[phalanx(UploadClient)]
+    impl UploadServer {
+        #[post("/import/{name}")]
+        async fn import(&self, name: String, body: BodyStream) -> Result<u64, std::io::Error> {
+            println!("Importing {}", name);
+            body.try_fold(0, |len, chunk| async move { Ok(len + chunk.len() as u64) })
+                .await
+        }
+
+        #[get("/export")]
+        async fn export(&self) -> BodyStream {
+            BodyStream::new(stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from(
+                "data",
+            ))]))
+        }
+    }
+
+    // Verify bodies are streamed both ways
+    async fn _test() {
+        let client = UploadClient(Client::url("http://localhost:8080"));
+        let chunks = stream::iter(vec![Ok::<_, std::io::Error>(vec![0u8; 1024])]);
+        let _import: Result<u64, _> = client
+            .import(String::from("posts"), BodyStream::new(chunks))
+            .await;
+        let _export: Result<BodyStream, _> = client.export().await;
+    }
+}
+
+mod event_stream {
+    use super::*;
+    use futures::{stream, StreamExt};
+    use phalanx::web::{Event, EventSource, EventStream};
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct EventServer;
+
+    #[derive(PhalanxClient)]
+    struct EventClient(#[client] Client);
+
+    #[phalanx(EventClient)]
+    impl EventServer {
+        #[get("/posts/{author}/events")]
+        async fn posts(&self, author: String) -> EventStream<String> {
+            EventStream::resume(move |last_event_id| {
+                let from = last_event_id.and_then(|id| id.parse().ok()).unwrap_or(0);
+                stream::iter(from..).map(move |id: u64| Event::new(author.clone()).with_id(id + 1))
+            })
+        }
+    }
+
+    // Verify events are received as a stream of results
+    async fn _test() {
+        let client = EventClient(Client::url("http://localhost:8080"));
+        let posts: EventSource<String> = client.posts(String::from("colin")).await.unwrap();
+        let _posts: Vec<Result<String, _>> = posts.take(3).collect().await;
+    }
+
+    #[derive(Clone)]
+    struct PageServer;
+
+    #[derive(PhalanxClient)]
+    struct PageClient(#[client] Client);
+
+    #[phalanx(PageClient)]
+    impl PageServer {
+        // Ends after two pages, for clients to reconnect from the last
+        #[get("/pages")]
+        async fn pages(&self) -> EventStream<u64> {
+            EventStream::resume(|last_event_id| {
+                let from: u64 = last_event_id.and_then(|id| id.parse().ok()).unwrap_or(0);
+                stream::iter(from + 1..=from + 2).map(|page| Event::new(page).with_id(page))
+            })
+            .with_retry(Duration::from_millis(10))
+        }
+
+        // The second page clears the id, so clients start again from the first
+        #[get("/restart")]
+        async fn restart(&self) -> EventStream<u64> {
+            EventStream::resume(|last_event_id| {
+                assert_eq!(last_event_id, None);
+                stream::iter(vec![Event::new(1).with_id(1), Event::new(2).with_id("")])
+            })
+            .with_retry(Duration::from_millis(10))
+        }
+    }
+
+    #[test]
+    fn reconnect() {
+        use actix_web::{test, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("reconnect").block_on(async {
+            let srv = test::start(|| App::new().phalanx_mount(PageServer));
+            let client = PageClient(Client::url(&format!("http://{}", srv.addr())));
+
+            // Reconnections send the id of the last event received
+            let pages = client.pages().await.unwrap();
+            let pages: Vec<u64> = pages.take(5).map(Result::unwrap).collect().await;
+            assert_eq!(pages, vec![1, 2, 3, 4, 5]);
+
+            let pages = client.restart().await.unwrap();
+            let pages: Vec<u64> = pages.take(4).map(Result::unwrap).collect().await;
+            assert_eq!(pages, vec![1, 2, 1, 2]);
+        });
+    }
+}
+
+mod websocket {
+    use super::*;
+    use futures::{SinkExt, Stream, StreamExt};
+    use phalanx::{
+        client::PhalanxClientError,
+        web::{WsSink, WsStream},
+    };
+    use phalanx_codegen::ws;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct ChatServer;
+
+    #[derive(PhalanxClient)]
+    struct ChatClient(#[client] Client);
+
+    #[phalanx(ChatClient)]
+    impl ChatServer {
+        #[ws("/rooms/{room}")]
+        async fn chat(
+            &self,
+            room: String,
+            messages: WsStream<String>,
+        ) -> impl Stream<Item = String> {
+            messages.map(move |message| format!("{}: {}", room, message))
+        }
+
+        // Reads more slowly than clients send, so the socket fills its buffer
+        #[ws("/rooms/{room}/slow")]
+        async fn slow(&self, room: String, messages: WsStream<u32>) -> impl Stream<Item = u32> {
+            let _ = room;
+            messages.then(|message| async move {
+                phalanx::reexports::rt::time::delay_for(Duration::from_millis(1)).await;
+                message
+            })
+        }
+    }
+
+    // Verify the client receives a sink and stream of typed messages
+    async fn _test() {
+        let client = ChatClient(Client::url("http://localhost:8080"));
+        let (mut sink, stream): (WsSink<String>, WsStream<Result<String, PhalanxClientError>>) =
+            client.chat(String::from("general")).await.unwrap();
+        sink.send(String::from("hello")).await.unwrap();
+        let _replies: Vec<Result<String, _>> = stream.take(1).collect().await;
+    }
+
+    #[test]
+    fn chat() {
+        use actix_web::{test, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("chat").block_on(async {
+            let srv = test::start(|| App::new().phalanx_mount(ChatServer));
+            let client = ChatClient(Client::url(&format!("http://{}", srv.addr())));
+
+            let (mut sink, stream) = client.chat(String::from("general")).await.unwrap();
+            sink.send(String::from("hello")).await.unwrap();
+            sink.send(String::from("bye")).await.unwrap();
+            sink.close().await.unwrap();
+
+            // The server closes the socket once the client's messages end
+            let replies: Vec<String> = stream.map(Result::unwrap).collect().await;
+            assert_eq!(replies, vec!["general: hello", "general: bye"]);
+        });
+    }
+
+    #[test]
+    fn backpressure() {
+        use actix_web::{test, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("backpressure").block_on(async {
+            let srv = test::start(|| App::new().phalanx_mount(ChatServer));
+            let client = ChatClient(Client::url(&format!("http://{}", srv.addr())));
+
+            // Messages sent while the handler is behind are held back, not dropped
+            let (mut sink, stream) = client.slow(String::from("general")).await.unwrap();
+            for message in 0..100 {
+                sink.send(message).await.unwrap();
+            }
+
+            let replies: Vec<u32> = stream.take(100).map(Result::unwrap).collect().await;
+            assert_eq!(replies, (0..100).collect::<Vec<_>>());
+            sink.close().await.unwrap();
+        });
+    }
+}
+
+mod pubsub {
+    use super::*;
+    use futures::StreamExt;
+    use phalanx::{
+        pubsub::{Broker, LagPolicy, Subscription},
+        web::{EventSource, Json},
+    };
+    use phalanx_codegen::subscribe;
+
+    #[derive(Clone)]
+    struct BlogServer {
+        broker: Broker,
+    }
+
+    #[derive(PhalanxClient)]
+    struct BlogClient(#[client] Client);
+
+    #[phalanx(BlogClient)]
+    impl BlogServer {
+        #[post("/posts")]
+        async fn create_post(&self, title: Json<String>) {
+            self.broker
+                .topic("posts.created")
+                .publish(title.into_inner());
+        }
+
+        #[subscribe("/posts/created")]
+        async fn posts_created(&self) -> Subscription<String> {
+            self.broker.topic("posts.created").subscribe()
+        }
+    }
+
+    // Verify the client gets a typed subscribe method
+    async fn _test() {
+        let client = BlogClient(Client::url("http://localhost:8080"));
+        let created: EventSource<String> = client.subscribe_posts_created().await.unwrap();
+        let _created: Vec<Result<String, _>> = created.take(1).collect().await;
+    }
+
+    #[test]
+    fn lagging() {
+        phalanx::reexports::rt::System::new("lagging").block_on(async {
+            let broker = Broker::new().capacity(2);
+            let topic = broker.topic::<u32>("numbers");
+            let skipping = topic.subscribe();
+            for n in 0..4 {
+                topic.publish(n);
+            }
+            drop(topic);
+            drop(broker);
+            assert_eq!(skipping.collect::<Vec<_>>().await, vec![2, 3]);
+
+            let broker = Broker::new().capacity(2).lag_policy(LagPolicy::Disconnect);
+            let topic = broker.topic::<u32>("numbers");
+            let mut disconnected = topic.subscribe();
+            for n in 0..4 {
+                topic.publish(n);
+            }
+            assert_eq!(disconnected.next().await, None);
+            assert_eq!(topic.subscriber_count(), 0);
+        });
+    }
+
+    #[test]
+    fn resuming() {
+        use actix_web::{test, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("resuming").block_on(async {
+            let broker = Broker::new().capacity(2);
+            let topic = broker.topic::<u32>("numbers");
+            for n in 1..=4 {
+                topic.publish(n);
+            }
+            let resumed = topic.subscribe().resume(1);
+            topic.publish(5);
+            drop(topic);
+            drop(broker);
+            // The second message is no longer held, so only the last two missed are sent
+            assert_eq!(resumed.collect::<Vec<_>>().await, vec![3, 4, 5]);
+
+            let broker = Broker::new();
+            let app = App::new().phalanx_mount(BlogServer {
+                broker: broker.clone(),
+            });
+            let mut app = test::init_service(app).await;
+            let topic = broker.topic::<String>("posts.created");
+            topic.publish(String::from("first"));
+            topic.publish(String::from("second"));
+
+            let req = test::TestRequest::get()
+                .uri("/posts/created")
+                .header("last-event-id", "1")
+                .to_request();
+            let mut res = test::call_service(&mut app, req).await;
+            let event = res.take_body().next().await.unwrap().unwrap();
+            assert_eq!(event, "id: 2\ndata: \"second\"\n\n");
+        });
+    }
+}
+
+mod jsonrpc {
+    use super::*;
+    use phalanx::{
+        jsonrpc,
+        reexports::ResponseError,
+        web::{BodyStream, Json},
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct NotFound;
+
+    impl std::fmt::Display for NotFound {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "not found")
+        }
+    }
+
+    impl ResponseError for NotFound {
+        fn status_code(&self) -> phalanx::reexports::http::StatusCode {
+            phalanx::reexports::http::StatusCode::NOT_FOUND
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Post {
+        title: String,
+    }
+
+    #[derive(Clone)]
+    struct BlogServer;
+
+    #[derive(PhalanxClient)]
+    struct BlogClient(#[client] Client);
+
+    #[derive(PhalanxClient)]
+    struct BlogRpcClient(#[client] Client);
+
+    #[phalanx(BlogClient, rpc = BlogRpcClient)]
+    impl BlogServer {
+        #[get("/posts/{id}")]
+        async fn read_post(&self, id: u32) -> Result<Json<Post>, NotFound> {
+            match id {
+                0 => Err(NotFound),
+                _ => Ok(Json(Post {
+                    title: format!("{}", id),
+                })),
+            }
+        }
+
+        #[post("/posts/{id}")]
+        async fn update_post(&self, id: u32, _post: Json<Post>) -> u32 {
+            id
+        }
+
+        #[post("/ping")]
+        async fn ping(&self) {}
+
+        // Left out of the JSON-RPC service, as its body isn't JSON
+        #[get("/raw")]
+        async fn raw(&self) -> BodyStream {
+            BodyStream::new(futures::stream::empty::<Result<Vec<u8>, std::io::Error>>())
+        }
+    }
+
+    // Verify the JSON-RPC client gets the same methods as the REST client
+    async fn _test() {
+        let client = BlogRpcClient(Client::url("http://localhost:8080"));
+        let _read: Result<Json<Post>, _> = client.read_post(1).await;
+        let post = Post {
+            title: String::new(),
+        };
+        let _update: Result<u32, _> = client.update_post(1, Json(post)).await;
+        let _ping: Result<(), _> = client.ping().await;
+    }
+
+    async fn handle(request: Value) -> Option<Value> {
+        let body = serde_json::to_vec(&request).unwrap();
+        jsonrpc::handle(Arc::new(BlogServer), &body).await
+    }
+
+    #[test]
+    fn dispatch() {
+        phalanx::reexports::rt::System::new("dispatch").block_on(async {
+            let batch = json!([
+                { "jsonrpc": "2.0", "method": "read_post", "params": { "id": 1 }, "id": 1 },
+                { "jsonrpc": "2.0", "method": "update_post", "params": [2, { "title": "" }], "id": "two" },
+                { "jsonrpc": "2.0", "method": "ping" },
+                { "jsonrpc": "2.0", "method": "read_post", "params": { "id": 0 }, "id": 3 },
+                { "jsonrpc": "2.0", "method": "raw", "id": 4 },
+                { "jsonrpc": "2.0", "method": "read_post", "params": { "id": "one" }, "id": 5 },
+                { "method": "ping", "id": 6 },
+            ]);
+            let responses = handle(batch).await.unwrap();
+            assert_eq!(
+                responses,
+                json!([
+                    { "jsonrpc": "2.0", "result": { "title": "1" }, "id": 1 },
+                    { "jsonrpc": "2.0", "result": 2, "id": "two" },
+                    {
+                        "jsonrpc": "2.0",
+                        "error": { "code": -32000, "message": "not found", "data": { "status": 404 } },
+                        "id": 3,
+                    },
+                    {
+                        "jsonrpc": "2.0",
+                        "error": { "code": -32601, "message": "no method named `raw`" },
+                        "id": 4,
+                    },
+                    responses[4],
+                    responses[5],
+                ])
+            );
+            assert_eq!(responses[4]["error"]["code"], jsonrpc::INVALID_PARAMS);
+            assert_eq!(responses[5]["error"]["code"], jsonrpc::INVALID_REQUEST);
+            assert_eq!(responses[5]["id"], 6);
+
+            // Notifications aren't responded to
+            let notification = json!({ "jsonrpc": "2.0", "method": "ping" });
+            assert_eq!(handle(notification.clone()).await, None);
+            assert_eq!(handle(json!([notification])).await, None);
+
+            let empty = handle(json!([])).await.unwrap();
+            assert_eq!(empty["error"]["code"], jsonrpc::INVALID_REQUEST);
+            let large = vec![notification; jsonrpc::MAX_BATCH_SIZE + 1];
+            let large = handle(Value::from(large)).await.unwrap();
+            assert_eq!(large["error"]["code"], jsonrpc::INVALID_REQUEST);
+            let unparsable = jsonrpc::handle(Arc::new(BlogServer), b"{").await.unwrap();
+            assert_eq!(unparsable["error"]["code"], jsonrpc::PARSE_ERROR);
+            assert_eq!(unparsable["id"], Value::Null);
+        });
+    }
+}
+
+mod batch {
+    use super::*;
+    use phalanx::{
+        batch::{self, Call, CallResult},
+        client::PhalanxClientError,
+        reexports::ResponseError,
+        web::Json,
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct NotFound;
+
+    impl std::fmt::Display for NotFound {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "not found")
+        }
+    }
+
+    impl ResponseError for NotFound {
+        fn status_code(&self) -> phalanx::reexports::http::StatusCode {
+            phalanx::reexports::http::StatusCode::NOT_FOUND
+        }
+    }
+
+    #[derive(Clone)]
+    struct BlogServer;
+
+    #[derive(PhalanxClient)]
+    struct BlogClient(#[client] Client);
+
+    #[phalanx(BlogClient, batch)]
+    impl BlogServer {
+        #[get("/posts/{id}")]
+        async fn read_post(&self, id: u32) -> Result<Json<String>, NotFound> {
+            match id {
+                0 => Err(NotFound),
+                _ => Ok(Json(format!("{}", id))),
+            }
+        }
+
+        #[get("/posts")]
+        async fn count_posts(&self) -> u32 {
+            2
+        }
+    }
+
+    // Verify the batch builder returns a typed result for each call
+    async fn _test() {
+        let client = BlogClient(Client::url("http://localhost:8080"));
+        let (_first, _count): (Result<Json<String>, _>, Result<u32, _>) = client
+            .batch()
+            .read_post(1)
+            .count_posts()
+            .send()
+            .await
+            .unwrap();
+        let _posts: Vec<Result<Json<String>, PhalanxClientError>> = [1, 2, 3]
+            .iter()
+            .fold(client.batch_all(), |batch, id| batch.read_post(*id))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn statuses() {
+        phalanx::reexports::rt::System::new("statuses").block_on(async {
+            let calls: Vec<Call> = serde_json::from_value(json!([
+                { "route": "read_post", "args": { "id": 1 } },
+                { "route": "read_post", "args": [0] },
+                { "route": "count_posts" },
+                { "route": "delete_post" },
+                { "route": "read_post", "args": { "id": -1 } },
+            ]))
+            .unwrap();
+            let results = batch::handle(Arc::new(BlogServer), calls).await;
+            let statuses: Vec<u16> = results.iter().map(|result| result.status).collect();
+            assert_eq!(statuses, vec![200, 404, 200, 404, 400]);
+            assert_eq!(
+                results[0],
+                CallResult {
+                    status: 200,
+                    body: Some(json!("1")),
+                    error: None,
+                }
+            );
+            assert_eq!(results[1].error.as_deref(), Some("not found"));
+            assert_eq!(results[2].body, Some(json!(2)));
+        });
+    }
+
+    #[test]
+    fn size_limit() {
+        use actix_web::{test, App};
+        use phalanx::{batch::MAX_BATCH_SIZE, prelude::PhalanxMount, reexports::http::StatusCode};
+
+        phalanx::reexports::rt::System::new("size_limit").block_on(async {
+            let mut app = test::init_service(App::new().phalanx_mount(BlogServer)).await;
+            let calls = vec![json!({ "route": "count_posts" }); MAX_BATCH_SIZE + 1];
+            let req = test::TestRequest::post()
+                .uri(batch::PATH)
+                .set_json(&calls)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            // Clients split larger batches into several requests
+            let srv = test::start(|| App::new().phalanx_mount(BlogServer));
+            let client = BlogClient(Client::url(&format!("http://{}", srv.addr())));
+            let ids: Vec<u32> = (1..=250).collect();
+            let posts = ids
+                .iter()
+                .fold(client.batch_all(), |batch, id| batch.read_post(*id))
+                .send()
+                .await
+                .unwrap();
+            let posts: Vec<String> = posts.into_iter().map(|post| post.unwrap().0).collect();
+            assert_eq!(posts.len(), 250);
+            assert_eq!(posts[249], "250");
+        });
+    }
+}
+
+mod loader {
+    use super::*;
+    use futures::future::join_all;
+    use phalanx::{loader::Loader, web::Json};
+    use phalanx_codegen::batch_of;
+    use std::{cell::RefCell, time::Duration};
+
+    #[derive(Clone)]
+    struct BlogServer;
+
+    #[derive(PhalanxClient)]
+    struct BlogClient(#[client] Client);
+
+    #[phalanx(BlogClient)]
+    impl BlogServer {
+        #[get("/posts/{id}")]
+        async fn read_post(&self, id: u32) -> Json<String> {
+            Json(format!("{}", id))
+        }
+
+        #[post("/posts/list")]
+        #[batch_of(read_post)]
+        async fn read_posts(&self, ids: Json<Vec<u32>>) -> Json<Vec<Option<String>>> {
+            Json(ids.iter().map(|id| Some(format!("{}", id))).collect())
+        }
+    }
+
+    #[derive(Clone)]
+    struct DraftServer;
+
+    #[derive(PhalanxClient)]
+    struct DraftClient(#[client] Client);
+
+    #[phalanx(DraftClient)]
+    impl DraftServer {
+        #[get("/drafts/{slug}")]
+        async fn read_post(&self, slug: String) -> Json<u32> {
+            Json(slug.len() as u32)
+        }
+
+        #[post("/drafts/list")]
+        #[batch_of(read_post)]
+        async fn read_posts(&self, slugs: Json<Vec<String>>) -> Json<Vec<u32>> {
+            Json(slugs.iter().map(|slug| slug.len() as u32).collect())
+        }
+    }
+
+    // Verify batched routes keep their signature
+    async fn _test() {
+        let client = BlogClient(
+            Client::url("http://localhost:8080").batch_window(Duration::from_millis(10)),
+        );
+        let _posts: Vec<Result<Json<String>, _>> =
+            join_all((0..10).map(|id| client.read_post(id))).await;
+        let _list: Result<Json<Vec<Option<String>>>, _> = client.read_posts(Json(vec![0])).await;
+
+        // Services sharing a client batch their routes of the same name separately
+        let drafts = DraftClient(client.0.clone());
+        let _draft: Result<Json<u32>, _> = drafts.read_post(String::from("draft")).await;
+    }
+
+    #[test]
+    fn shared_client() {
+        let client = Client::url("http://localhost:8080");
+        let _posts = client.loader::<u32, Json<String>>("BlogServer::read_post");
+        let _drafts = client.loader::<String, Json<u32>>("DraftServer::read_post");
+        let posts = client.loader::<u32, Json<String>>("BlogServer::read_post");
+        assert_eq!(std::sync::Arc::strong_count(&posts), 3);
+    }
+
+    #[test]
+    fn coalescing() {
+        phalanx::reexports::rt::System::new("coalescing").block_on(async {
+            let loader = Loader::new(Duration::from_millis(1));
+            let fetches = RefCell::new(Vec::new());
+            let fetch = |keys: Vec<u32>| {
+                fetches.borrow_mut().push(keys.clone());
+                async move { Ok(keys.iter().map(|key| key * 2).collect()) }
+            };
+            let values = join_all((0..5).map(|key| loader.load(key, fetch))).await;
+            let values: Vec<u32> = values.into_iter().map(Result::unwrap).collect();
+            assert_eq!(values, vec![0, 2, 4, 6, 8]);
+            assert_eq!(*fetches.borrow(), vec![vec![0, 1, 2, 3, 4]]);
+
+            // Calls after the window are sent separately
+            assert_eq!(loader.load(5, fetch).await.unwrap(), 10);
+            assert_eq!(fetches.borrow().len(), 2);
+
+            let short = |_: Vec<u32>| async { Ok(Vec::new()) };
+            let results = join_all((0..2).map(|key| loader.load(key, short))).await;
+            assert!(results.iter().all(Result::is_err));
+        });
+    }
+
+    #[test]
+    fn leader_dropped() {
+        use actix_web::rt::time::delay_for;
+        use futures::poll;
+
+        phalanx::reexports::rt::System::new("leader_dropped").block_on(async {
+            let loader = Loader::new(Duration::from_millis(1));
+            let fetches = RefCell::new(Vec::new());
+            let fetch = |keys: Vec<u32>| {
+                fetches.borrow_mut().push(keys.clone());
+                async move {
+                    delay_for(Duration::from_millis(5)).await;
+                    Ok(keys.iter().map(|key| key * 2).collect())
+                }
+            };
+
+            // Dropped while waiting for others to join
+            let mut leader = Box::pin(loader.load(1, fetch));
+            let mut follower = Box::pin(loader.load(2, fetch));
+            assert!(poll!(&mut leader).is_pending());
+            assert!(poll!(&mut follower).is_pending());
+            drop(leader);
+            assert_eq!(follower.await.unwrap(), 4);
+            assert_eq!(*fetches.borrow(), vec![vec![2]]);
+
+            // Dropped while sending the batch
+            let mut leader = Box::pin(loader.load(3, fetch));
+            let mut follower = Box::pin(loader.load(4, fetch));
+            assert!(poll!(&mut leader).is_pending());
+            assert!(poll!(&mut follower).is_pending());
+            delay_for(Duration::from_millis(2)).await;
+            assert!(poll!(&mut leader).is_pending());
+            drop(leader);
+            assert_eq!(follower.await.unwrap(), 8);
+            assert_eq!(fetches.borrow()[1..], [vec![3, 4], vec![4]]);
+        });
+    }
+
+    #[test]
+    fn candidate_dropped() {
+        use actix_web::rt::time::delay_for;
+        use futures::poll;
+
+        phalanx::reexports::rt::System::new("candidate_dropped").block_on(async {
+            let loader = Loader::new(Duration::from_millis(1));
+            let fetches = RefCell::new(Vec::new());
+            let fetch = |keys: Vec<u32>| {
+                fetches.borrow_mut().push(keys.clone());
+                async move {
+                    delay_for(Duration::from_millis(5)).await;
+                    Ok(keys.iter().map(|key| key * 2).collect())
+                }
+            };
+
+            // The next caller in line has gone too, so the one after it sends the batch
+            let mut callers: Vec<_> = (5..9)
+                .map(|key| Box::pin(loader.load(key, fetch)))
+                .collect();
+            for caller in &mut callers {
+                assert!(poll!(caller).is_pending());
+            }
+            delay_for(Duration::from_millis(2)).await;
+            assert!(poll!(&mut callers[0]).is_pending());
+            let callers: Vec<_> = callers.into_iter().skip(2).collect();
+
+            let values: Vec<u32> = join_all(callers)
+                .await
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(values, vec![14, 16]);
+            assert_eq!(*fetches.borrow(), vec![vec![5, 6, 7, 8], vec![7, 8]]);
+        });
+    }
+}
+
+mod retry {
+    use super::*;
+    use actix_web::{test, App, HttpResponse, ResponseError};
+    use phalanx::{prelude::PhalanxMount, retry::RetryPolicy, web::Json};
+    use phalanx_codegen::retry;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// Responds `503 Service Unavailable`, asking for a delay in seconds if it has one
+    #[derive(Debug)]
+    struct Unavailable(Option<u64>);
+
+    impl std::fmt::Display for Unavailable {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "unavailable")
+        }
+    }
+
+    impl ResponseError for Unavailable {
+        fn status_code(&self) -> phalanx::reexports::http::StatusCode {
+            phalanx::reexports::http::StatusCode::SERVICE_UNAVAILABLE
+        }
+
+        fn error_response(&self) -> HttpResponse {
+            let mut res = HttpResponse::build(self.status_code());
+            if let Some(seconds) = self.0 {
+                res.header("retry-after", seconds.to_string());
+            }
+            res.finish()
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct RetryServer {
+        hits: Arc<AtomicUsize>,
+        comments: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RetryServer {
+        /// Count a request, returning how many came before it
+        fn hit(&self) -> usize {
+            self.hits.fetch_add(1, Ordering::SeqCst)
+        }
+
+        fn hits(&self) -> usize {
+            self.hits.load(Ordering::SeqCst)
+        }
+    }
+
+    #[derive(PhalanxClient)]
+    struct RetryClient(#[client] Client);
+
+    #[phalanx(RetryClient)]
+    impl RetryServer {
+        /// Unavailable for the first two attempts
+        #[get("/posts/{id}")]
+        #[retry(attempts = 5, backoff_ms = 50, max_backoff_ms = 2000, statuses = [503, 429])]
+        async fn read_post(&self, id: u32) -> Result<Json<u32>, Unavailable> {
+            match self.hit() {
+                0 | 1 => Err(Unavailable(None)),
+                _ => Ok(Json(id)),
+            }
+        }
+
+        #[post("/posts")]
+        async fn create_post(&self, _title: String) -> Result<(), Unavailable> {
+            self.hit();
+            Err(Unavailable(None))
+        }
+
+        /// Unavailable for the first attempt
+        #[post("/posts/{id}/comments")]
+        #[retry(non_idempotent, jitter = false)]
+        async fn comment(&self, id: u32, comment: String) -> Result<(), Unavailable> {
+            self.comments
+                .lock()
+                .unwrap()
+                .push(format!("{}: {}", id, comment));
+            match self.hit() {
+                0 => Err(Unavailable(None)),
+                _ => Ok(()),
+            }
+        }
+
+        #[get("/posts/{id}/views")]
+        #[retry(never)]
+        async fn views(&self, id: u32) -> u64 {
+            u64::from(id)
+        }
+
+        /// Asks for `seconds` of delay on the first attempt
+        #[get("/busy/{seconds}")]
+        async fn busy(&self, seconds: u64) -> Result<(), Unavailable> {
+            match self.hit() {
+                0 => Err(Unavailable(Some(seconds))),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    // Verify routes with retry policies keep their signature
+    async fn _test() {
+        let client = RetryClient(
+            Client::url("http://localhost:8080").retry(
+                RetryPolicy::new()
+                    .attempts(4)
+                    .backoff(Duration::from_millis(10)),
+            ),
+        );
+        let _read: Result<Json<u32>, _> = client.read_post(0).await;
+        let _comment: Result<(), _> = client.comment(0, String::new()).await;
+        let _views: Result<u64, _> = client.views(0).await;
+    }
+
+    /// Serve `server` on a test server, returning a client calling it with `policy`
+    fn serve(server: &RetryServer, policy: RetryPolicy) -> (test::TestServer, RetryClient) {
+        let mounted = server.clone();
+        let srv = test::start(move || App::new().phalanx_mount(mounted.clone()));
+        let url = format!("http://{}", srv.addr());
+        (srv, RetryClient(Client::url(&url).retry(policy)))
+    }
+
+    #[test]
+    fn retried() {
+        phalanx::reexports::rt::System::new("retried").block_on(async {
+            // Unavailable responses are retried
+            let server = RetryServer::default();
+            let (_srv, client) = serve(&server, RetryPolicy::new());
+            assert_eq!(client.read_post(7).await.unwrap().0, 7);
+            assert_eq!(server.hits(), 3);
+
+            // POSTs aren't retried by default
+            let server = RetryServer::default();
+            let (_srv, client) = serve(&server, RetryPolicy::new());
+            assert!(client.create_post(String::from("title")).await.is_err());
+            assert_eq!(server.hits(), 1);
+
+            // Unless the route allows it, in which case the body is sent with each attempt
+            let server = RetryServer::default();
+            let (_srv, client) = serve(&server, RetryPolicy::new());
+            client.comment(1, String::from("hello")).await.unwrap();
+            assert_eq!(
+                *server.comments.lock().unwrap(),
+                vec!["1: hello", "1: hello"]
+            );
+        });
+    }
+
+    #[test]
+    fn delays() {
+        phalanx::reexports::rt::System::new("delays").block_on(async {
+            let policy = RetryPolicy::new()
+                .backoff(Duration::from_millis(1))
+                .jitter(false);
+
+            // Retry-After is waited for instead of the backoff
+            let server = RetryServer::default();
+            let (_srv, client) = serve(&server, policy.clone());
+            let start = Instant::now();
+            client.busy(1).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_secs(1));
+            assert_eq!(server.hits(), 2);
+
+            // Up to the longest backoff
+            let server = RetryServer::default();
+            let capped = policy.clone().max_backoff(Duration::from_millis(20));
+            let (_srv, client) = serve(&server, capped);
+            let start = Instant::now();
+            client.busy(60).await.unwrap();
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            // Which also caps the backoff
+            let server = RetryServer::default();
+            let capped = policy
+                .backoff(Duration::from_secs(60))
+                .max_backoff(Duration::from_millis(20))
+                .retry_after(false);
+            let (_srv, client) = serve(&server, capped);
+            let start = Instant::now();
+            client.busy(60).await.unwrap();
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert_eq!(server.hits(), 2);
+        });
+    }
+}
+
+mod timeout {
+    use super::*;
+    use phalanx::{client::PhalanxClientError, web::Json};
+    use phalanx_codegen::timeout;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct TimeoutServer;
+
+    #[derive(PhalanxClient)]
+    struct TimeoutClient(#[client] Client);
+
+    #[phalanx(TimeoutClient)]
+    impl TimeoutServer {
+        #[get("/reports/{id}")]
+        #[timeout(ms = 500)]
+        async fn report(&self, id: u32) -> Json<u32> {
+            Json(id)
+        }
+
+        #[post("/reports")]
+        #[timeout(ms = 2000)]
+        async fn generate(&self, title: String) {
+            println!("Generating {}", title);
+        }
+
+        #[get("/status")]
+        async fn status(&self) -> String {
+            String::from("ok")
+        }
+    }
+
+    // Verify routes with timeouts keep their signature
+    async fn _test() {
+        let client =
+            TimeoutClient(Client::url("http://localhost:8080").timeout(Duration::from_secs(5)));
+        let _report: Result<Json<u32>, _> = client.report(0).await;
+        let _generate: Result<(), _> = client.generate(String::new()).await;
+        let _status: Result<String, _> = client.status().await;
+    }
+
+    #[test]
+    fn timed_out() {
+        phalanx::reexports::rt::System::new("timed_out").block_on(async {
+            let slow = async {
+                phalanx::reexports::rt::time::delay_for(Duration::from_secs(5)).await;
+                Ok(())
+            };
+            let err = phalanx::client::timeout(Some(Duration::from_millis(10)), slow)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<PhalanxClientError>(),
+                Some(PhalanxClientError::Timeout(_))
+            ));
+
+            let fast = async { Ok(1) };
+            let res = phalanx::client::timeout(Some(Duration::from_millis(10)), fast).await;
+            assert_eq!(res.unwrap(), 1);
+
+            let res = phalanx::server::timeout(
+                Duration::from_millis(10),
+                futures::future::pending::<()>(),
+            )
+            .await;
+            let status = res.unwrap_err().as_response_error().status_code();
+            assert_eq!(
+                status,
+                phalanx::reexports::http::StatusCode::GATEWAY_TIMEOUT
+            );
+        });
+    }
+}
+
+mod deadline {
+    use super::*;
+    use phalanx::{client::PhalanxClientError, deadline::Deadline, web::Json};
+    use std::{sync::Arc, time::Duration};
+
+    #[derive(Clone)]
+    struct FeedServer {
+        upstream: Arc<FeedClient>,
+    }
+
+    #[derive(PhalanxClient)]
+    struct FeedClient(#[client] Client);
+
+    #[phalanx(FeedClient)]
+    impl FeedServer {
+        #[get("/feed/{id}")]
+        async fn feed(&self, id: u32) -> Json<Vec<u32>> {
+            // Calls made while handling a request inherit its deadline
+            let mut feed = vec![id];
+            if Deadline::current().remaining() > Some(Duration::from_millis(100)) {
+                if let Ok(Json(more)) = self.upstream.recommended(id).await {
+                    feed.extend(more);
+                }
+            }
+            Json(feed)
+        }
+
+        #[get("/feed/{id}/recommended")]
+        async fn recommended(&self, id: u32) -> Json<Vec<u32>> {
+            Json(vec![id + 1])
+        }
+    }
+
+    #[test]
+    fn inherited() {
+        phalanx::reexports::rt::System::new("inherited").block_on(async {
+            assert_eq!(Deadline::current(), Deadline::none());
+
+            let deadline = Deadline::after(Duration::from_millis(50));
+            let inner = deadline
+                .scope(async {
+                    let outer = Deadline::current();
+                    // Calls with a longer timeout are cut short by the deadline
+                    let call = phalanx::client::timeout(Some(Duration::from_secs(5)), async {
+                        Ok(Deadline::current())
+                    });
+                    (outer, call.await.unwrap())
+                })
+                .await;
+            assert_eq!(inner.0, deadline);
+            assert_eq!(inner.1, deadline);
+
+            // Calls made once the caller's deadline has passed report their own budget
+            let fast = async { Ok(1) };
+            let call = phalanx::client::timeout(Some(Duration::from_millis(10)), fast);
+            let err = Deadline::after(Duration::from_secs(0))
+                .scope(call)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<PhalanxClientError>(),
+                Some(PhalanxClientError::Timeout(budget)) if *budget == Duration::from_millis(10)
+            ));
+
+            let expired = Deadline::after(Duration::from_secs(0));
+            assert!(expired.is_expired());
+            let res = phalanx::server::within(expired, async {}).await;
+            let status = res.unwrap_err().as_response_error().status_code();
+            assert_eq!(
+                status,
+                phalanx::reexports::http::StatusCode::GATEWAY_TIMEOUT
+            );
+        });
+    }
+
+    #[test]
+    fn trusted() {
+        use actix_web::test;
+        use phalanx::{
+            deadline::{DeadlineConfig, HEADER},
+            reexports::FromRequest,
+        };
+
+        phalanx::reexports::rt::System::new("trusted").block_on(async {
+            // Deadlines sent by untrusted callers are ignored
+            let req = test::TestRequest::default()
+                .header(HEADER, "50")
+                .to_http_request();
+            let deadline = Deadline::extract(&req).await.unwrap();
+            assert_eq!(deadline, Deadline::none());
+
+            let trusted = DeadlineConfig::default().trust(|req| req.path() == "/internal");
+            let req = test::TestRequest::with_uri("/public")
+                .app_data(trusted.clone())
+                .header(HEADER, "50")
+                .to_http_request();
+            let deadline = Deadline::extract(&req).await.unwrap();
+            assert_eq!(deadline, Deadline::none());
+
+            let req = test::TestRequest::with_uri("/internal")
+                .app_data(trusted)
+                .header(HEADER, "50")
+                .to_http_request();
+            let remaining = Deadline::extract(&req).await.unwrap().remaining();
+            assert!(remaining.unwrap() <= Duration::from_millis(50));
+        });
+    }
+
+    #[test]
+    fn unrepresentable() {
+        use actix_web::test;
+        use phalanx::{
+            deadline::{DeadlineConfig, HEADER},
+            reexports::FromRequest,
+        };
+
+        phalanx::reexports::rt::System::new("unrepresentable").block_on(async {
+            // Deadlines too far off to represent are treated as no deadline
+            let req = test::TestRequest::default()
+                .app_data(DeadlineConfig::default().trust_all())
+                .header(HEADER, u64::MAX.to_string())
+                .to_http_request();
+            let deadline = Deadline::extract(&req).await.unwrap();
+            assert_eq!(deadline, Deadline::none());
+
+            let deadline = Deadline::after(Duration::from_millis(50));
+            assert_eq!(deadline.limit(Duration::from_millis(u64::MAX)), deadline);
+            assert_eq!(
+                Deadline::none().limit(Duration::from_millis(u64::MAX)),
+                Deadline::none()
+            );
+        });
+    }
+}
+
+mod breaker {
+    use phalanx::{
+        breaker::{CircuitBreaker, CircuitState, ManualClock},
+        client::PhalanxClientError,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn tripping() {
+        let clock = ManualClock::new();
+        let breaker = CircuitBreaker::new()
+            .failure_rate(0.5)
+            .min_calls(4)
+            .open_for(Duration::from_secs(5))
+            .per_route(true)
+            .clock(clock.clone());
+        let key = breaker.key("http://posts", "read_post");
+        assert_eq!(key, "http://posts read_post");
+
+        // Half the calls failing opens the circuit once there are enough of them
+        breaker.acquire(key.clone()).unwrap().succeed();
+        breaker.acquire(key.clone()).unwrap().fail();
+        breaker.acquire(key.clone()).unwrap().succeed();
+        assert_eq!(breaker.state(&key), CircuitState::Closed);
+        drop(breaker.acquire(key.clone()).unwrap());
+        assert_eq!(breaker.state(&key), CircuitState::Open);
+        assert!(matches!(
+            breaker.acquire(key.clone()),
+            Err(PhalanxClientError::CircuitOpen(_))
+        ));
+
+        // Other routes have their own circuit
+        let other = breaker.key("http://posts", "list_posts");
+        breaker.acquire(other.clone()).unwrap().succeed();
+
+        // A failed probe keeps the circuit open
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(breaker.state(&key), CircuitState::HalfOpen);
+        let probe = breaker.acquire(key.clone()).unwrap();
+        assert!(breaker.acquire(key.clone()).is_err());
+        probe.fail();
+        assert_eq!(breaker.state(&key), CircuitState::Open);
+
+        // A successful probe closes it
+        clock.advance(Duration::from_secs(5));
+        breaker.acquire(key.clone()).unwrap().succeed();
+        assert_eq!(breaker.state(&key), CircuitState::Closed);
+
+        let circuits = breaker.circuits();
+        assert_eq!(circuits.len(), 2);
+        assert_eq!(circuits[0].key, other);
+        assert_eq!(circuits[0].calls, 1);
+        assert_eq!(circuits[1].state, CircuitState::Closed);
+    }
+    mod shared {
+        use phalanx::{client::Client, web::Json};
+        use phalanx_codegen::{get, phalanx, PhalanxClient};
+
+        #[derive(Clone)]
+        pub struct PostsServer;
+
+        #[derive(PhalanxClient)]
+        pub struct PostsClient(#[client] pub Client);
+
+        #[phalanx(PostsClient)]
+        impl PostsServer {
+            #[get("/posts/{id}")]
+            async fn read_post(&self, id: u32) -> Json<u32> {
+                Json(id)
+            }
+        }
+
+        #[derive(Clone)]
+        pub struct CommentsServer;
+
+        #[derive(PhalanxClient)]
+        pub struct CommentsClient(#[client] pub Client);
+
+        #[phalanx(CommentsClient)]
+        impl CommentsServer {
+            #[get("/comments/{id}")]
+            async fn read_post(&self, id: u32) -> Json<u32> {
+                Json(id)
+            }
+        }
+    }
+
+    #[test]
+    fn shared_base_url() {
+        use actix_web::{test, App};
+        use phalanx::{client::Client, prelude::PhalanxMount};
+        use shared::{CommentsClient, CommentsServer, PostsClient, PostsServer};
+
+        phalanx::reexports::rt::System::new("shared_base_url").block_on(async {
+            let server = test::start(|| {
+                App::new()
+                    .phalanx_mount(PostsServer)
+                    .phalanx_mount(CommentsServer)
+            });
+
+            // Services behind one base URL have their own circuit for routes of the same name
+            let url = format!("http://{}", server.addr());
+            let breaker = CircuitBreaker::new().per_route(true);
+            let client = Client::url(&url).circuit_breaker(breaker.clone());
+            PostsClient(client.clone()).read_post(1).await.unwrap();
+            CommentsClient(client).read_post(1).await.unwrap();
+
+            let keys: Vec<_> = breaker
+                .circuits()
+                .into_iter()
+                .map(|circuit| circuit.key)
+                .collect();
+            assert_eq!(
+                keys,
+                vec![
+                    format!("{} CommentsServer::read_post", url),
+                    format!("{} PostsServer::read_post", url),
+                ]
+            );
+        });
+    }
+}
+
+mod balance {
+    use super::*;
+    use actix_web::{error::ErrorServiceUnavailable, test, App};
+    use phalanx::{
+        balance::{hash_key, Balancer, Strategy},
+        prelude::PhalanxMount,
+        reexports::Client as ReqwestClient,
+        retry::RetryPolicy,
+        web::Json,
+    };
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    const REPLICAS: [&str; 3] = ["http://posts-1", "http://posts-2", "http://posts-3"];
+
+    #[derive(Clone, Default)]
+    struct ReplicaServer {
+        down: bool,
+        hits: Arc<AtomicUsize>,
+    }
+
+    #[derive(PhalanxClient)]
+    struct ReplicaClient(#[client] Client);
+
+    #[phalanx(ReplicaClient)]
+    impl ReplicaServer {
+        #[get("/posts/{id}")]
+        async fn read_post(&self, id: u32) -> Result<Json<u32>, actix_web::Error> {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+            if self.down {
+                return Err(ErrorServiceUnavailable("down"));
+            }
+            Ok(Json(id))
+        }
+    }
+
+    #[test]
+    fn strategies() {
+        let balancer = Balancer::new(REPLICAS.to_vec());
+        let client = Client::balanced(ReqwestClient::default(), balancer);
+        let urls: Vec<_> = (0..6).map(|_| client.format_url("/posts/1")).collect();
+        assert_eq!(urls[0], "http://posts-1/posts/1");
+        assert_eq!(urls[1], "http://posts-2/posts/1");
+        assert_eq!(urls[2], "http://posts-3/posts/1");
+        assert_eq!(urls[3], urls[0]);
+
+        // The same path always goes to the same replica, and paths are spread between them
+        let balancer = Balancer::new(REPLICAS.to_vec()).strategy(Strategy::ConsistentHash);
+        let picked: HashSet<_> = (0..100)
+            .map(|id| {
+                let path = format!("/posts/{}", id);
+                let replica = balancer.pick(&path);
+                assert_eq!(replica, balancer.pick(&path));
+                String::from(replica)
+            })
+            .collect();
+        assert_eq!(picked.len(), 3);
+
+        let balancer = Balancer::new(REPLICAS.to_vec()).strategy(Strategy::LeastOutstanding);
+        let picked: HashSet<_> = (0..3).map(|_| String::from(balancer.pick("/"))).collect();
+        assert_eq!(picked.len(), 3);
+        assert!(balancer.endpoints().iter().all(|endpoint| endpoint.healthy));
+    }
+
+    #[test]
+    fn keyed() {
+        phalanx::reexports::rt::System::new("keyed").block_on(async {
+            // Paths picked with the same key go to the same replica
+            let balancer = Balancer::new(REPLICAS.to_vec()).strategy(Strategy::ConsistentHash);
+            let keyed = hash_key("user-1", async {
+                let picked: HashSet<_> = (0..100)
+                    .map(|id| String::from(balancer.pick(&format!("/posts/{}", id))))
+                    .collect();
+                picked
+            })
+            .await;
+            assert_eq!(keyed.len(), 1);
+        });
+    }
+
+    #[test]
+    fn repicked() {
+        phalanx::reexports::rt::System::new("repicked").block_on(async {
+            let down = ReplicaServer {
+                down: true,
+                ..ReplicaServer::default()
+            };
+            let up = ReplicaServer::default();
+            let replicas: Vec<_> = vec![down.clone(), up.clone()]
+                .into_iter()
+                .map(|server| test::start(move || App::new().phalanx_mount(server.clone())))
+                .collect();
+
+            let urls: Vec<_> = replicas
+                .iter()
+                .map(|replica| format!("http://{}", replica.addr()))
+                .collect();
+            let client = Client::balanced(ReqwestClient::default(), Balancer::new(urls))
+                .retry(RetryPolicy::new().jitter(false).statuses(&[503]));
+            let client = ReplicaClient(client);
+
+            // The retry goes to the next replica in turn, rather than the one which failed
+            assert_eq!(client.read_post(1).await.unwrap().into_inner(), 1);
+            assert_eq!(down.hits.load(Ordering::SeqCst), 1);
+            assert_eq!(up.hits.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn least_outstanding_concurrent() {
+        phalanx::reexports::rt::System::new("least_outstanding_concurrent").block_on(async {
+            let servers = vec![ReplicaServer::default(), ReplicaServer::default()];
+            let replicas: Vec<_> = servers
+                .iter()
+                .cloned()
+                .map(|server| test::start(move || App::new().phalanx_mount(server.clone())))
+                .collect();
+
+            let urls: Vec<_> = replicas
+                .iter()
+                .map(|replica| format!("http://{}", replica.addr()))
+                .collect();
+            let balancer = Balancer::new(urls).strategy(Strategy::LeastOutstanding);
+            let client = ReplicaClient(Client::balanced(ReqwestClient::default(), balancer));
+
+            // Calls made at once are counted as they're picked, so they don't all go to one replica
+            let posts = futures::future::join_all((0..4).map(|id| client.read_post(id))).await;
+            assert!(posts.into_iter().all(|post| post.is_ok()));
+            for server in &servers {
+                assert_eq!(server.hits.load(Ordering::SeqCst), 2);
+            }
+        });
+    }
+}
+
+mod limit {
+    use phalanx::{
+        client::PhalanxClientError,
+        limit::{LimitStats, Limiter},
+        reexports::http::StatusCode,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn queueing() {
+        phalanx::reexports::rt::System::new("queueing").block_on(async {
+            let limiter = Limiter::new()
+                .max(2)
+                .queue(1)
+                .queue_timeout(Duration::from_millis(20));
+            let first = limiter.acquire("read_post").await.unwrap();
+            let _second = limiter.acquire("list_posts").await.unwrap();
+
+            // The third request waits for the first to finish, and a fourth can't join it
+            let third = limiter.acquire("read_post");
+            futures::pin_mut!(third);
+            assert!(futures::poll!(third.as_mut()).is_pending());
+            assert!(matches!(
+                limiter.acquire("read_post").await,
+                Err(PhalanxClientError::QueueFull(_))
+            ));
+            drop(first);
+            let _third = third.await.unwrap();
+
+            assert!(matches!(
+                limiter.acquire("read_post").await,
+                Err(PhalanxClientError::QueueTimeout(_))
+            ));
+            assert_eq!(
+                limiter.stats(),
+                vec![LimitStats {
+                    route: None,
+                    limit: 2,
+                    in_flight: 2,
+                    waiting: 0,
+                }]
+            );
+        });
+    }
+
+    #[test]
+    fn adaptive() {
+        phalanx::reexports::rt::System::new("adaptive").block_on(async {
+            let limiter = Limiter::new().per_route(8).adaptive(2);
+            let limit = || limiter.stats()[0].limit;
+
+            let permit = limiter.acquire("read_post").await.unwrap();
+            permit.record(StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(limit(), 4);
+            permit.record(StatusCode::TOO_MANY_REQUESTS);
+            permit.record(StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(limit(), 2);
+
+            // Successes raise the limit by about one for each limit's worth of them
+            permit.record(StatusCode::OK);
+            permit.record(StatusCode::OK);
+            assert_eq!(limit(), 2);
+            permit.record(StatusCode::OK);
+            assert_eq!(limit(), 3);
+        });
+    }
+
+    #[test]
+    fn adaptive_retried() {
+        use actix_web::{test, web, App, HttpResponse};
+        use phalanx::{client::Client, retry::RetryPolicy};
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        phalanx::reexports::rt::System::new("adaptive_retried").block_on(async {
+            // Unavailable for the first two attempts
+            let hits = Arc::new(AtomicUsize::new(0));
+            let server_hits = hits.clone();
+            let server = test::start(move || {
+                let hits = server_hits.clone();
+                App::new().route(
+                    "/posts",
+                    web::get().to(move || {
+                        let hit = hits.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            match hit {
+                                0 | 1 => HttpResponse::ServiceUnavailable().finish(),
+                                _ => HttpResponse::Ok().finish(),
+                            }
+                        }
+                    }),
+                )
+            });
+
+            let limiter = Limiter::new().per_route(8).adaptive(2);
+            let client = Client::url(&format!("http://{}", server.addr()))
+                .concurrency_limit(limiter.clone());
+            let policy = RetryPolicy::new()
+                .backoff(Duration::from_millis(1))
+                .jitter(false);
+            let req = client.client.get(&client.format_url("/posts"));
+            let res = client.send("read_posts", req, &policy).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // Each unavailable attempt halves the limit, though the call succeeded
+            assert_eq!(hits.load(Ordering::SeqCst), 3);
+            assert_eq!(limiter.stats()[0].limit, 2);
+        });
+    }
+    mod shared {
+        use phalanx::{client::Client, web::Json};
+        use phalanx_codegen::{get, phalanx, PhalanxClient};
+
+        #[derive(Clone)]
+        pub struct PostsServer;
+
+        #[derive(PhalanxClient)]
+        pub struct PostsClient(#[client] pub Client);
+
+        #[phalanx(PostsClient)]
+        impl PostsServer {
+            #[get("/posts/{id}")]
+            async fn read_post(&self, id: u32) -> Json<u32> {
+                Json(id)
+            }
+        }
+
+        #[derive(Clone)]
+        pub struct CommentsServer;
+
+        #[derive(PhalanxClient)]
+        pub struct CommentsClient(#[client] pub Client);
+
+        #[phalanx(CommentsClient)]
+        impl CommentsServer {
+            #[get("/comments/{id}")]
+            async fn read_post(&self, id: u32) -> Json<u32> {
+                Json(id)
+            }
+        }
+    }
+
+    #[test]
+    fn shared_client() {
+        use actix_web::{test, App};
+        use phalanx::{client::Client, prelude::PhalanxMount};
+        use shared::{CommentsClient, CommentsServer, PostsClient, PostsServer};
+
+        phalanx::reexports::rt::System::new("shared_client").block_on(async {
+            let server = test::start(|| {
+                App::new()
+                    .phalanx_mount(PostsServer)
+                    .phalanx_mount(CommentsServer)
+            });
+
+            // Services sharing a client are limited apart, even with routes of the same name
+            let limiter = Limiter::new().per_route(4);
+            let client = Client::url(&format!("http://{}", server.addr()))
+                .concurrency_limit(limiter.clone());
+            PostsClient(client.clone()).read_post(1).await.unwrap();
+            CommentsClient(client).read_post(1).await.unwrap();
+
+            let routes: Vec<_> = limiter.stats().into_iter().map(|stats| stats.route).collect();
+            assert_eq!(
+                routes,
+                vec![
+                    Some(String::from("CommentsServer::read_post")),
+                    Some(String::from("PostsServer::read_post")),
+                ]
+            );
+        });
+    }
+}
+
+mod concurrency {
+    use super::*;
+    use phalanx::{
+        reexports::http::{header::RETRY_AFTER, StatusCode},
+        reexports::ResponseError,
+        server::concurrency::{enter, Concurrency, ConcurrencyLimits, RouteStats},
+        web::Json,
+    };
+    use phalanx_codegen::concurrency;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct SearchServer;
+
+    #[derive(PhalanxClient)]
+    struct SearchClient(#[client] Client);
+
+    #[phalanx(SearchClient, concurrency(max = 64))]
+    impl SearchServer {
+        #[concurrency(max = 4, queue = 16, wait_ms = 200, retry_after = 2)]
+        #[get("/search/{query}")]
+        async fn search(&self, query: String) -> Json<Vec<String>> {
+            Json(vec![query])
+        }
+
+        #[get("/suggest/{query}")]
+        async fn suggest(&self, query: String) -> Json<Vec<String>> {
+            Json(vec![query])
+        }
+
+        #[concurrency(max = 1, queue = 0)]
+        #[post("/search/{query}/saved")]
+        async fn save(&self, query: String, tags: Json<Vec<String>>) -> Json<usize> {
+            Json(query.len() + tags.len())
+        }
+    }
+
+    async fn _test() {
+        let client = SearchClient(Client::url("http://localhost:8080"));
+        let _: Json<Vec<String>> = client.search("phalanx".into()).await.unwrap();
+        let _: Json<Vec<String>> = client.suggest("phal".into()).await.unwrap();
+        let _: Json<usize> = client.save("phalanx".into(), Json(vec![])).await.unwrap();
+    }
+
+    #[test]
+    fn rejected() {
+        use actix_web::{test, web::Data, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("rejected").block_on(async {
+            let limit = Concurrency::new(1)
+                .queue(0)
+                .retry_after(Duration::from_secs(3));
+            let limits = Data::new(ConcurrencyLimits::new());
+            let req = test::TestRequest::default()
+                .app_data(limits.clone())
+                .to_http_request();
+            let permit = enter("test::rejected", limit, &req).await.unwrap();
+            assert!(permit.is_some());
+
+            // Requests beyond the limit, with no room to wait, are rejected
+            let err = enter("test::rejected", limit, &req).await.unwrap_err();
+            assert!(!err.waited);
+            let res = err.error_response();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "3");
+
+            // Routes entered with another limit are limited on their own
+            let other = Concurrency::new(2);
+            assert!(enter("test::rejected", other, &req).await.is_ok());
+
+            assert_eq!(
+                limits.stats()[0],
+                RouteStats {
+                    route: "test::rejected",
+                    max: 1,
+                    in_flight: 1,
+                    waiting: 0,
+                }
+            );
+            drop(permit);
+            assert_eq!(limits.stats()[0].in_flight, 0);
+
+            // Apps not sharing limits have their own
+            let mut app = test::init_service(App::new().phalanx_mount(SearchServer)).await;
+            let req = test::TestRequest::get().uri("/search/phalanx").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(limits
+                .stats()
+                .iter()
+                .all(|stats| stats.route != "SearchServer::search"));
+
+            let app = App::new()
+                .app_data(limits.clone())
+                .phalanx_mount(SearchServer);
+            let mut app = test::init_service(app).await;
+            let req = test::TestRequest::get().uri("/search/phalanx").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(limits
+                .stats()
+                .iter()
+                .any(|stats| stats.route == "SearchServer::search"));
+        });
+    }
+
+    #[test]
+    fn retry_after_rounded_up() {
+        use phalanx::server::concurrency::Overloaded;
+
+        // Callers aren't told to retry right away
+        let err = Overloaded {
+            waited: true,
+            retry_after: Duration::from_millis(200),
+        };
+        let res = err.error_response();
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "1");
+        assert_eq!(err.to_string(), "the server is busy, retry in 1 seconds");
+    }
+
+    #[test]
+    fn queued() {
+        use actix_web::{test, web::Data};
+        use futures::poll;
+
+        phalanx::reexports::rt::System::new("queued").block_on(async {
+            let limit = Concurrency::new(1).queue(1).wait(Duration::from_millis(20));
+            let limits = Data::new(ConcurrencyLimits::new());
+            let req = test::TestRequest::default()
+                .app_data(limits.clone())
+                .to_http_request();
+            let permit = enter("test::queued", limit, &req).await.unwrap();
+
+            // Waiting requests are let through as others finish
+            let mut waiting = Box::pin(enter("test::queued", limit, &req));
+            assert!(poll!(&mut waiting).is_pending());
+            assert_eq!(limits.stats()[0].waiting, 1);
+            assert!(!enter("test::queued", limit, &req).await.unwrap_err().waited);
+            drop(permit);
+            let permit = waiting.await.unwrap();
+            assert_eq!(limits.stats()[0].in_flight, 1);
+
+            // Or turned away if they don't get a turn in time
+            assert!(enter("test::queued", limit, &req).await.unwrap_err().waited);
+            drop(permit);
+            assert_eq!(
+                limits.stats()[0],
+                RouteStats {
+                    route: "test::queued",
+                    max: 1,
+                    in_flight: 0,
+                    waiting: 0,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn refused_before_payload() {
+        use actix_web::{test, web::Data, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("refused_before_payload").block_on(async {
+            let limits = Data::new(ConcurrencyLimits::new());
+            let app = App::new()
+                .app_data(limits.clone())
+                .phalanx_mount(SearchServer);
+            let mut app = test::init_service(app).await;
+
+            // A request over the limit is refused without its body being read
+            let req = test::TestRequest::default()
+                .app_data(limits.clone())
+                .to_http_request();
+            let limit = Concurrency::new(1).queue(0);
+            let _permit = enter("SearchServer::save", limit, &req).await.unwrap();
+            let req = test::TestRequest::post()
+                .uri("/search/phalanx/saved")
+                .header("content-type", "application/json")
+                .set_payload("not json")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        });
+    }
+}
+
+mod rate_limit {
+    use super::*;
+    use phalanx::{
+        breaker::ManualClock,
+        reexports::{http::StatusCode, ResponseError},
+        server::rate_limit::{Limited, MemoryStore, RateLimit, RateLimits},
+        web::Json,
+    };
+    use phalanx_codegen::rate_limit;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct SearchServer;
+
+    #[derive(PhalanxClient)]
+    struct SearchClient(#[client] Client);
+
+    #[phalanx(SearchClient)]
+    impl SearchServer {
+        #[rate_limit(per_minute = 60, key = "header:X-Api-Key")]
+        #[get("/search/{query}")]
+        async fn search(&self, query: String) -> Json<Vec<String>> {
+            Json(vec![query])
+        }
+
+        #[rate_limit(per_second = 5, key = "user")]
+        #[get("/suggest/{query}")]
+        async fn suggest(&self, query: String) -> Json<Vec<String>> {
+            Json(vec![query])
+        }
+
+        #[rate_limit(per_minute = 1, key = "user")]
+        #[post("/search/{query}/saved")]
+        async fn save(&self, query: String, tags: Json<Vec<String>>) -> Json<usize> {
+            Json(query.len() + tags.len())
+        }
+    }
+
+    async fn _test() {
+        let client = SearchClient(Client::url("http://localhost:8080"));
+        let _: Json<Vec<String>> = client.search("phalanx".into()).await.unwrap();
+        let _: Json<Vec<String>> = client.suggest("phal".into()).await.unwrap();
+        let _: Json<usize> = client.save("phalanx".into(), Json(vec![])).await.unwrap();
+    }
+
+    #[test]
+    fn buckets() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::new().clock(clock.clone());
+        let limit = RateLimit::per_minute(2);
+
+        assert!(store.take_now("search alice".into(), limit).allowed);
+        assert!(store.take_now("bob".into(), limit).allowed);
+        let second = store.take_now("search alice".into(), limit);
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+
+        // The bucket refills a token every 30 seconds
+        let limited = store.take_now("search alice".into(), limit);
+        assert!(!limited.allowed);
+        assert_eq!(limited.reset, Duration::from_secs(30));
+        clock.advance(Duration::from_secs(30));
+        assert!(store.take_now("search alice".into(), limit).allowed);
+
+        let res = Limited(limited).error_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("ratelimit-limit").unwrap(), "2");
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "0");
+        assert_eq!(res.headers().get("ratelimit-reset").unwrap(), "30");
+        assert_eq!(res.headers().get("retry-after").unwrap(), "30");
+
+        // Buckets are forgotten once they've refilled
+        assert_eq!(store.buckets(), 2);
+        clock.advance(Duration::from_secs(60));
+        assert!(store.take_now("carol".into(), limit).allowed);
+        assert_eq!(store.buckets(), 1);
+    }
+
+    #[test]
+    fn shared() {
+        use actix_web::{test, web::Data, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("shared").block_on(async {
+            // Apps registering the same limits, like the workers of a server, share buckets
+            let limits = Data::new(RateLimits::new(MemoryStore::new()));
+            let app = || {
+                App::new()
+                    .app_data(limits.clone())
+                    .phalanx_mount(SearchServer)
+            };
+            let mut first = test::init_service(app()).await;
+            let mut second = test::init_service(app()).await;
+            let suggest = || test::TestRequest::get().uri("/suggest/phal").to_request();
+
+            for _ in 0..5 {
+                let res = test::call_service(&mut first, suggest()).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+            let res = test::call_service(&mut second, suggest()).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+            // Apps without limits registered have their own
+            let mut own = test::init_service(App::new().phalanx_mount(SearchServer)).await;
+            let res = test::call_service(&mut own, suggest()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn refused_before_payload() {
+        use actix_web::{test, App};
+        use phalanx::prelude::PhalanxMount;
+
+        phalanx::reexports::rt::System::new("refused_before_payload").block_on(async {
+            let mut app = test::init_service(App::new().phalanx_mount(SearchServer)).await;
+            let save = |body: &'static str| {
+                test::TestRequest::post()
+                    .uri("/search/phalanx/saved")
+                    .header("content-type", "application/json")
+                    .set_payload(body)
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, save("[]")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // Callers over the limit are refused without their body being read
+            let res = test::call_service(&mut app, save("not json")).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        });
+    }
 }